@@ -0,0 +1,66 @@
+// Many sessions at once against the tokio server: every client downloads the
+// same resource over its own session, in all three modes, and checks what it got.
+// A runtime thread stalled on the disk or a lost control packet shows up as a
+// failed session or as a slower run.
+//
+//     cargo bench --features async --bench concurrent_sessions [-- SESSIONS]
+use std::{
+    fs, net::SocketAddr, time::{Duration, Instant}
+};
+
+use tarefa_01::{
+    ztp::{ZTPChecksum, ZTPTransferMode},
+    AsyncZtpClient, ZtpClient, ZtpServer,
+};
+
+const SERVER: &str = "127.0.0.1:34303";
+const SESSIONS: usize = 200;
+const RESOURCE_SIZE: usize = 200_000;
+
+#[tokio::main]
+async fn main(){
+    let sessions = std::env::args().skip(1).find_map(|arg| arg.parse().ok()).unwrap_or(SESSIONS);
+    let root = std::env::temp_dir().join("ztp_concurrent_sessions");
+    fs::create_dir_all(&root).expect("could not create the resource root");
+    let resource: Vec<u8> = (0..RESOURCE_SIZE).map(|i| (i * 31 % 251) as u8).collect();
+    fs::write(root.join("resource.bin"), &resource).expect("could not write the resource");
+
+    let mut server = ZtpServer::builder()
+        .with_bind_address(SERVER)
+        .with_resource_root(root.to_string_lossy())
+        .with_pool_size(sessions)
+        .build_async()
+        .expect("could not build the server");
+    tokio::spawn(async move { server.run().await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let server: SocketAddr = SERVER.parse().expect("SERVER is an ip:port address");
+
+    let started_at = Instant::now();
+    let clients: Vec<_> = (0..sessions).map(|i|{
+        let mode = match i % 3{
+            0 => ZTPTransferMode::StopAndWait,
+            1 => ZTPTransferMode::SelectiveRepeat(16),
+            _ => ZTPTransferMode::GoBackN(16),
+        };
+        let client = AsyncZtpClient::from(ZtpClient::new(mode, ZTPChecksum::Xxh3));
+        tokio::spawn(async move { client.get(server, "resource.bin").await })
+    }).collect();
+
+    let mut failed = 0;
+    for client in clients{
+        match client.await.expect("a client task panicked"){
+            Ok(bytes) if bytes == resource => {},
+            Ok(_) => failed += 1,
+            Err(e) => {
+                eprintln!("session failed: {e}");
+                failed += 1;
+            }
+        }
+    }
+    let elapsed = started_at.elapsed();
+
+    println!();
+    println!("{sessions} sessions of {RESOURCE_SIZE} bytes in {elapsed:.2?}, {failed} failed");
+    println!("{:.1} sessions/s", sessions as f64 / elapsed.as_secs_f64());
+    assert_eq!(failed, 0, "every session must deliver the resource");
+}
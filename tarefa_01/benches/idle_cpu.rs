@@ -0,0 +1,110 @@
+// CPU used by a server and a client that are only waiting: a server with no
+// sessions, a client whose server never answers, and a server whose sessions
+// never send their request. The baseline is as many threads waiting the way the
+// transfer loops did before links could block, a receive every POLL_MILLIS.
+// Linux only, the CPU time is read from /proc/self/stat.
+//
+//     cargo bench --bench idle_cpu
+use std::{
+    fs, net::{SocketAddr, UdpSocket}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread,
+    time::{Duration, Instant}
+};
+
+use tarefa_01::{
+    constants::{POLL_MILLIS, THREAD_POOL_SIZE},
+    ztp::ZTPSessionControl,
+    ZtpClient, ZtpServer,
+};
+
+const SERVER: &str = "127.0.0.1:34301";
+const SILENT_SERVER: &str = "127.0.0.1:34302";
+// a busy pool, every worker on a session
+const SESSIONS: usize = THREAD_POOL_SIZE;
+// shorter than a session waits for its request
+const WINDOW: Duration = Duration::from_secs(2);
+// USER_HZ, the unit of the times in /proc/self/stat
+const TICK: Duration = Duration::from_millis(10);
+
+fn main(){
+    let root = std::env::temp_dir().join("ztp_idle_cpu");
+    fs::create_dir_all(&root).expect("could not create the resource root");
+    let mut server = ZtpServer::builder()
+        .with_bind_address(SERVER)
+        .with_resource_root(root.to_string_lossy())
+        .with_pool_size(SESSIONS)
+        .build()
+        .expect("could not build the server");
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+    let server: SocketAddr = SERVER.parse().expect("SERVER is an ip:port address");
+
+    let mut results = Vec::new();
+    results.push(("idle server".to_string(), measure()));
+
+    // the server never answers, the client waits out its retries
+    let _silent = UdpSocket::bind(SILENT_SERVER).expect("could not bind the silent server");
+    let silent: SocketAddr = SILENT_SERVER.parse().expect("SILENT_SERVER is an ip:port address");
+    let client = thread::spawn(move || ZtpClient::default().get(silent, "nothing"));
+    results.push(("client waiting for an OpenAck".to_string(), measure()));
+    let _ = client.join();
+
+    let _sessions: Vec<UdpSocket> = (0..SESSIONS).map(|_| open_session(server)).collect();
+    results.push((format!("server with {SESSIONS} sessions waiting for a request"), measure()));
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let pollers: Vec<_> = (0..SESSIONS).map(|_|{
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || poll_until(&stop))
+    }).collect();
+    results.push((format!("baseline: {SESSIONS} threads polling every {POLL_MILLIS}ms"), measure()));
+    stop.store(true, Ordering::Relaxed);
+    for poller in pollers{
+        let _ = poller.join();
+    }
+
+    println!();
+    for (name, cpu) in results{
+        println!("{name:<48} {cpu:>6.2}% of a core");
+    }
+}
+
+// Sends an Open and waits for its OpenAck, the session then waits for a request.
+fn open_session(server: SocketAddr) -> UdpSocket{
+    let socket = UdpSocket::bind("127.0.0.1:0").expect("could not bind a client socket");
+    let open = ZTPSessionControl::open(rand::random()).encode_to_vec()
+        .expect("an Open always encodes");
+    socket.send_to(&open, server).expect("could not send the Open");
+    socket.set_read_timeout(Some(Duration::from_secs(1))).expect("could not set a read timeout");
+    let _ = socket.recv(&mut [0u8; 4096]);
+    socket
+}
+
+// Waits for a datagram that never comes like the transfer loops did before
+// `Link::wait`: a non-blocking receive, then a POLL_MILLIS sleep.
+fn poll_until(stop: &AtomicBool){
+    let socket = UdpSocket::bind("127.0.0.1:0").expect("could not bind a polling socket");
+    socket.set_nonblocking(true).expect("could not make the polling socket non-blocking");
+    while !stop.load(Ordering::Relaxed){
+        if socket.recv(&mut [0u8; 4096]).is_err(){
+            thread::sleep(Duration::from_millis(POLL_MILLIS));
+        }
+    }
+}
+
+// Share of one core the whole process used over `WINDOW`.
+fn measure() -> f64{
+    let cpu_before = cpu_time();
+    let started_at = Instant::now();
+    thread::sleep(WINDOW);
+    let cpu = cpu_time() - cpu_before;
+    100.0 * cpu.as_secs_f64() / started_at.elapsed().as_secs_f64()
+}
+
+// utime + stime of every thread in the process.
+fn cpu_time() -> Duration{
+    let stat = fs::read_to_string("/proc/self/stat").expect("the CPU time is read from /proc/self/stat");
+    // the command name may hold spaces, the fields after it start with the state (field 3)
+    let fields: Vec<&str> = stat[stat.rfind(')').expect("malformed /proc/self/stat") + 2..].split(' ').collect();
+    let ticks: u32 = fields[11..=12].iter().map(|field| field.parse::<u32>().expect("malformed /proc/self/stat")).sum();
+    TICK * ticks
+}
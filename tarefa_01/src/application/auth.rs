@@ -0,0 +1,350 @@
+use std::{collections::HashMap, fs, future::Future, io::{Error, ErrorKind}, str::FromStr, time::Instant};
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use super::transfer::Link;
+use super::ztp::from_hex;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const TAG_SIZE: usize = 32;
+
+/*================================================= KEYS ============================================================= */
+
+// A pre-shared key. Sealed datagrams look like
+//   packet | key id | key id length (u8) | HMAC-SHA256(secret, packet | key id)
+// so the receiver knows which key to check the tag with.
+#[derive(Clone, Debug)]
+pub struct Key{
+    id: String,
+    secret: Vec<u8>,
+    permission: Permission,
+}
+
+impl Key{
+    pub fn new(id: String, secret: Vec<u8>) -> Key{
+        Key{id, secret, permission: Permission::ReadWrite}
+    }
+
+    pub fn with_permission(mut self, permission: Permission) -> Key{
+        self.permission = permission;
+        self
+    }
+
+    pub fn id(&self) -> &str{
+        &self.id
+    }
+
+    pub fn permission(&self) -> Permission{
+        self.permission
+    }
+
+    pub fn seal(&self, packet: &[u8]) -> Vec<u8>{
+        let mut sealed = Vec::with_capacity(packet.len() + self.id.len() + 1 + TAG_SIZE);
+        sealed.extend_from_slice(packet);
+        sealed.extend_from_slice(self.id.as_bytes());
+        sealed.push(self.id.len() as u8);
+        let tag = self.mac(&sealed[..sealed.len() - 1]).finalize().into_bytes();
+        sealed.extend_from_slice(&tag);
+        sealed
+    }
+
+    // Returns the inner packet if the tag was produced with this key.
+    pub fn open<'a>(&self, sealed: &'a [u8]) -> Option<&'a [u8]>{
+        let (packet, key_id, tag) = split_sealed(sealed)?;
+        if key_id != self.id.as_bytes() {return None;}
+
+        let signed = &sealed[..packet.len() + key_id.len()];
+        self.mac(signed).verify_slice(tag).ok()?;
+        Some(packet)
+    }
+
+    fn mac(&self, bytes: &[u8]) -> HmacSha256{
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key size");
+        mac.update(bytes);
+        mac
+    }
+}
+
+fn split_sealed(sealed: &[u8]) -> Option<(&[u8], &[u8], &[u8])>{
+    let tag_start = sealed.len().checked_sub(TAG_SIZE)?;
+    let id_len = *sealed.get(tag_start.checked_sub(1)?)? as usize;
+    let id_start = (tag_start - 1).checked_sub(id_len)?;
+    Some((&sealed[..id_start], &sealed[id_start..tag_start - 1], &sealed[tag_start..]))
+}
+
+/*================================================= PERMISSIONS ============================================================= */
+
+// What a session may do on the server: read-only keys can Get, List and Stat,
+// read-write keys can also Post, Delete and Rename.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+pub enum Permission{
+    #[serde(rename = "ro")]
+    ReadOnly,
+    #[serde(rename = "rw")]
+    ReadWrite,
+}
+
+impl Permission{
+    pub fn can_write(&self) -> bool{
+        *self == Permission::ReadWrite
+    }
+}
+
+impl FromStr for Permission{
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Permission, Error>{
+        match value{
+            "ro" => Ok(Permission::ReadOnly),
+            "rw" => Ok(Permission::ReadWrite),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("permission {value} is neither ro nor rw")))
+        }
+    }
+}
+
+/*================================================= KEYRING ============================================================= */
+
+// Keys loaded from a file with one `<key id> <hex secret> [ro|rw]` line per key,
+// read-write if the permission is left out. Blank lines and lines starting with
+// `#` are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct Keyring{
+    keys: HashMap<String, Key>,
+    order: Vec<String>,
+}
+
+impl Keyring{
+    pub fn load(path: &str) -> Result<Keyring, Error>{
+        let text = fs::read_to_string(path)?;
+        let mut keyring = Keyring::default();
+
+        for (number, line) in text.lines().enumerate(){
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {continue;}
+
+            let invalid = |reason: &str| Error::new(
+                ErrorKind::InvalidData,
+                format!("{path}:{}: {reason}", number + 1)
+            );
+            let mut fields = line.split_whitespace();
+            let (Some(id), Some(secret)) = (fields.next(), fields.next()) else{
+                return Err(invalid("expected `<key id> <hex secret> [ro|rw]`"));
+            };
+            let permission = match fields.next(){
+                Some(permission) => permission.parse().map_err(|e: Error| invalid(&e.to_string()))?,
+                None => Permission::ReadWrite
+            };
+            if fields.next().is_some(){
+                return Err(invalid("expected `<key id> <hex secret> [ro|rw]`"));
+            }
+            if id.len() > u8::MAX as usize{
+                return Err(invalid("key id is too long"));
+            }
+            let secret = from_hex(secret).ok_or_else(|| invalid("secret is not valid hex"))?;
+            if secret.len() < 16{
+                return Err(invalid("secret must be at least 16 bytes"));
+            }
+
+            keyring.order.push(id.to_string());
+            keyring.keys.insert(id.to_string(), Key::new(id.to_string(), secret).with_permission(permission));
+        }
+
+        if keyring.keys.is_empty(){
+            return Err(Error::new(ErrorKind::InvalidData, format!("{path} has no keys")));
+        }
+        Ok(keyring)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Key>{
+        self.keys.get(id)
+    }
+
+    pub fn first(&self) -> Option<&Key>{
+        self.order.first().and_then(|id| self.keys.get(id))
+    }
+
+    // Finds the key named in the datagram and checks its tag with it.
+    pub fn open<'a>(&self, sealed: &'a [u8]) -> Option<(&Key, &'a [u8])>{
+        let (_, key_id, _) = split_sealed(sealed)?;
+        let key = self.keys.get(std::str::from_utf8(key_id).ok()?)?;
+        Some((key, key.open(sealed)?))
+    }
+}
+
+/*================================================= AUTH LINK ============================================================= */
+
+// Seals everything sent and drops every received datagram whose tag does not
+// check out. Without a key it passes datagrams through untouched.
+pub struct AuthLink<L: Link>{
+    link: L,
+    key: Option<Key>,
+}
+
+impl<L: Link> AuthLink<L>{
+    pub fn new(link: L, key: Option<Key>) -> AuthLink<L>{
+        AuthLink{link, key}
+    }
+
+    pub fn inner(&self) -> &L{
+        &self.link
+    }
+}
+
+impl<L: Link> Link for AuthLink<L>{
+    fn send(&self, buff: &[u8]) -> Result<usize, Error>{
+        match &self.key{
+            Some(key) => self.link.send(&key.seal(buff)),
+            None => self.link.send(buff)
+        }
+    }
+
+    fn recv(&self, buff: &mut [u8]) -> Result<usize, Error>{
+        let bytes = self.link.recv(buff)?;
+        let Some(key) = &self.key else{
+            return Ok(bytes);
+        };
+
+        match key.open(&buff[..bytes]){
+            Some(packet) => {
+                let len = packet.len();
+                buff.copy_within(..len, 0);
+                Ok(len)
+            },
+            None => {
+                warn!("Dropping datagram with an invalid HMAC");
+                Err(Error::new(ErrorKind::InvalidData, "invalid HMAC"))
+            }
+        }
+    }
+
+    fn wait(&self, deadline: Instant) -> impl Future<Output = Result<bool, Error>> + Send{
+        self.link.wait(deadline)
+    }
+
+    fn offload<T: Send + 'static>(&self, work: impl FnOnce() -> T + Send + 'static) -> impl Future<Output = T> + Send{
+        self.link.offload(work)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::testing::socket_pair;
+    use super::super::transfer::block_on;
+
+    fn key(id: &str, byte: u8) -> Key{
+        Key::new(id.to_string(), vec![byte; 32])
+    }
+
+    fn keyring(lines: &str) -> Result<Keyring, Error>{
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), lines).unwrap();
+        Keyring::load(&file.path().to_string_lossy())
+    }
+
+    #[test]
+    fn sealed_packets_open_with_the_same_key(){
+        let key = key("alice", 1);
+        let sealed = key.seal(b"some packet");
+        assert_eq!(sealed.len(), b"some packet".len() + "alice".len() + 1 + TAG_SIZE);
+        assert_eq!(key.open(&sealed), Some(&b"some packet"[..]));
+        assert_eq!(key.open(&key.seal(b"")), Some(&b""[..]));
+    }
+
+    #[test]
+    fn a_tampered_datagram_does_not_open(){
+        let key = key("alice", 1);
+        let sealed = key.seal(b"some packet");
+        // the packet, the key id, its length and the tag are all covered
+        for offset in [0, 11, 13, 16, sealed.len() - TAG_SIZE, sealed.len() - 1]{
+            let mut tampered = sealed.clone();
+            tampered[offset] ^= 0x01;
+            assert_eq!(key.open(&tampered), None, "offset {offset}");
+        }
+        assert_eq!(key.open(&sealed[..sealed.len() - 1]), None);
+        assert_eq!(key.open(&sealed[..TAG_SIZE]), None);
+        assert_eq!(key.open(&[]), None);
+    }
+
+    #[test]
+    fn another_secret_under_the_same_id_does_not_open(){
+        let sealed = key("alice", 1).seal(b"some packet");
+        assert_eq!(key("alice", 2).open(&sealed), None);
+        assert_eq!(key("bob", 1).open(&sealed), None);
+    }
+
+    #[test]
+    fn the_keyring_picks_the_key_by_id(){
+        let keyring = keyring(&format!("# keys\nalice {}\n\nbob {} ro\n", "01".repeat(32), "02".repeat(32))).unwrap();
+        let sealed = key("bob", 2).seal(b"hi");
+        let (key, packet) = keyring.open(&sealed).unwrap();
+        assert_eq!(key.id(), "bob");
+        assert_eq!(packet, b"hi");
+        assert_eq!(keyring.first().map(Key::id), Some("alice"));
+    }
+
+    #[test]
+    fn an_unknown_key_id_is_refused(){
+        let keyring = keyring(&format!("alice {}\n", "01".repeat(32))).unwrap();
+        assert!(keyring.open(&key("mallory", 1).seal(b"hi")).is_none());
+        assert!(keyring.get("mallory").is_none());
+        // the right id with the wrong secret
+        assert!(keyring.open(&key("alice", 9).seal(b"hi")).is_none());
+        // a datagram that was never sealed
+        assert!(keyring.open(b"plain datagram").is_none());
+    }
+
+    #[test]
+    fn keys_are_read_write_unless_marked_read_only(){
+        let keyring = keyring(&format!("alice {}\nbob {} ro\ncarol {} rw\n", "01".repeat(16), "02".repeat(16), "03".repeat(16))).unwrap();
+        assert_eq!(keyring.get("alice").unwrap().permission(), Permission::ReadWrite);
+        assert_eq!(keyring.get("bob").unwrap().permission(), Permission::ReadOnly);
+        assert_eq!(keyring.get("carol").unwrap().permission(), Permission::ReadWrite);
+        assert!(!Permission::ReadOnly.can_write());
+        assert!(Permission::ReadWrite.can_write());
+    }
+
+    #[test]
+    fn bad_key_files_are_refused(){
+        for lines in [
+            String::new(),
+            "# only comments\n".to_string(),
+            "alice\n".to_string(),
+            format!("alice {}\n", "01".repeat(15)),
+            format!("alice {}x\n", "01".repeat(16)),
+            format!("alice {} rx\n", "01".repeat(16)),
+            format!("alice {} ro extra\n", "01".repeat(16)),
+            format!("{} {}\n", "a".repeat(256), "01".repeat(16)),
+        ]{
+            assert!(keyring(&lines).is_err(), "{lines:?}");
+        }
+    }
+
+    #[test]
+    fn the_auth_link_drops_datagrams_with_a_bad_tag(){
+        let (a, b) = socket_pair();
+        let sender = AuthLink::new(a, Some(key("alice", 1)));
+        let receiver = AuthLink::new(b, Some(key("alice", 1)));
+        let mut buff = [0u8; 4096];
+
+        sender.send(b"first").unwrap();
+        assert!(block_on(receiver.wait(Instant::now() + Duration::from_secs(1))).unwrap());
+        let bytes = receiver.recv(&mut buff).unwrap();
+        assert_eq!(&buff[..bytes], b"first");
+
+        let forger = AuthLink::new(sender.inner().try_clone().unwrap(), Some(key("alice", 2)));
+        forger.send(b"forged").unwrap();
+        assert!(block_on(receiver.wait(Instant::now() + Duration::from_secs(1))).unwrap());
+        assert_eq!(receiver.recv(&mut buff).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        // unsealed datagrams do not pass either
+        sender.inner().send(b"plain").unwrap();
+        assert!(block_on(receiver.wait(Instant::now() + Duration::from_secs(1))).unwrap());
+        assert_eq!(receiver.recv(&mut buff).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,109 @@
+use std::{future::Future, io::Error, net::SocketAddr, path::Path, time::Instant};
+use log::info;
+use tokio::net::UdpSocket;
+
+use super::super::error::ZtpError;
+use super::super::transfer::{asynchronous, Link};
+use super::super::ztp::{ZTPListEntry, ZTPMetadata, ZTPRequestCode};
+use super::{any_local, is_unchanged, load, open_partial, ZtpClient};
+
+/*================================================= SOCKET LINK ============================================================= */
+
+// A connected tokio socket. Sends never wait, `wait` awaits the socket becoming readable.
+struct SocketLink{
+    socket: UdpSocket,
+}
+
+impl Link for SocketLink{
+    fn send(&self, buff: &[u8]) -> Result<usize, Error>{
+        self.socket.try_send(buff)
+    }
+
+    fn recv(&self, buff: &mut [u8]) -> Result<usize, Error>{
+        self.socket.try_recv(buff)
+    }
+
+    fn wait(&self, deadline: Instant) -> impl Future<Output = Result<bool, Error>> + Send{
+        asynchronous::wait_readable(self.socket.readable(), deadline)
+    }
+
+    fn offload<T: Send + 'static>(&self, work: impl FnOnce() -> T + Send + 'static) -> impl Future<Output = T> + Send{
+        asynchronous::offload(work)
+    }
+}
+
+/*================================================= ASYNC CLIENT ============================================================= */
+
+// Everything `ZtpClient` does, from inside a tokio runtime. Configured through
+// the `ZtpClient` it is made from.
+pub struct AsyncZtpClient{
+    client: ZtpClient,
+}
+
+impl From<ZtpClient> for AsyncZtpClient{
+    fn from(client: ZtpClient) -> Self{
+        AsyncZtpClient{client}
+    }
+}
+
+impl Default for AsyncZtpClient{
+    fn default() -> Self{
+        ZtpClient::default().into()
+    }
+}
+
+impl AsyncZtpClient{
+
+    pub async fn get(&self, server: SocketAddr, resource: &str) -> Result<Vec<u8>, ZtpError>{
+        self.client.get_over(connect(server).await?, resource).await
+    }
+
+    pub async fn get_to_file(&self, server: SocketAddr, resource: &str, save_path: &str) -> Result<(), ZtpError>{
+        let (path, name) = (save_path.to_string(), resource.to_string());
+        if self.client.skip_unchanged && Path::new(save_path).is_file(){
+            let metadata = self.stat(server, resource).await?;
+            let path = path.clone();
+            if asynchronous::offload(move || is_unchanged(&path, &metadata)).await?{
+                info!("{save_path} already matches {resource} on {server}, skipping the download");
+                return Ok(());
+            }
+        }
+        let mut partial = asynchronous::offload(move || open_partial(&path, &name)).await?;
+        while let Some(restarted) = self.client.download_over(connect(server).await?, resource, save_path, partial).await?{
+            partial = restarted;
+        }
+        Ok(())
+    }
+
+    pub async fn stat(&self, server: SocketAddr, resource: &str) -> Result<ZTPMetadata, ZtpError>{
+        self.client.stat_over(connect(server).await?, resource).await
+    }
+
+    pub async fn delete(&self, server: SocketAddr, resource: &str) -> Result<(), ZtpError>{
+        let request = self.client.request(ZTPRequestCode::Delete, resource, None);
+        self.client.change_over(connect(server).await?, request).await
+    }
+
+    pub async fn rename(&self, server: SocketAddr, resource: &str, target: &str) -> Result<(), ZtpError>{
+        let request = self.client.request(ZTPRequestCode::Rename, resource, None).with_target(target.to_string());
+        self.client.change_over(connect(server).await?, request).await
+    }
+
+    pub async fn list(&self, server: SocketAddr, dir: &str) -> Result<Vec<ZTPListEntry>, ZtpError>{
+        self.client.list_over(connect(server).await?, dir).await
+    }
+
+    pub async fn put(&self, server: SocketAddr, load_path: &str, resource: &str) -> Result<(), ZtpError>{
+        let (path, mode, checksum) = (load_path.to_string(), self.client.mode, self.client.checksum);
+        let (file, metadata) = asynchronous::offload(move || load(&path, mode, checksum)).await?;
+        self.client.upload_over(connect(server).await?, resource, file, metadata).await?;
+        info!("Uploaded {load_path} to {server} as {resource}");
+        Ok(())
+    }
+}
+
+async fn connect(server: SocketAddr) -> Result<SocketLink, Error>{
+    let socket = UdpSocket::bind(any_local(server)).await?;
+    socket.connect(server).await?;
+    Ok(SocketLink{socket})
+}
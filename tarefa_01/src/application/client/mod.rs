@@ -0,0 +1,616 @@
+use std::{fs::File, io::{Error, ErrorKind}, net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket}, path::Path};
+use log::{debug, info, warn};
+
+use super::auth::{AuthLink, Key};
+use super::error::ZtpError;
+use super::crypto::{CryptoLink, Handshake, Role};
+use super::rtt::TransferStats;
+use super::session::{self, SessionLink};
+use super::transfer::{self, Link};
+use super::ztp::{
+    self, ZTPChecksum, ZTPErrorCode, ZTPErrorReport, ZTPListEntry, ZTPMetadata, ZTPRequest, ZTPRequestCode,
+    ZTPResponse, ZTPResponseData, ZTPTransferMode
+};
+use partial::PartialDownload;
+
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncZtpClient;
+
+#[cfg(feature = "async")]
+mod asynchronous;
+mod partial;
+
+pub struct ZtpClient{
+    mode: ZTPTransferMode,
+    checksum: ZTPChecksum,
+    key: Option<Key>,
+    encrypt: bool,
+    skip_unchanged: bool,
+    corrupt_percent: u8,
+}
+
+type ClientLink<L> = CryptoLink<SessionLink<AuthLink<L>>>;
+
+impl Default for ZtpClient{
+    fn default() -> Self{
+        ZtpClient::new(ZTPTransferMode::StopAndWait, ZTPChecksum::Xxh3)
+    }
+}
+
+impl ZtpClient{
+
+    pub fn new(mode: ZTPTransferMode, checksum: ZTPChecksum) -> ZtpClient{
+        ZtpClient{mode, checksum, key: None, encrypt: true, skip_unchanged: true, corrupt_percent: 0}
+    }
+
+    // Seals every packet with `key` and drops whatever the server sends that is not sealed with it.
+    pub fn with_key(mut self, key: Key) -> ZtpClient{
+        self.key = Some(key);
+        self
+    }
+
+    // Pieces are encrypted unless this is turned off.
+    pub fn with_encryption(mut self, encrypt: bool) -> ZtpClient{
+        self.encrypt = encrypt;
+        self
+    }
+
+    // A file already at the save path is checked against the server before
+    // downloading, and left alone if it is the same. On unless turned off.
+    pub fn with_skip_unchanged(mut self, skip_unchanged: bool) -> ZtpClient{
+        self.skip_unchanged = skip_unchanged;
+        self
+    }
+
+    // Fault injection: rejects this percentage of the downloaded pieces as corrupt,
+    // so the retransmissions can be watched at work. 0 unless set.
+    pub fn with_corrupt_percent(mut self, corrupt_percent: u8) -> ZtpClient{
+        self.corrupt_percent = corrupt_percent.min(100);
+        self
+    }
+
+    // Downloads `resource` from `server` into memory. Nothing is kept if it fails,
+    // use `get_to_file` for resources worth resuming.
+    pub fn get(&self, server: SocketAddr, resource: &str) -> Result<Vec<u8>, ZtpError>{
+        transfer::block_on(self.get_over(connect(server)?, resource))
+    }
+
+    // Downloads `resource` from `server` into `save_path`, resuming an earlier
+    // attempt if `<save_path>.part` is still around and the resource did not change since.
+    pub fn get_to_file(&self, server: SocketAddr, resource: &str, save_path: &str) -> Result<(), ZtpError>{
+        debug!("Initializing Client");
+        if self.skip_unchanged && Path::new(save_path).is_file(){
+            let metadata = self.stat(server, resource)?;
+            if is_unchanged(save_path, &metadata)?{
+                info!("{save_path} already matches {resource} on {server}, skipping the download");
+                return Ok(());
+            }
+        }
+        let mut partial = open_partial(save_path, resource)?;
+        while let Some(restarted) = transfer::block_on(self.download_over(connect(server)?, resource, save_path, partial))?{
+            partial = restarted;
+        }
+        Ok(())
+    }
+
+    // Size, modification time and digest of `resource` on `server`, without downloading it.
+    pub fn stat(&self, server: SocketAddr, resource: &str) -> Result<ZTPMetadata, ZtpError>{
+        transfer::block_on(self.stat_over(connect(server)?, resource))
+    }
+
+    // Removes `resource` from `server`, needs a read-write key.
+    pub fn delete(&self, server: SocketAddr, resource: &str) -> Result<(), ZtpError>{
+        let request = self.request(ZTPRequestCode::Delete, resource, None);
+        transfer::block_on(self.change_over(connect(server)?, request))?;
+        info!("Deleted {resource} on {server}");
+        Ok(())
+    }
+
+    // Renames `resource` on `server` to `target`, which must not exist yet. Needs a read-write key.
+    pub fn rename(&self, server: SocketAddr, resource: &str, target: &str) -> Result<(), ZtpError>{
+        let request = self.request(ZTPRequestCode::Rename, resource, None).with_target(target.to_string());
+        transfer::block_on(self.change_over(connect(server)?, request))?;
+        info!("Renamed {resource} to {target} on {server}");
+        Ok(())
+    }
+
+    // Entries of `dir` on `server`, the resource root if empty.
+    pub fn list(&self, server: SocketAddr, dir: &str) -> Result<Vec<ZTPListEntry>, ZtpError>{
+        transfer::block_on(self.list_over(connect(server)?, dir))
+    }
+
+    // Uploads the file at `load_path` to `server`, stored there as `resource`.
+    pub fn put(&self, server: SocketAddr, load_path: &str, resource: &str) -> Result<(), ZtpError>{
+        debug!("Initializing Client");
+        let (file, metadata) = load(load_path, self.mode, self.checksum)?;
+        transfer::block_on(self.upload_over(connect(server)?, resource, file, metadata))?;
+        info!("Uploaded {load_path} to {server} as {resource}");
+        Ok(())
+    }
+
+    fn request(&self, code: ZTPRequestCode, resource: &str, handshake: Option<&Handshake>) -> ZTPRequest{
+        let request = ZTPRequest::new(code, resource.to_string(), self.mode)
+            .with_checksum(self.checksum);
+        match handshake{
+            Some(handshake) => request.with_public_key(handshake.public_key()),
+            None => request
+        }
+    }
+}
+
+/*================================================= SESSIONS ============================================================= */
+
+// One session per call, opened over `link` and closed once the call is done.
+// The same whether `link` blocks (`ZtpClient`) or is awaited (`AsyncZtpClient`).
+impl ZtpClient{
+    async fn get_over<L: Link>(&self, link: L, resource: &str) -> Result<Vec<u8>, ZtpError>{
+        let mut stats = TransferStats::new();
+        let socket = self.open(link, &mut stats).await?;
+        let result = self.fetch(&socket, ZTPRequestCode::Get, resource, &mut stats).await;
+        session::close(socket.inner());
+        result
+    }
+
+    // Hands the partial download back, started over, if the resource changed on the server.
+    async fn download_over<L: Link>(
+        &self,
+        link: L,
+        resource: &str,
+        save_path: &str,
+        partial: PartialDownload
+    ) -> Result<Option<PartialDownload>, ZtpError>{
+        let mut stats = TransferStats::new();
+        let socket = self.open(link, &mut stats).await?;
+        let result = self.download(&socket, resource, save_path, partial, &mut stats).await;
+        session::close(socket.inner());
+        result
+    }
+
+    async fn stat_over<L: Link>(&self, link: L, resource: &str) -> Result<ZTPMetadata, ZtpError>{
+        let mut stats = TransferStats::new();
+        let socket = self.open(link, &mut stats).await?;
+        let result = self.fetch_metadata(&socket, resource, &mut stats).await;
+        session::close(socket.inner());
+        result
+    }
+
+    // Sends a request that changes the server and waits for it to be carried out.
+    async fn change_over<L: Link>(&self, link: L, request: ZTPRequest) -> Result<(), ZtpError>{
+        let mut stats = TransferStats::new();
+        let socket = self.open(link, &mut stats).await?;
+        let result = match send_request(&socket, request, &mut stats).await{
+            Ok(answer) => done_from(answer),
+            Err(e) => Err(e)
+        };
+        session::close(socket.inner());
+        result
+    }
+
+    async fn list_over<L: Link>(&self, link: L, dir: &str) -> Result<Vec<ZTPListEntry>, ZtpError>{
+        let mut stats = TransferStats::new();
+        let socket = self.open(link, &mut stats).await?;
+        let result = self.fetch_listing(&socket, dir, &mut stats).await;
+        session::close(socket.inner());
+        result
+    }
+
+    async fn upload_over<L: Link>(&self, link: L, resource: &str, file: File, metadata: ZTPMetadata) -> Result<(), ZtpError>{
+        let mut stats = TransferStats::new();
+        let socket = self.open(link, &mut stats).await?;
+        let result = self.upload(&socket, resource, file, metadata, &mut stats).await;
+        session::close(socket.inner());
+        result
+    }
+
+    async fn open<L: Link>(&self, link: L, stats: &mut TransferStats) -> Result<ClientLink<L>, ZtpError>{
+        let socket = CryptoLink::new(SessionLink::new(AuthLink::new(link, self.key.clone())));
+        session::open(socket.inner(), stats).await?;
+        Ok(socket)
+    }
+
+    async fn download<L: Link>(
+        &self,
+        socket: &ClientLink<L>,
+        resource: &str,
+        save_path: &str,
+        partial: PartialDownload,
+        stats: &mut TransferStats
+    ) -> Result<Option<PartialDownload>, ZtpError>{
+        let handshake = self.encrypt.then(Handshake::new);
+        let request = self.request(ZTPRequestCode::Get, resource, handshake.as_ref()).resume_from(partial.next_pkg());
+        debug!("Sending GET request for {resource}");
+        let answer = send_request(socket, request, stats).await?;
+        let metadata = match handshake{
+            Some(handshake) => match establish(socket, handshake, answer){
+                Ok(_) => receive_metadata(socket, stats).await,
+                Err(e) => Err(e)
+            },
+            None => metadata_from(answer)
+        };
+        // the partial download is checked and saved by offloaded work, like every piece write
+        let (resource, save_path) = (resource.to_string(), save_path.to_string());
+        let begun_resource = resource.clone();
+        let (partial, metadata) = match socket.offload(move || begin_download(partial, &begun_resource, metadata)).await?{
+            Begun::Resumed(partial, metadata) => (partial, metadata),
+            // the server only sends what comes after the resumed prefix, so ask again in a new session
+            Begun::Restarted(partial) => return Ok(Some(partial))
+        };
+        let (partial, received) = transfer::receive_resource(socket, metadata, stats, partial, self.corrupt_percent).await;
+        info!("Transfer stats: {stats}");
+        socket.offload(move || finish_download(partial, &resource, &save_path, &metadata, received)).await.map(|_| None)
+    }
+
+    // Nothing but the metadata comes back, so there are no pieces to encrypt.
+    async fn fetch_metadata<L: Link>(&self, socket: &ClientLink<L>, resource: &str, stats: &mut TransferStats) -> Result<ZTPMetadata, ZtpError>{
+        debug!("Sending STAT request for {resource}");
+        metadata_from(send_request(socket, self.request(ZTPRequestCode::Stat, resource, None), stats).await?)
+    }
+
+    async fn fetch_listing<L: Link>(&self, socket: &ClientLink<L>, dir: &str, stats: &mut TransferStats) -> Result<Vec<ZTPListEntry>, ZtpError>{
+        let listing = self.fetch(socket, ZTPRequestCode::List, dir, stats).await?;
+        ZTPListEntry::decode_listing(&listing).map_err(|e| ZtpError::Integrity(format!("bad listing: {e}")))
+    }
+
+    // Whatever `code` answers with, received whole into memory and checked against its digest.
+    async fn fetch<L: Link>(
+        &self,
+        socket: &ClientLink<L>,
+        code: ZTPRequestCode,
+        name: &str,
+        stats: &mut TransferStats
+    ) -> Result<Vec<u8>, ZtpError>{
+        let handshake = self.encrypt.then(Handshake::new);
+        debug!("Sending {code:?} request for {name:?}");
+        let answer = send_request(socket, self.request(code, name, handshake.as_ref()), stats).await?;
+        let metadata = match handshake{
+            Some(handshake) => {
+                establish(socket, handshake, answer)?;
+                receive_metadata(socket, stats).await?
+            },
+            None => metadata_from(answer)?
+        };
+        // grows as pieces arrive rather than to whatever size the server claims
+        let bytes = Vec::new();
+        let (bytes, received) = transfer::receive_resource(socket, metadata, stats, bytes, self.corrupt_percent).await;
+        info!("Transfer stats: {stats}");
+        received?;
+        check_digest(name, bytes, &metadata)
+    }
+
+    async fn upload<L: Link>(
+        &self,
+        socket: &ClientLink<L>,
+        resource: &str,
+        file: File,
+        metadata: ZTPMetadata,
+        stats: &mut TransferStats
+    ) -> Result<(), ZtpError>{
+        let handshake = self.encrypt.then(Handshake::new);
+        let request = ZTPRequest::encode_to_vec(self.request(ZTPRequestCode::Post, resource, handshake.as_ref()))?;
+        debug!("Sending POST request for {resource}");
+        // only an encrypted Post is answered before the metadata, with the Handshake
+        let request = match handshake{
+            Some(handshake) => {
+                establish(socket, handshake, negotiate(socket, &request, stats).await)?;
+                None
+            },
+            None => Some(request.as_slice())
+        };
+        let mut reply = transfer::send_metadata(socket, request, metadata, stats).await;
+        if downgrade(socket, reply.as_ref()){
+            reply = transfer::send_metadata(socket, request, metadata, stats).await;
+        }
+        accepted(reply)?;
+        let uploaded = transfer::send_resource(socket, file, metadata, stats).await;
+        info!("Transfer stats: {stats}");
+        Ok(uploaded?)
+    }
+}
+
+// The OS picks the client port, any address of the server's family will do.
+fn connect(server: SocketAddr) -> Result<UdpSocket, Error>{
+    let socket = UdpSocket::bind(any_local(server))?;
+    socket.connect(server)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+fn any_local(server: SocketAddr) -> SocketAddr{
+    match server{
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    }
+}
+
+// Hashed up front, a large file would otherwise keep the server waiting for the metadata.
+fn load(load_path: &str, mode: ZTPTransferMode, checksum: ZTPChecksum) -> Result<(File, ZTPMetadata), ZtpError>{
+    let mut file = File::open(load_path)
+        .map_err(|e| Error::new(e.kind(), format!("could not read {load_path}: {e}")))?;
+    let metadata = ZTPMetadata::from_reader(&mut file, mode)?
+        .with_checksum(checksum);
+    Ok((file, metadata))
+}
+
+fn open_partial(save_path: &str, resource: &str) -> Result<PartialDownload, Error>{
+    PartialDownload::open(save_path, resource)
+        .map_err(|e| Error::new(e.kind(), format!("could not open {save_path}.part: {e}")))
+}
+
+// Finishes the key exchange with the server's answer to the request.
+fn establish<L: Link>(socket: &CryptoLink<L>, handshake: Handshake, res: Option<ZTPResponse>) -> Result<(), ZtpError>{
+    let Some(res) = res else{
+        return Err(ZtpError::Timeout("Handshake did not arrive".to_string()));
+    };
+    if let Some(error) = res.get_error(){
+        return Err(error.into());
+    }
+    let Some(&ZTPResponseData::PublicKey(server_key)) = res.get_data() else{
+        return Err(ZtpError::Handshake);
+    };
+    let Some(cipher) = handshake.finish(server_key, Role::Client) else{
+        return Err(ZtpError::Handshake);
+    };
+    socket.establish(cipher);
+    Ok(())
+}
+
+// The metadata answering a request, or why the server refused it.
+async fn receive_metadata<L: Link>(socket: &ClientLink<L>, stats: &mut TransferStats) -> Result<ZTPMetadata, ZtpError>{
+    metadata_from(transfer::receive_control(socket, stats).await)
+}
+
+fn metadata_from(res: Option<ZTPResponse>) -> Result<ZTPMetadata, ZtpError>{
+    let Some(res) = res else{
+        return Err(ZtpError::Timeout("Metadata did not arrive".to_string()));
+    };
+    if let Some(error) = res.get_error(){
+        return Err(error.into());
+    }
+    transfer::extract_metadata(res).ok_or(ZtpError::Timeout("Metadata did not arrive".to_string()))
+}
+
+// Same size and digest as what the server has.
+fn is_unchanged(path: &str, metadata: &ZTPMetadata) -> Result<bool, Error>{
+    let mut file = File::open(path)?;
+    if file.metadata()?.len() != metadata.size() as u64{
+        return Ok(false);
+    }
+    let (_, digest) = ztp::digest_reader(&mut file)?;
+    Ok(&digest == metadata.digest())
+}
+
+fn done_from(res: Option<ZTPResponse>) -> Result<(), ZtpError>{
+    let Some(res) = res else{
+        return Err(ZtpError::Timeout("the server did not answer".to_string()));
+    };
+    if let Some(error) = res.get_error(){
+        return Err(error.into());
+    }
+    if !res.is_ack(){
+        let message = format!("unexpected {:?} answering the request", res.get_code());
+        return Err(Error::new(ErrorKind::InvalidData, message).into());
+    }
+    Ok(())
+}
+
+// Checks the metadata against an earlier attempt, whatever is left of it after
+// the server refused the resource or it changed is discarded.
+// What the metadata made of a partial download.
+enum Begun{
+    Resumed(PartialDownload, ZTPMetadata),
+    // the resource changed on the server, nothing downloaded so far is kept
+    Restarted(PartialDownload),
+}
+
+fn begin_download(
+    mut partial: PartialDownload,
+    resource: &str,
+    metadata: Result<ZTPMetadata, ZtpError>
+) -> Result<Begun, ZtpError>{
+    let metadata = match metadata{
+        Ok(metadata) => metadata,
+        Err(e) => {
+            // the server will not serve it at all, nothing left to resume
+            if matches!(e.code(), Some(ZTPErrorCode::NotFound | ZTPErrorCode::Forbidden)){
+                let _ = partial.discard();
+            }
+            return Err(e);
+        }
+    };
+    debug!("Metadata of {resource}: {metadata:?}");
+
+    if !partial.matches(&metadata){
+        warn!("{resource} changed on the server, downloading it again from the start");
+        partial.restart()?;
+        return Ok(Begun::Restarted(partial));
+    }
+    partial.begin(&metadata)?;
+    Ok(Begun::Resumed(partial, metadata))
+}
+
+// Saves a complete download, or checkpoints what arrived so a re-run can resume it.
+fn finish_download(
+    mut partial: PartialDownload,
+    resource: &str,
+    save_path: &str,
+    metadata: &ZTPMetadata,
+    received: Result<(), ZTPErrorReport>
+) -> Result<(), ZtpError>{
+    if let Err(e) = received{
+        let _ = partial.checkpoint();
+        warn!("Kept {} of {} bytes, run again to resume", partial.bytes(), metadata.size());
+        return Err(e.into());
+    }
+
+    info!("Saving Resource to: {save_path}");
+    match partial.complete(save_path, metadata){
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            Err(ZtpError::Integrity(format!("{resource} was not saved: {e}")))
+        },
+        Err(e) => Err(e.into())
+    }
+}
+
+fn check_digest(name: &str, bytes: Vec<u8>, metadata: &ZTPMetadata) -> Result<Vec<u8>, ZtpError>{
+    let (_, digest) = ztp::digest_reader(&mut bytes.as_slice())?;
+    if &digest != metadata.digest(){
+        return Err(ZtpError::Integrity(format!("{name} does not match its digest")));
+    }
+    Ok(bytes)
+}
+
+// The receiver's reply to the Metadata of an upload.
+fn accepted(reply: Option<ZTPResponse>) -> Result<(), ZtpError>{
+    if let Some(error) = reply.as_ref().and_then(ZTPResponse::get_error){
+        return Err(error.into());
+    }
+    if !reply.is_some_and(|res| res.is_ack()){
+        return Err(ZtpError::Timeout("Metadata was not acknowledged".to_string()));
+    }
+    Ok(())
+}
+
+async fn send_request<L: Link>(socket: &ClientLink<L>, req: ZTPRequest, stats: &mut TransferStats) -> Result<Option<ZTPResponse>, ZtpError>{
+    let bytes = ZTPRequest::encode_to_vec(req)?;
+    Ok(negotiate(socket, &bytes, stats).await)
+}
+
+// Sends `request` again in another version if the server refused the one we spoke.
+async fn negotiate<L: Link>(socket: &ClientLink<L>, request: &[u8], stats: &mut TransferStats) -> Option<ZTPResponse>{
+    let answer = transfer::send_request(socket, request, stats).await;
+    if downgrade(socket, answer.as_ref()){
+        return transfer::send_request(socket, request, stats).await;
+    }
+    answer
+}
+
+// Switches the session to the highest version we share with a server that refused
+// ours. False if there is no such version, or it is the one that was refused.
+fn downgrade<L: Link>(socket: &ClientLink<L>, answer: Option<&ZTPResponse>) -> bool{
+    let Some(ZTPResponseData::SupportedVersions(min, max)) = answer.and_then(ZTPResponse::get_data) else{
+        return false;
+    };
+    match ztp::common_version(*min, *max){
+        Some(version) if version != socket.inner().version() => {
+            info!("The server speaks ZTP {min}..={max}, switching to version {version}");
+            socket.inner().speak(version);
+            true
+        },
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{fs, thread, time::{Duration, Instant}};
+
+    use super::*;
+    use super::super::server::{ServerConfig, ZtpServer};
+    use super::super::testing::socket_pair;
+    use super::super::transfer::PieceSink;
+    use super::super::ztp::{ZTPResponseCode, ZTPWireError, ZTP_VERSION};
+    use crate::constants::DATA_PIECE_SIZE;
+
+    // Answers requests like a server speaking `min..=max` would, until one in
+    // a version it speaks is answered with Metadata. Returns the versions requested in.
+    fn scripted_server(server: UdpSocket, min: u8, max: u8, requests: usize) -> thread::JoinHandle<Vec<u8>>{
+        thread::spawn(move ||{
+            let mut rx_buff = [0u8; 4096];
+            let mut versions = Vec::new();
+            while versions.len() < requests{
+                if !transfer::block_on(server.wait(Instant::now() + Duration::from_secs(2))).unwrap(){
+                    break;
+                }
+                let Ok(bytes) = server.recv(&mut rx_buff) else{
+                    continue;
+                };
+                let answer = match ZTPRequest::decode_from_slice(&rx_buff[..bytes]){
+                    Ok((req, _)) if (min..=max).contains(&req.get_version()) => {
+                        versions.push(req.get_version());
+                        let metadata = ZTPMetadata::new(0, 1, req.get_mode(), [0; 32]);
+                        ZTPResponse::new(ZTPResponseCode::Metadata, Some(ZTPResponseData::Metadata(metadata)), None)
+                    },
+                    Ok((req, _)) => {
+                        versions.push(req.get_version());
+                        refusal(min, max, req.get_version())
+                    },
+                    Err(ZTPWireError::UnsupportedVersion(version)) => {
+                        versions.push(version);
+                        refusal(min, max, version)
+                    },
+                    Err(_) => continue
+                };
+                transfer::send_response(&server, answer);
+            }
+            versions
+        })
+    }
+
+    fn refusal(min: u8, max: u8, version: u8) -> ZTPResponse{
+        let versions = ZTPResponseData::SupportedVersions(min, max);
+        ZTPResponse::new(ZTPResponseCode::VersionMismatch, Some(versions), None).with_version(version)
+    }
+
+    #[test]
+    fn a_refused_request_is_sent_again_in_the_highest_common_version(){
+        let (server, client) = socket_pair();
+        let server = scripted_server(server, 0, ZTP_VERSION, 2);
+        let socket = CryptoLink::new(SessionLink::new(AuthLink::new(client, None)));
+        // a client newer than the server
+        socket.inner().speak(ZTP_VERSION + 1);
+
+        let metadata = transfer::block_on(ZtpClient::default().fetch_metadata(&socket, "a.txt", &mut TransferStats::new()));
+
+        assert_eq!(metadata.unwrap().size(), 0);
+        assert_eq!(server.join().unwrap(), vec![ZTP_VERSION + 1, ZTP_VERSION]);
+        assert_eq!(socket.inner().version(), ZTP_VERSION);
+    }
+
+    #[test]
+    fn without_a_common_version_the_refusal_is_reported(){
+        let (server, client) = socket_pair();
+        let server = scripted_server(server, ZTP_VERSION + 1, ZTP_VERSION + 2, 2);
+        let socket = CryptoLink::new(SessionLink::new(AuthLink::new(client, None)));
+
+        let metadata = transfer::block_on(ZtpClient::default().fetch_metadata(&socket, "a.txt", &mut TransferStats::new()));
+
+        assert_eq!(metadata.unwrap_err().code(), Some(ZTPErrorCode::BadRequest));
+        // the request was not sent again
+        assert_eq!(server.join().unwrap(), vec![ZTP_VERSION]);
+    }
+
+    #[test]
+    fn a_resource_changed_since_the_partial_download_is_downloaded_again_from_the_start(){
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        let old: Vec<u8> = (0..5 * DATA_PIECE_SIZE + 100).map(|i| (i * 7 % 251) as u8).collect();
+        let new: Vec<u8> = old.iter().map(|byte| byte ^ 0xff).collect();
+        fs::write(root.join("a.bin"), &new).unwrap();
+
+        // an interrupted run left the first pieces of the old resource behind
+        let save_path = dir.path().join("a.bin").to_string_lossy().into_owned();
+        let (size, digest) = ztp::digest_reader(&mut &old[..]).unwrap();
+        let mut partial = PartialDownload::open(&save_path, "a.bin").unwrap();
+        partial.begin(&ZTPMetadata::new(size, size.div_ceil(DATA_PIECE_SIZE), ZTPTransferMode::StopAndWait, digest)).unwrap();
+        for (pkg_id, piece) in old.chunks(DATA_PIECE_SIZE).take(3).enumerate(){
+            partial.write_piece((pkg_id * DATA_PIECE_SIZE) as u64, piece).unwrap();
+        }
+        partial.checkpoint().unwrap();
+
+        let server_addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config = ServerConfig{
+            bind_address: server_addr.to_string(),
+            resource_root: root.to_string_lossy().into_owned(),
+            ..ServerConfig::default()
+        };
+        thread::spawn(move || ZtpServer::new(config).run());
+
+        ZtpClient::default().get_to_file(server_addr, "a.bin", &save_path).unwrap();
+
+        assert_eq!(fs::read(&save_path).unwrap(), new);
+        assert!(!Path::new(&format!("{save_path}.part")).exists());
+        assert!(!Path::new(&format!("{save_path}.ztpstate")).exists());
+    }
+}
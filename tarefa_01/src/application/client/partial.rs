@@ -0,0 +1,399 @@
+use std::{
+    collections::{BTreeMap, HashMap}, fs::{self, File, OpenOptions}, io::{Error, ErrorKind, Read, Seek, SeekFrom, Write}
+};
+use log::{info, warn};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::constants::*;
+use crate::application::transfer::PieceSink;
+use crate::application::ztp::{self, to_hex, ZTPMetadata};
+
+/*================================================= PARTIAL DOWNLOAD ============================================================= */
+
+// A download in progress: `<save_path>.part` is preallocated to the resource size
+// and filled piece by piece, `<save_path>.ztpstate` records how much of it was
+// checkpointed and its xxh3, so a re-run can verify that prefix and ask the
+// server for the remaining pieces only.
+pub struct PartialDownload{
+    data_path: String,
+    state_path: String,
+    file: File,
+    hasher: Xxh3,
+    state: PartialState,
+    unsaved: usize,
+    // pieces written past the end of the prefix, hashed once the prefix reaches them
+    ahead: BTreeMap<u64, Vec<u8>>,
+}
+
+#[derive(Default, Debug)]
+struct PartialState{
+    resource: String,
+    size: usize,
+    package_count: usize,
+    piece_size: usize,
+    digest: String,
+    bytes: usize,
+    prefix_hash: u64,
+}
+
+impl PartialDownload{
+    pub fn open(save_path: &str, resource: &str) -> Result<PartialDownload, Error>{
+        let data_path = format!("{save_path}.part");
+        let state_path = format!("{save_path}.ztpstate");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&data_path)?;
+
+        let mut partial = PartialDownload{
+            data_path,
+            state_path,
+            file,
+            hasher: Xxh3::new(),
+            state: PartialState::new(resource),
+            unsaved: 0,
+            ahead: BTreeMap::new(),
+        };
+
+        match partial.load_state(){
+            Ok(state) if state.resource == resource => {
+                if let Err(e) = partial.verify_prefix(state){
+                    warn!("Discarding partial download of {resource}: {e}");
+                    partial.restart()?;
+                }
+            },
+            _ => partial.restart()?,
+        }
+        Ok(partial)
+    }
+
+    pub fn next_pkg(&self) -> u64{
+        (self.state.bytes / self.state.piece_size) as u64
+    }
+
+    pub fn bytes(&self) -> usize{
+        self.state.bytes
+    }
+
+    // A resumed prefix is only valid for the exact resource it was taken from.
+    pub fn matches(&self, metadata: &ZTPMetadata) -> bool{
+        self.state.bytes == 0 || (
+            self.state.size == metadata.size() &&
+            self.state.package_count == metadata.count() &&
+            self.state.piece_size == metadata.piece_size() &&
+            self.state.digest == to_hex(metadata.digest()) &&
+            metadata.start_pkg() == self.next_pkg()
+        )
+    }
+
+    pub fn begin(&mut self, metadata: &ZTPMetadata) -> Result<(), Error>{
+        self.file.set_len(metadata.size() as u64)?;
+        self.state.size = metadata.size();
+        self.state.package_count = metadata.count();
+        self.state.piece_size = metadata.piece_size();
+        self.state.digest = to_hex(metadata.digest());
+        self.checkpoint()
+    }
+
+    pub fn checkpoint(&mut self) -> Result<(), Error>{
+        self.file.sync_data()?;
+        self.state.prefix_hash = self.hasher.digest();
+        self.unsaved = 0;
+        fs::write(&self.state_path, self.state.to_string())
+    }
+
+    // Only a resource whose SHA-256 matches the metadata is moved to `save_path`,
+    // a mismatching one is thrown away since resuming it would not help.
+    pub fn complete(self, save_path: &str, metadata: &ZTPMetadata) -> Result<(), Error>{
+        self.file.sync_all()?;
+
+        let (_, digest) = ztp::digest_reader(&mut File::open(&self.data_path)?)?;
+        if &digest != metadata.digest(){
+            let message = format!(
+                "sha256 mismatch, expected {} got {}",
+                to_hex(metadata.digest()),
+                to_hex(&digest),
+            );
+            self.discard()?;
+            return Err(Error::new(ErrorKind::InvalidData, message));
+        }
+
+        fs::rename(&self.data_path, save_path)?;
+        fs::remove_file(&self.state_path)
+    }
+
+    pub fn discard(self) -> Result<(), Error>{
+        fs::remove_file(&self.data_path)?;
+        let _ = fs::remove_file(&self.state_path);
+        Ok(())
+    }
+
+    // Throws away whatever was downloaded, e.g. of a resource since changed on the server.
+    pub fn restart(&mut self) -> Result<(), Error>{
+        self.file.set_len(0)?;
+        self.hasher = Xxh3::new();
+        self.state = PartialState::new(&self.state.resource);
+        self.unsaved = 0;
+        self.ahead.clear();
+        Ok(())
+    }
+
+    fn load_state(&self) -> Result<PartialState, Error>{
+        let state = fs::read_to_string(&self.state_path)?;
+        PartialState::parse(&state).ok_or(Error::new(ErrorKind::InvalidData, "corrupt state file"))
+    }
+
+    // Anything written after the last checkpoint is dropped, the rest must hash to the saved value.
+    fn verify_prefix(&mut self, state: PartialState) -> Result<(), Error>{
+        if (self.file.metadata()?.len() as usize) < state.bytes{
+            return Err(Error::new(ErrorKind::UnexpectedEof, "partial file is shorter than its state"));
+        }
+        self.file.set_len(state.bytes as u64)?;
+        if hash_prefix(&self.data_path, state.bytes)?.digest() != state.prefix_hash{
+            return Err(Error::new(ErrorKind::InvalidData, "partial file does not match its checksum"));
+        }
+
+        // a trailing short piece is fetched again so resuming always starts on a piece boundary
+        let mut state = state;
+        state.bytes -= state.bytes % state.piece_size;
+        self.file.set_len(state.bytes as u64)?;
+
+        info!("Resuming {} after {} bytes", state.resource, state.bytes);
+        self.hasher = hash_prefix(&self.data_path, state.bytes)?;
+        self.state = state;
+        Ok(())
+    }
+}
+
+// Pieces land at their offset in any order, but only the contiguous prefix is
+// hashed and checkpointed since that is all a resume can rely on.
+impl PieceSink for PartialDownload{
+    fn write_piece(&mut self, offset: u64, data: &[u8]) -> Result<(), Error>{
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        self.ahead.insert(offset, data.to_vec());
+
+        while let Some(piece) = self.ahead.remove(&(self.state.bytes as u64)){
+            self.hasher.update(&piece);
+            self.state.bytes += piece.len();
+            self.unsaved += piece.len();
+        }
+        if self.unsaved >= CHECKPOINT_PIECES * self.state.piece_size{
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+}
+
+fn hash_prefix(path: &str, len: usize) -> Result<Xxh3, Error>{
+    let mut hasher = Xxh3::new();
+    let mut buffer = [0u8; 8 * DATA_PIECE_SIZE];
+    let mut file = File::open(path)?.take(len as u64);
+    loop{
+        let bytes = file.read(&mut buffer)?;
+        if bytes == 0 {break;}
+        hasher.update(&buffer[..bytes]);
+    }
+    Ok(hasher)
+}
+
+/*================================================= STATE FILE ============================================================= */
+
+impl PartialState{
+    // the piece size is only known once the metadata arrives, until then nothing is resumed
+    fn new(resource: &str) -> PartialState{
+        PartialState{resource: resource.to_string(), piece_size: DATA_PIECE_SIZE, ..Default::default()}
+    }
+
+    fn parse(text: &str) -> Option<PartialState>{
+        let vars: HashMap<&str, &str> = text.lines()
+            .filter_map(|line| line.split_once('='))
+            .collect();
+
+        Some(PartialState{
+            resource: vars.get("resource")?.to_string(),
+            size: vars.get("size")?.parse().ok()?,
+            package_count: vars.get("package_count")?.parse().ok()?,
+            piece_size: vars.get("piece_size")?.parse().ok().filter(|size| *size > 0)?,
+            digest: vars.get("digest")?.to_string(),
+            bytes: vars.get("bytes")?.parse().ok()?,
+            prefix_hash: vars.get("prefix_hash")?.parse().ok()?,
+        })
+    }
+}
+
+impl std::fmt::Display for PartialState{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        writeln!(f, "resource={}", self.resource)?;
+        writeln!(f, "size={}", self.size)?;
+        writeln!(f, "package_count={}", self.package_count)?;
+        writeln!(f, "piece_size={}", self.piece_size)?;
+        writeln!(f, "digest={}", self.digest)?;
+        writeln!(f, "bytes={}", self.bytes)?;
+        writeln!(f, "prefix_hash={}", self.prefix_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::path::Path;
+
+    use super::*;
+    use crate::application::ztp::ZTPTransferMode;
+
+    const SIZE: usize = 5 * DATA_PIECE_SIZE + 100;
+
+    fn resource() -> Vec<u8>{
+        (0..SIZE).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn metadata(resource: &[u8]) -> ZTPMetadata{
+        let (size, digest) = ztp::digest_reader(&mut &resource[..]).unwrap();
+        ZTPMetadata::new(size, size.div_ceil(DATA_PIECE_SIZE), ZTPTransferMode::StopAndWait, digest)
+    }
+
+    // Downloads the first `pieces` pieces of `resource` and checkpoints them.
+    fn download(save_path: &str, resource: &[u8], pieces: usize) -> (PartialDownload, ZTPMetadata){
+        let metadata = metadata(resource);
+        let mut partial = PartialDownload::open(save_path, "teste.jpg").unwrap();
+        partial.begin(&metadata).unwrap();
+        for (pkg_id, piece) in resource.chunks(DATA_PIECE_SIZE).take(pieces).enumerate(){
+            partial.write_piece((pkg_id * DATA_PIECE_SIZE) as u64, piece).unwrap();
+        }
+        partial.checkpoint().unwrap();
+        (partial, metadata)
+    }
+
+    // What an interrupted run leaves behind.
+    fn interrupted(save_path: &str, pieces: usize) -> ZTPMetadata{
+        download(save_path, &resource(), pieces).1
+    }
+
+    fn save_path(dir: &tempfile::TempDir) -> String{
+        dir.path().join("teste.jpg").to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn resumes_after_the_checkpointed_prefix(){
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = save_path(&dir);
+        let metadata = interrupted(&save_path, 3);
+
+        let partial = PartialDownload::open(&save_path, "teste.jpg").unwrap();
+        assert_eq!(partial.bytes(), 3 * DATA_PIECE_SIZE);
+        assert_eq!(partial.next_pkg(), 3);
+        assert!(partial.matches(&metadata.resume_from(3)));
+        assert!(!partial.matches(&metadata.resume_from(0)));
+    }
+
+    #[test]
+    fn pieces_out_of_order_only_count_once_the_prefix_reaches_them(){
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = save_path(&dir);
+        let resource = resource();
+        let mut partial = PartialDownload::open(&save_path, "teste.jpg").unwrap();
+        partial.begin(&metadata(&resource)).unwrap();
+
+        let piece = |pkg_id: usize| &resource[pkg_id * DATA_PIECE_SIZE..(pkg_id + 1) * DATA_PIECE_SIZE];
+        partial.write_piece(2 * DATA_PIECE_SIZE as u64, piece(2)).unwrap();
+        partial.write_piece(DATA_PIECE_SIZE as u64, piece(1)).unwrap();
+        assert_eq!(partial.bytes(), 0);
+        partial.write_piece(0, piece(0)).unwrap();
+        assert_eq!(partial.bytes(), 3 * DATA_PIECE_SIZE);
+    }
+
+    #[test]
+    fn a_trailing_short_piece_is_fetched_again(){
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = save_path(&dir);
+        interrupted(&save_path, 6);
+
+        let partial = PartialDownload::open(&save_path, "teste.jpg").unwrap();
+        assert_eq!(partial.bytes(), 5 * DATA_PIECE_SIZE);
+        assert_eq!(partial.next_pkg(), 5);
+    }
+
+    #[test]
+    fn a_corrupt_state_file_restarts_the_download(){
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = save_path(&dir);
+        interrupted(&save_path, 3);
+        fs::write(format!("{save_path}.ztpstate"), "resource=teste.jpg\nbytes=not a number\n").unwrap();
+
+        let partial = PartialDownload::open(&save_path, "teste.jpg").unwrap();
+        assert_eq!(partial.bytes(), 0);
+        assert_eq!(partial.next_pkg(), 0);
+        assert_eq!(fs::metadata(format!("{save_path}.part")).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn a_state_file_of_another_resource_restarts_the_download(){
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = save_path(&dir);
+        interrupted(&save_path, 3);
+
+        let partial = PartialDownload::open(&save_path, "other.jpg").unwrap();
+        assert_eq!(partial.bytes(), 0);
+        assert_eq!(fs::metadata(format!("{save_path}.part")).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn a_prefix_hash_mismatch_restarts_the_download(){
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = save_path(&dir);
+        interrupted(&save_path, 3);
+        let mut part = fs::read(format!("{save_path}.part")).unwrap();
+        part[DATA_PIECE_SIZE + 10] ^= 0xff;
+        fs::write(format!("{save_path}.part"), part).unwrap();
+
+        let partial = PartialDownload::open(&save_path, "teste.jpg").unwrap();
+        assert_eq!(partial.bytes(), 0);
+        assert_eq!(partial.next_pkg(), 0);
+    }
+
+    #[test]
+    fn a_part_file_shorter_than_its_state_restarts_the_download(){
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = save_path(&dir);
+        interrupted(&save_path, 3);
+        OpenOptions::new().write(true).open(format!("{save_path}.part")).unwrap()
+            .set_len(DATA_PIECE_SIZE as u64).unwrap();
+
+        let partial = PartialDownload::open(&save_path, "teste.jpg").unwrap();
+        assert_eq!(partial.bytes(), 0);
+    }
+
+    #[test]
+    fn a_resource_changed_on_the_server_does_not_match(){
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = save_path(&dir);
+        let metadata = interrupted(&save_path, 3);
+        let partial = PartialDownload::open(&save_path, "teste.jpg").unwrap();
+
+        let mut changed = resource();
+        changed[0] ^= 0xff;
+        assert!(!partial.matches(&self::metadata(&changed).resume_from(3)));
+        let grown = ZTPMetadata::new(SIZE + 1, metadata.count(), metadata.mode(), *metadata.digest()).resume_from(3);
+        assert!(!partial.matches(&grown));
+    }
+
+    #[test]
+    fn completes_only_with_the_right_digest(){
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = save_path(&dir);
+        let (partial, metadata) = download(&save_path, &resource(), 6);
+        partial.complete(&save_path, &metadata).unwrap();
+        assert_eq!(fs::read(&save_path).unwrap(), resource());
+        assert!(!Path::new(&format!("{save_path}.ztpstate")).exists());
+
+        let other = dir.path().join("other.jpg").to_string_lossy().into_owned();
+        let (partial, _) = download(&other, &resource(), 6);
+        let mut changed = resource();
+        changed[0] ^= 0xff;
+        assert!(partial.complete(&other, &self::metadata(&changed)).is_err());
+        assert!(!Path::new(&format!("{other}.part")).exists());
+        assert!(!Path::new(&other).exists());
+    }
+}
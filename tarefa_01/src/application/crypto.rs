@@ -0,0 +1,353 @@
+use std::{future::Future, io::{Error, ErrorKind}, sync::OnceLock, time::Instant};
+use chacha20poly1305::{aead::{Aead, KeyInit, Payload}, ChaCha20Poly1305, Nonce};
+use hkdf::Hkdf;
+use log::trace;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::transfer::Link;
+use super::ztp::ZTPResponse;
+
+/*================================================= HANDSHAKE ============================================================= */
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Role{
+    Client,
+    Server,
+}
+
+// One side of the X25519 key agreement, the secret is fresh for every session.
+pub struct Handshake{
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl Default for Handshake{
+    fn default() -> Self{
+        Handshake::new()
+    }
+}
+
+impl Handshake{
+    pub fn new() -> Handshake{
+        let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+        let public = PublicKey::from(&secret);
+        Handshake{secret, public}
+    }
+
+    pub fn public_key(&self) -> [u8; 32]{
+        self.public.to_bytes()
+    }
+
+    // Derives one key per direction with HKDF-SHA256, salted with both public keys
+    // (client first). Low order peer keys, which would give a known secret, are refused.
+    pub fn finish(self, peer: [u8; 32], role: Role) -> Option<SessionCipher>{
+        let peer = PublicKey::from(peer);
+        let shared = self.secret.diffie_hellman(&peer);
+        if !shared.was_contributory() {return None;}
+
+        let (client, server) = match role{
+            Role::Client => (self.public, peer),
+            Role::Server => (peer, self.public),
+        };
+        let mut salt = [0u8; 64];
+        salt[..32].copy_from_slice(client.as_bytes());
+        salt[32..].copy_from_slice(server.as_bytes());
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+        let mut client_key = [0u8; 32];
+        let mut server_key = [0u8; 32];
+        hkdf.expand(b"ztp client to server", &mut client_key).ok()?;
+        hkdf.expand(b"ztp server to client", &mut server_key).ok()?;
+
+        let (tx, rx) = match role{
+            Role::Client => (client_key, server_key),
+            Role::Server => (server_key, client_key),
+        };
+        Some(SessionCipher{
+            tx: ChaCha20Poly1305::new(&tx.into()),
+            rx: ChaCha20Poly1305::new(&rx.into()),
+        })
+    }
+}
+
+/*================================================= SESSION CIPHER ============================================================= */
+
+// ChaCha20-Poly1305 with the pkg_id as nonce. Each direction has its own key and a
+// retransmitted piece carries the same bytes, so a nonce never covers two plaintexts.
+pub struct SessionCipher{
+    tx: ChaCha20Poly1305,
+    rx: ChaCha20Poly1305,
+}
+
+impl SessionCipher{
+    // The piece hash is encrypted along with the payload so it does not leak anything
+    // about the plaintext: ciphertext = AEAD(payload | hash | hash length).
+    pub fn encrypt(&self, response: &mut ZTPResponse) -> Result<(), Error>{
+        let Some(pkg_id) = response.get_pkg_id() else{
+            return Err(Error::new(ErrorKind::InvalidInput, "pieces need a pkg_id to be encrypted"));
+        };
+        let Some((mut bytes, hash)) = response.take_bytes() else{
+            return Ok(());
+        };
+        let hash = hash.unwrap_or_default();
+        bytes.extend_from_slice(&hash);
+        bytes.push(hash.len() as u8);
+
+        let aad = pkg_id.to_be_bytes();
+        let sealed = self.tx
+            .encrypt(&nonce(pkg_id), Payload{msg: &bytes, aad: &aad})
+            .map_err(|_| Error::other("encryption failed"))?;
+        response.set_bytes(sealed, None);
+        Ok(())
+    }
+
+    pub fn decrypt(&self, response: &mut ZTPResponse) -> Result<(), Error>{
+        let invalid = || Error::new(ErrorKind::InvalidData, "piece failed to decrypt");
+        let Some((sealed, _)) = response.take_bytes() else{
+            return Ok(());
+        };
+        let pkg_id = response.get_pkg_id().ok_or_else(invalid)?;
+
+        let aad = pkg_id.to_be_bytes();
+        let mut bytes = self.rx
+            .decrypt(&nonce(pkg_id), Payload{msg: &sealed, aad: &aad})
+            .map_err(|_| invalid())?;
+        let hash_len = bytes.pop().ok_or_else(invalid)? as usize;
+        let hash_start = bytes.len().checked_sub(hash_len).ok_or_else(invalid)?;
+        let hash = bytes.split_off(hash_start);
+
+        response.set_bytes(bytes, (!hash.is_empty()).then_some(hash));
+        Ok(())
+    }
+}
+
+fn nonce(pkg_id: u64) -> Nonce{
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&pkg_id.to_be_bytes());
+    nonce.into()
+}
+
+/*================================================= CRYPTO LINK ============================================================= */
+
+// Encrypts the Bytes payload of every response once the session is established,
+// everything else (requests, acks, metadata) goes through as is.
+pub struct CryptoLink<L: Link>{
+    link: L,
+    cipher: OnceLock<SessionCipher>,
+}
+
+impl<L: Link> CryptoLink<L>{
+    pub fn new(link: L) -> CryptoLink<L>{
+        CryptoLink{link, cipher: OnceLock::new()}
+    }
+
+    pub fn establish(&self, cipher: SessionCipher){
+        let _ = self.cipher.set(cipher);
+    }
+
+    pub fn is_encrypted(&self) -> bool{
+        self.cipher.get().is_some()
+    }
+
+    pub fn inner(&self) -> &L{
+        &self.link
+    }
+}
+
+impl<L: Link> Link for CryptoLink<L>{
+    fn send(&self, buff: &[u8]) -> Result<usize, Error>{
+        let Some(cipher) = self.cipher.get() else{
+            return self.link.send(buff);
+        };
+        let Ok((mut response, _)) = ZTPResponse::decode_from_slice(buff) else{
+            return self.link.send(buff);
+        };
+        if response.get_bytes().is_none(){
+            return self.link.send(buff);
+        }
+
+        cipher.encrypt(&mut response)?;
+        let sealed = response.encode_to_vec().map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        self.link.send(&sealed)
+    }
+
+    fn recv(&self, buff: &mut [u8]) -> Result<usize, Error>{
+        let bytes = self.link.recv(buff)?;
+        let Some(cipher) = self.cipher.get() else{
+            return Ok(bytes);
+        };
+        let Ok((mut response, _)) = ZTPResponse::decode_from_slice(&buff[..bytes]) else{
+            return Ok(bytes);
+        };
+        if response.get_bytes().is_none(){
+            return Ok(bytes);
+        }
+
+        if let Err(e) = cipher.decrypt(&mut response){
+            trace!("Dropping piece {:?}: {e}", response.get_pkg_id());
+            return Err(e);
+        }
+        let plain = response.encode_to_vec().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if plain.len() > buff.len(){
+            return Err(Error::new(ErrorKind::InvalidData, "decrypted piece does not fit the buffer"));
+        }
+        buff[..plain.len()].copy_from_slice(&plain);
+        Ok(plain.len())
+    }
+
+    fn wait(&self, deadline: Instant) -> impl Future<Output = Result<bool, Error>> + Send{
+        self.link.wait(deadline)
+    }
+
+    fn offload<T: Send + 'static>(&self, work: impl FnOnce() -> T + Send + 'static) -> impl Future<Output = T> + Send{
+        self.link.offload(work)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::testing::socket_pair;
+    use super::super::transfer::block_on;
+    use super::super::ztp::{ZTPChecksum, ZTPResponseCode, ZTPResponseData};
+
+    fn ciphers() -> (SessionCipher, SessionCipher){
+        let client = Handshake::new();
+        let server = Handshake::new();
+        let (client_key, server_key) = (client.public_key(), server.public_key());
+        (client.finish(server_key, Role::Client).unwrap(), server.finish(client_key, Role::Server).unwrap())
+    }
+
+    fn piece(pkg_id: u64) -> ZTPResponse{
+        ZTPResponse::new_piece(b"some piece".to_vec(), pkg_id, ZTPChecksum::Sha256)
+    }
+
+    // The Data response carrying `sealed` under another pkg_id.
+    fn with_pkg_id(sealed: &ZTPResponse, pkg_id: u64) -> ZTPResponse{
+        let bytes = sealed.get_bytes().unwrap().to_vec();
+        ZTPResponse::new(ZTPResponseCode::Data, Some(ZTPResponseData::Bytes(bytes)), Some(pkg_id))
+    }
+
+    #[test]
+    fn pieces_round_trip_in_both_directions(){
+        let (client, server) = ciphers();
+        for (tx, rx) in [(&client, &server), (&server, &client)]{
+            let mut response = piece(7);
+            tx.encrypt(&mut response).unwrap();
+            assert_ne!(response.get_bytes(), Some(&b"some piece"[..]));
+            assert_eq!(response.get_hash(), None);
+
+            rx.decrypt(&mut response).unwrap();
+            assert_eq!(response.get_bytes(), Some(&b"some piece"[..]));
+            assert_eq!(response.get_pkg_id(), Some(7));
+            assert_eq!(response.hash_and_cmp(ZTPChecksum::Sha256), Some(true));
+        }
+    }
+
+    #[test]
+    fn each_direction_has_its_own_key(){
+        let (client, _server) = ciphers();
+        let mut response = piece(7);
+        client.encrypt(&mut response).unwrap();
+        assert_eq!(client.decrypt(&mut response).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_piece_under_another_pkg_id_does_not_decrypt(){
+        let (client, server) = ciphers();
+        let mut response = piece(5);
+        client.encrypt(&mut response).unwrap();
+
+        for pkg_id in [4, 6, 5 + (1 << 32)]{
+            let mut moved = with_pkg_id(&response, pkg_id);
+            assert_eq!(server.decrypt(&mut moved).unwrap_err().kind(), ErrorKind::InvalidData, "{pkg_id}");
+        }
+        let mut unchanged = with_pkg_id(&response, 5);
+        server.decrypt(&mut unchanged).unwrap();
+        assert_eq!(unchanged.get_bytes(), Some(&b"some piece"[..]));
+    }
+
+    #[test]
+    fn a_tampered_piece_does_not_decrypt(){
+        let (client, server) = ciphers();
+        let mut response = piece(5);
+        client.encrypt(&mut response).unwrap();
+        let mut bytes = response.get_bytes().unwrap().to_vec();
+        bytes[0] ^= 0x01;
+        let mut tampered = ZTPResponse::new(ZTPResponseCode::Data, Some(ZTPResponseData::Bytes(bytes)), Some(5));
+        assert!(server.decrypt(&mut tampered).is_err());
+    }
+
+    #[test]
+    fn pieces_need_a_pkg_id(){
+        let (client, _server) = ciphers();
+        let mut response = ZTPResponse::new(ZTPResponseCode::Data, Some(ZTPResponseData::Bytes(b"x".to_vec())), None);
+        assert_eq!(client.encrypt(&mut response).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn finish_agrees_on_keys_only_with_the_right_peer(){
+        let client = Handshake::new();
+        let server = Handshake::new();
+        let eve = Handshake::new();
+        let (client_key, eve_key) = (client.public_key(), eve.public_key());
+        let client = client.finish(eve_key, Role::Client).unwrap();
+        let server = server.finish(client_key, Role::Server).unwrap();
+
+        let mut response = piece(1);
+        client.encrypt(&mut response).unwrap();
+        assert!(server.decrypt(&mut response).is_err());
+    }
+
+    #[test]
+    fn finish_needs_opposite_roles(){
+        let a = Handshake::new();
+        let b = Handshake::new();
+        let (a_key, b_key) = (a.public_key(), b.public_key());
+        let a = a.finish(b_key, Role::Client).unwrap();
+        let b = b.finish(a_key, Role::Client).unwrap();
+
+        let mut response = piece(1);
+        a.encrypt(&mut response).unwrap();
+        assert!(b.decrypt(&mut response).is_err());
+    }
+
+    #[test]
+    fn finish_refuses_low_order_keys(){
+        assert!(Handshake::new().finish([0u8; 32], Role::Client).is_none());
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        assert!(Handshake::new().finish(one, Role::Server).is_none());
+    }
+
+    #[test]
+    fn the_crypto_link_only_encrypts_pieces(){
+        let (a, b) = socket_pair();
+        let (client, server) = ciphers();
+        let sender = CryptoLink::new(a);
+        let receiver = CryptoLink::new(b.try_clone().unwrap());
+        sender.establish(client);
+        receiver.establish(server);
+        let mut buff = [0u8; 4096];
+        let wait = || assert!(block_on(b.wait(Instant::now() + Duration::from_secs(1))).unwrap());
+
+        let plain = piece(3).encode_to_vec().unwrap();
+        sender.send(&plain).unwrap();
+        wait();
+        let bytes = b.peek(&mut buff).unwrap();
+        assert!(!buff[..bytes].windows(10).any(|window| window == b"some piece"));
+        let bytes = receiver.recv(&mut buff).unwrap();
+        let (res, _) = ZTPResponse::decode_from_slice(&buff[..bytes]).unwrap();
+        assert_eq!(res.get_bytes(), Some(&b"some piece"[..]));
+        assert_eq!(res.hash_and_cmp(ZTPChecksum::Sha256), Some(true));
+
+        let ack = ZTPResponse::new(ZTPResponseCode::Ack, None, Some(3)).encode_to_vec().unwrap();
+        sender.send(&ack).unwrap();
+        wait();
+        let bytes = receiver.recv(&mut buff).unwrap();
+        assert_eq!(&buff[..bytes], &ack[..]);
+    }
+}
@@ -0,0 +1,63 @@
+use std::{fmt, io};
+
+use super::ztp::{ZTPErrorCode, ZTPErrorReport, ZTPWireError};
+
+/*================================================= ZTP ERROR ============================================================= */
+
+// Everything the client and server APIs fail with.
+#[derive(Debug)]
+pub enum ZtpError{
+    // an Error the peer sent, or the one we sent it when giving up
+    Report(ZTPErrorReport),
+    // a datagram we could not encode or decode
+    Wire(ZTPWireError),
+    Timeout(String),
+    Integrity(String),
+    Handshake,
+    Config(String),
+    Io(io::Error),
+}
+
+impl ZtpError{
+    // The code of a reported error, None for errors that never went on the wire.
+    pub fn code(&self) -> Option<ZTPErrorCode>{
+        match self{
+            ZtpError::Report(report) => Some(report.code),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for ZtpError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            ZtpError::Report(report) => write!(f, "{report}"),
+            ZtpError::Wire(e) => write!(f, "{e}"),
+            ZtpError::Timeout(message) => write!(f, "Connection Timeout: {message}"),
+            ZtpError::Integrity(message) => write!(f, "Integrity check failed: {message}"),
+            ZtpError::Handshake => write!(f, "Server sent an invalid public key"),
+            ZtpError::Config(message) => write!(f, "{message}"),
+            ZtpError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ZtpError{}
+
+impl From<io::Error> for ZtpError{
+    fn from(e: io::Error) -> Self{
+        ZtpError::Io(e)
+    }
+}
+
+impl From<ZTPErrorReport> for ZtpError{
+    fn from(report: ZTPErrorReport) -> Self{
+        ZtpError::Report(report)
+    }
+}
+
+impl From<ZTPWireError> for ZtpError{
+    fn from(e: ZTPWireError) -> Self{
+        ZtpError::Wire(e)
+    }
+}
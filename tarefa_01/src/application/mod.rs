@@ -1,3 +1,12 @@
 pub mod server;
 pub mod client;
 pub mod ztp;
+pub(crate) mod transfer;
+pub(crate) mod rtt;
+pub mod auth;
+pub(crate) mod crypto;
+pub(crate) mod session;
+pub mod error;
+
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,135 @@
+use std::{fmt, time::Duration};
+
+use crate::constants::*;
+
+/*================================================= RTT ESTIMATOR ============================================================= */
+
+// Jacobson/Karels estimator (RFC 6298). Samples must only come from pieces that
+// were sent once (Karn's algorithm), retransmissions back the timeout off instead.
+#[derive(Clone, Copy, Debug)]
+pub struct RttEstimator{
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    max_retries: usize,
+}
+
+impl Default for RttEstimator{
+    fn default() -> Self{
+        RttEstimator::new()
+    }
+}
+
+impl RttEstimator{
+    pub fn new() -> RttEstimator{
+        RttEstimator::with_limits(Duration::from_millis(TTL_MILLIS), MAX_RETRIES)
+    }
+
+    // `initial_rto` is used until the first sample arrives
+    pub fn with_limits(initial_rto: Duration, max_retries: usize) -> RttEstimator{
+        RttEstimator{
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: clamp_rto(initial_rto),
+            max_retries,
+        }
+    }
+
+    pub fn sample(&mut self, rtt: Duration){
+        let srtt = match self.srtt{
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            },
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+
+        let granularity = Duration::from_millis(POLL_MILLIS);
+        self.rto = clamp_rto(srtt + granularity.max(self.rttvar * 4));
+    }
+
+    pub fn srtt(&self) -> Option<Duration>{
+        self.srtt
+    }
+
+    pub fn rttvar(&self) -> Duration{
+        self.rttvar
+    }
+
+    pub fn rto(&self) -> Duration{
+        self.rto
+    }
+
+    pub fn max_retries(&self) -> usize{
+        self.max_retries
+    }
+
+    // timeout for the `tries`-th retransmission of the same piece
+    pub fn backoff(&self, tries: usize) -> Duration{
+        let factor = 1u32 << tries.min(16);
+        clamp_rto(self.rto.saturating_mul(factor))
+    }
+
+    // how long a peer keeps retrying before giving up, receivers wait at least that long
+    pub fn give_up_after(&self) -> Duration{
+        (0..=self.max_retries).map(|tries| self.backoff(tries)).sum()
+    }
+}
+
+fn clamp_rto(rto: Duration) -> Duration{
+    rto.clamp(
+        Duration::from_millis(MIN_RTO_MILLIS),
+        Duration::from_millis(MAX_RTO_MILLIS),
+    )
+}
+
+/*================================================= TRANSFER STATS ============================================================= */
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransferStats{
+    pub rtt: RttEstimator,
+    pub pieces_sent: u64,
+    pub retransmissions: u64,
+    pub pieces_received: u64,
+    pub pieces_rejected: u64,
+}
+
+impl TransferStats{
+    pub fn new() -> TransferStats{
+        TransferStats::default()
+    }
+
+    pub fn with_rtt(rtt: RttEstimator) -> TransferStats{
+        TransferStats{rtt, ..Default::default()}
+    }
+
+    pub fn count_sent(&mut self, tries: usize){
+        self.pieces_sent += 1;
+        if tries > 0{
+            self.retransmissions += 1;
+        }
+    }
+}
+
+impl fmt::Display for TransferStats{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        let srtt = match self.rtt.srtt(){
+            Some(srtt) => format!("{:.2}ms", srtt.as_secs_f64() * 1000.0),
+            None => "n/a".to_string()
+        };
+        write!(
+            f,
+            "srtt={srtt} rttvar={:.2}ms rto={:.2}ms sent={} retransmitted={} received={} rejected={}",
+            self.rtt.rttvar().as_secs_f64() * 1000.0,
+            self.rtt.rto().as_secs_f64() * 1000.0,
+            self.pieces_sent,
+            self.retransmissions,
+            self.pieces_received,
+            self.pieces_rejected,
+        )
+    }
+}
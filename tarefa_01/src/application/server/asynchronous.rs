@@ -0,0 +1,226 @@
+use std::{
+    collections::VecDeque, future::Future, io::{Error, ErrorKind},
+    net::SocketAddr, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::{Duration, Instant}
+};
+use log::{error, info};
+use tokio::{
+    net::UdpSocket, sync::{mpsc::{self, UnboundedSender}, Notify}, time
+};
+
+use crate::constants::*;
+
+use super::super::auth::Keyring;
+use super::super::error::ZtpError;
+use super::super::transfer::{asynchronous, Link};
+use super::dispatcher::{Deliver, Dispatcher, Route};
+use super::{run_session, ServerConfig, SessionInbox, ZtpServerBuilder};
+
+/*================================================= ASYNC SERVER ============================================================= */
+
+// `ZtpServer` on tokio: every session is a task instead of a worker thread, so
+// `pool_size` only caps how many sessions run at once and can go to thousands.
+// Tasks sleep on their inbox and on retransmission timers, an idle server costs
+// next to nothing.
+pub struct AsyncZtpServer{
+    config: Arc<ServerConfig>,
+    keyring: Option<Arc<Keyring>>,
+}
+
+impl Default for AsyncZtpServer{
+    fn default() -> Self{
+        AsyncZtpServer::new(ServerConfig::default())
+    }
+}
+
+impl AsyncZtpServer{
+
+    pub fn new(config: ServerConfig) -> AsyncZtpServer{
+        AsyncZtpServer{config: Arc::new(config), keyring: None}
+    }
+
+    pub fn with_keyring(mut self, keyring: Keyring) -> AsyncZtpServer{
+        self.keyring = Some(Arc::new(keyring));
+        self
+    }
+
+    // Same dispatching as `ZtpServer::run`, waiting on the socket, the finished
+    // sessions and the expiry sweep at once.
+    pub async fn run(&mut self) -> Result<(), ZtpError>{
+        info!("Initializing Server");
+        let socket = Arc::new(UdpSocket::bind(&self.config.bind_address).await?);
+        info!("Serving {} on {}", self.config.resource_root, self.config.bind_address);
+        let mut buffer: [u8; 4096] = [0; 4096];
+        let mut dispatcher = Dispatcher::new(&self.config);
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<u32>();
+        let mut sweep = time::interval(Duration::from_millis(SESSION_SWEEP_MILLIS));
+
+        loop{
+            tokio::select!{
+                received = socket.recv_from(&mut buffer) => match received{
+                    Ok((bytes, addr)) => self.dispatch(&mut dispatcher, &socket, &buffer[..bytes], addr, &sender),
+                    Err(e) => error!("Failed to receive datagram: {e}")
+                },
+                Some(session_id) = receiver.recv() => dispatcher.end(session_id),
+                _ = sweep.tick() => dispatcher.expire()
+            }
+        }
+    }
+
+    fn dispatch(
+        &self,
+        dispatcher: &mut Dispatcher<InboxSender>,
+        socket: &Arc<UdpSocket>,
+        datagram: &[u8],
+        addr: SocketAddr,
+        sender: &UnboundedSender<u32>
+    ){
+        let nonce = match dispatcher.route(datagram, addr){
+            Route::Handled => return,
+            Route::Busy(busy) => {
+                let _ = socket.try_send_to(&busy, addr);
+                return;
+            },
+            Route::Open(nonce) => nonce
+        };
+
+        let inbox = Arc::new(Inbox::default());
+        let session_id = dispatcher.start(addr, nonce, InboxSender(Arc::clone(&inbox)), datagram);
+
+        let link = InboxLink{socket: Arc::clone(socket), addr, inbox};
+        tokio::spawn(handle_session(session_id, link, sender.clone(), self.keyring.clone(), Arc::clone(&self.config)));
+    }
+}
+
+impl ZtpServerBuilder{
+    pub fn build_async(self) -> Result<AsyncZtpServer, ZtpError>{
+        let (config, keyring) = self.validated()?;
+        let server = AsyncZtpServer::new(config);
+        Ok(match keyring{
+            Some(keyring) => server.with_keyring(keyring),
+            None => server
+        })
+    }
+}
+
+/*================================================= INBOX LINK ============================================================= */
+
+// What the dispatcher routed to a session, waiting to be read.
+#[derive(Default)]
+struct Inbox{
+    queue: Mutex<VecDeque<Vec<u8>>>,
+    ready: Notify,
+    closed: AtomicBool,
+}
+
+impl Inbox{
+    fn push(&self, datagram: Vec<u8>){
+        if let Ok(mut queue) = self.queue.lock(){
+            queue.push_back(datagram);
+        }
+        self.ready.notify_one();
+    }
+
+    fn pop(&self) -> Option<Vec<u8>>{
+        self.queue.lock().ok()?.pop_front()
+    }
+
+    fn is_empty(&self) -> bool{
+        self.queue.lock().map_or(true, |queue| queue.is_empty())
+    }
+
+    fn close(&self){
+        self.closed.store(true, Ordering::Relaxed);
+        self.ready.notify_one();
+    }
+
+    fn is_closed(&self) -> bool{
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+// The dispatcher's end of an inbox. A session dropped by the dispatcher, finished
+// or expired, closes its inbox, like dropping the `Sender` of a worker's inbox.
+struct InboxSender(Arc<Inbox>);
+
+impl Deliver for InboxSender{
+    fn deliver(&self, datagram: Vec<u8>){
+        self.0.push(datagram);
+    }
+}
+
+impl Drop for InboxSender{
+    fn drop(&mut self){
+        self.0.close();
+    }
+}
+
+// Sends straight to the shared socket and receives what the dispatcher routed to this session.
+struct InboxLink{
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    inbox: Arc<Inbox>,
+}
+
+impl Link for InboxLink{
+    fn send(&self, buff: &[u8]) -> Result<usize, Error>{
+        self.socket.try_send_to(buff, self.addr)
+    }
+
+    fn recv(&self, buff: &mut [u8]) -> Result<usize, Error>{
+        let Some(datagram) = self.inbox.pop() else{
+            if self.inbox.is_closed(){
+                return Err(Error::new(ErrorKind::ConnectionAborted, "session expired"));
+            }
+            return Err(Error::from(ErrorKind::WouldBlock));
+        };
+        let bytes = datagram.len().min(buff.len());
+        buff[..bytes].copy_from_slice(&datagram[..bytes]);
+        Ok(bytes)
+    }
+
+    fn wait(&self, deadline: Instant) -> impl Future<Output = Result<bool, Error>> + Send{
+        asynchronous::wait_readable(self.readable(), deadline)
+    }
+
+    fn offload<T: Send + 'static>(&self, work: impl FnOnce() -> T + Send + 'static) -> impl Future<Output = T> + Send{
+        asynchronous::offload(work)
+    }
+}
+
+impl SessionInbox for InboxLink{
+    fn addr(&self) -> SocketAddr{
+        self.addr
+    }
+}
+
+impl InboxLink{
+    async fn readable(&self) -> Result<(), Error>{
+        loop{
+            if !self.inbox.is_empty(){
+                return Ok(());
+            }
+            if self.inbox.is_closed(){
+                return Err(Error::new(ErrorKind::ConnectionAborted, "session expired"));
+            }
+            self.inbox.ready.notified().await;
+        }
+    }
+}
+
+/*================================================= HANDLERS ============================================================= */
+
+// Same session as on a worker thread, awaiting where the worker would block.
+async fn handle_session(
+    session_id: u32,
+    inbox_link: InboxLink,
+    sender: UnboundedSender<u32>,
+    keyring: Option<Arc<Keyring>>,
+    config: Arc<ServerConfig>
+){
+    // the dispatcher queued the Open before starting the task
+    if let Some(open) = inbox_link.inbox.pop(){
+        run_session(session_id, &open, inbox_link, keyring.as_deref(), &config).await;
+    }
+    let _ = sender.send(session_id);
+}
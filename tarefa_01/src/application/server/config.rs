@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap, fs, net::SocketAddr, path::Path, str::FromStr, time::Duration
+};
+use serde::Deserialize;
+
+use crate::constants::*;
+
+use super::super::auth::Permission;
+use super::super::error::ZtpError;
+use super::super::rtt::RttEstimator;
+use super::sandbox::Sandbox;
+
+/*================================================= SERVER CONFIG ============================================================= */
+
+// Everything the server can be tuned with. Read from a TOML file (`config=<file>`)
+// whose keys are the field names, any field given as `<field>=<value>` on the
+// command line wins over the file, and whatever is missing keeps its default.
+// `allow` and `deny` are lists of globs on resource names, comma separated on
+// the command line. `anonymous` is what sessions without a key may do, `ro` unless
+// set to `rw`, keys carry their own permission in the key file. `corrupt_percent`
+// is fault injection, that share of the uploaded pieces is rejected as corrupt.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig{
+    pub bind_address: String,
+    pub resource_root: String,
+    pub pool_size: usize,
+    pub ttl_millis: u64,
+    pub max_retries: usize,
+    pub piece_size: usize,
+    pub session_idle_millis: u64,
+    pub keys: Option<String>,
+    pub anonymous: Permission,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub corrupt_percent: u8,
+}
+
+impl Default for ServerConfig{
+    fn default() -> Self{
+        ServerConfig{
+            bind_address: SERVER_ADDRESS.to_string(),
+            resource_root: RESOURCE_ROOT.to_string(),
+            pool_size: THREAD_POOL_SIZE,
+            ttl_millis: TTL_MILLIS,
+            max_retries: MAX_RETRIES,
+            piece_size: DATA_PIECE_SIZE,
+            session_idle_millis: SESSION_IDLE_MILLIS,
+            keys: None,
+            anonymous: Permission::ReadOnly,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            corrupt_percent: 0,
+        }
+    }
+}
+
+impl ServerConfig{
+    pub fn load(path: &str) -> Result<ServerConfig, ZtpError>{
+        let text = fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| ZtpError::Config(format!("{path}: {e}")))
+    }
+
+    // The file named by `config`, if any, overridden by the other vars, then validated.
+    pub fn from_vars(var_map: &HashMap<String, String>) -> Result<ServerConfig, ZtpError>{
+        let mut config = match var_map.get("config"){
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default()
+        };
+        for (key, value) in var_map{
+            config.set(key, value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Anything but a server setting, or one of the vars that start the server, is a
+    // typo to report, as an unknown field of the file is.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ZtpError>{
+        match key{
+            "bind_address" => self.bind_address = value.to_string(),
+            "resource_root" => self.resource_root = value.to_string(),
+            "pool_size" => self.pool_size = parse_var(key, value)?,
+            "ttl_millis" => self.ttl_millis = parse_var(key, value)?,
+            "max_retries" => self.max_retries = parse_var(key, value)?,
+            "piece_size" => self.piece_size = parse_var(key, value)?,
+            "session_idle_millis" => self.session_idle_millis = parse_var(key, value)?,
+            "keys" => self.keys = Some(value.to_string()),
+            "anonymous" => self.anonymous = value.parse().map_err(|e| ZtpError::Config(format!("anonymous: {e}")))?,
+            "allow" => self.allow = split_list(value),
+            "deny" => self.deny = split_list(value),
+            "corrupt_percent" => self.corrupt_percent = parse_var(key, value)?,
+            "role" | "config" | "async" => {},
+            _ => return Err(ZtpError::Config(format!("unknown setting {key}={value}")))
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ZtpError>{
+        let invalid = |message: String| Err(ZtpError::Config(message));
+
+        if self.bind_address.parse::<SocketAddr>().is_err(){
+            return invalid(format!("bind_address {} is not an ip:port address", self.bind_address));
+        }
+        if !Path::new(&self.resource_root).is_dir(){
+            return invalid(format!("resource_root {} is not a directory", self.resource_root));
+        }
+        self.sandbox()?;
+        if self.pool_size == 0{
+            return invalid("pool_size must be at least 1".to_string());
+        }
+        if !(MIN_RTO_MILLIS..=MAX_RTO_MILLIS).contains(&self.ttl_millis){
+            return invalid(format!("ttl_millis must be between {MIN_RTO_MILLIS} and {MAX_RTO_MILLIS}"));
+        }
+        if self.corrupt_percent > 100{
+            return invalid("corrupt_percent must be between 0 and 100".to_string());
+        }
+        if self.max_retries == 0{
+            return invalid("max_retries must be at least 1".to_string());
+        }
+        if !(1..=MAX_PIECE_SIZE).contains(&self.piece_size){
+            return invalid(format!("piece_size must be between 1 and {MAX_PIECE_SIZE}"));
+        }
+        // an idle session must outlive the retries of its own worker
+        let give_up_after = self.rtt().give_up_after();
+        if Duration::from_millis(self.session_idle_millis) < give_up_after{
+            return invalid(format!(
+                "session_idle_millis must be at least {} with these retries",
+                give_up_after.as_millis()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn sandbox(&self) -> Result<Sandbox, ZtpError>{
+        Sandbox::new(&self.resource_root, &self.allow, &self.deny).map_err(|e| ZtpError::Config(e.to_string()))
+    }
+
+    pub fn rtt(&self) -> RttEstimator{
+        RttEstimator::with_limits(Duration::from_millis(self.ttl_millis), self.max_retries)
+    }
+}
+
+fn split_list(value: &str) -> Vec<String>{
+    value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+}
+
+fn parse_var<T: FromStr>(key: &str, value: &str) -> Result<T, ZtpError>{
+    value.parse().map_err(|_| ZtpError::Config(format!("{key}={value} is not a number")))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> HashMap<String, String>{
+        vars.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn the_vars_that_start_the_server_are_accepted(){
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy();
+        let config = ServerConfig::from_vars(&vars(&[
+            ("role", "server"), ("async", "on"), ("resource_root", &root), ("pool_size", "3"),
+        ])).unwrap();
+        assert_eq!(config.pool_size, 3);
+    }
+
+    #[test]
+    fn an_unknown_setting_is_refused(){
+        let refused = ServerConfig::from_vars(&vars(&[("role", "server"), ("pool_szie", "3")]));
+        assert!(matches!(refused, Err(ZtpError::Config(message)) if message.contains("pool_szie")));
+    }
+}
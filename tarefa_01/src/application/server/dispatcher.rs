@@ -0,0 +1,215 @@
+use std::{collections::HashMap, net::SocketAddr, time::{Duration, Instant}};
+use log::{debug, warn};
+
+use super::super::ztp::{self, ZTPErrorCode, ZTPErrorReport, ZTPResponse};
+use super::ServerConfig;
+
+/*================================================= DISPATCHER ============================================================= */
+
+// Where the dispatcher puts the datagrams of a session. Dropping it tells the
+// session it expired.
+pub(super) trait Deliver{
+    fn deliver(&self, datagram: Vec<u8>);
+}
+
+// The session table both servers dispatch with: every datagram goes to the session
+// whose id it carries, an Open starts a new session. Only how a session is started
+// and what its inbox is differ between them.
+pub(super) struct Dispatcher<I: Deliver>{
+    sessions: HashMap<u32, Session<I>>,
+    pool_size: usize,
+    idle: Duration,
+}
+
+struct Session<I: Deliver>{
+    addr: SocketAddr,
+    nonce: u64,
+    inbox: I,
+    last_seen: Instant,
+}
+
+// What to do with a datagram the dispatcher could not deliver itself.
+pub(super) enum Route{
+    // delivered or dropped, nothing left to do
+    Handled,
+    // send this answer back, every session is taken
+    Busy(Vec<u8>),
+    // an Open with this nonce, start a session for it
+    Open(u64),
+}
+
+impl<I: Deliver> Dispatcher<I>{
+    pub(super) fn new(config: &ServerConfig) -> Dispatcher<I>{
+        Dispatcher{
+            sessions: HashMap::new(),
+            pool_size: config.pool_size,
+            idle: Duration::from_millis(config.session_idle_millis),
+        }
+    }
+
+    pub(super) fn route(&mut self, datagram: &[u8], addr: SocketAddr) -> Route{
+        let nonce = match ztp::peek_session_id(datagram){
+            None => {
+                warn!("Dropping non ZTP datagram from {addr}");
+                return Route::Handled;
+            },
+            Some(0) => match ztp::peek_open_nonce(datagram){
+                Some(nonce) => nonce,
+                None => {
+                    warn!("Dropping datagram without a session from {addr}");
+                    return Route::Handled;
+                }
+            },
+            Some(session_id) => {
+                match self.sessions.get_mut(&session_id){
+                    Some(session) if session.addr == addr => {
+                        session.last_seen = Instant::now();
+                        session.inbox.deliver(datagram.to_vec());
+                    },
+                    _ => debug!("Dropping datagram of unknown session {session_id:08x} from {addr}")
+                }
+                return Route::Handled;
+            }
+        };
+
+        // a retransmitted Open goes to the session it already started
+        if let Some(session) = self.sessions.values().find(|s| s.addr == addr && s.nonce == nonce){
+            session.inbox.deliver(datagram.to_vec());
+            return Route::Handled;
+        }
+
+        // answered here rather than left to time out waiting for a session, a client
+        // with a key drops the unsealed answer and times out all the same
+        if self.sessions.len() >= self.pool_size{
+            warn!("Turning {addr} away, all {} sessions are taken", self.pool_size);
+            let busy = ZTPErrorReport::new(ZTPErrorCode::ServerBusy, "the server is busy, try again later");
+            return ZTPResponse::error(busy).encode_to_vec().map_or(Route::Handled, Route::Busy);
+        }
+        Route::Open(nonce)
+    }
+
+    // Registers the session an Open starts and hands it the Open. Returns its id.
+    pub(super) fn start(&mut self, addr: SocketAddr, nonce: u64, inbox: I, open: &[u8]) -> u32{
+        let session_id = self.new_session_id();
+        inbox.deliver(open.to_vec());
+        self.sessions.insert(session_id, Session{addr, nonce, inbox, last_seen: Instant::now()});
+        session_id
+    }
+
+    pub(super) fn end(&mut self, session_id: u32){
+        debug!("Removing session {session_id:08x}");
+        self.sessions.remove(&session_id);
+    }
+
+    // Dropping the inbox of an idle session makes it give up.
+    pub(super) fn expire(&mut self){
+        let idle = self.idle;
+        self.sessions.retain(|session_id, session|{
+            let alive = session.last_seen.elapsed() < idle;
+            if !alive{
+                warn!("Session {session_id:08x} of {} expired", session.addr);
+            }
+            alive
+        });
+    }
+
+    fn new_session_id(&self) -> u32{
+        loop{
+            let session_id: u32 = rand::random();
+            if session_id != 0 && !self.sessions.contains_key(&session_id){
+                return session_id;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use super::super::super::ztp::{ZTPResponseCode, ZTPSessionControl};
+
+    type Delivered = Rc<RefCell<Vec<Vec<u8>>>>;
+
+    impl Deliver for Delivered{
+        fn deliver(&self, datagram: Vec<u8>){
+            self.borrow_mut().push(datagram);
+        }
+    }
+
+    fn dispatcher(pool_size: usize, session_idle_millis: u64) -> Dispatcher<Delivered>{
+        Dispatcher::new(&ServerConfig{pool_size, session_idle_millis, ..ServerConfig::default()})
+    }
+
+    fn addr(port: u16) -> SocketAddr{
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn open(nonce: u64) -> Vec<u8>{
+        ZTPSessionControl::open(nonce).encode_to_vec().unwrap()
+    }
+
+    fn of_session(session_id: u32) -> Vec<u8>{
+        let mut datagram = ZTPResponse::new(ZTPResponseCode::Ack, None, None).encode_to_vec().unwrap();
+        ztp::set_session_id(&mut datagram, session_id).unwrap();
+        datagram
+    }
+
+    fn start(dispatcher: &mut Dispatcher<Delivered>, port: u16, nonce: u64) -> (u32, Delivered){
+        let Route::Open(routed) = dispatcher.route(&open(nonce), addr(port)) else{
+            panic!("the Open did not start a session");
+        };
+        assert_eq!(routed, nonce);
+        let inbox = Delivered::default();
+        (dispatcher.start(addr(port), nonce, Rc::clone(&inbox), &open(nonce)), inbox)
+    }
+
+    #[test]
+    fn datagrams_go_to_the_session_of_their_id_and_address(){
+        let mut dispatcher = dispatcher(4, 60_000);
+        let (session_id, inbox) = start(&mut dispatcher, 1000, 7);
+        assert_eq!(inbox.borrow().len(), 1);
+
+        // a retransmitted Open and a datagram of the session reach it
+        assert!(matches!(dispatcher.route(&open(7), addr(1000)), Route::Handled));
+        assert!(matches!(dispatcher.route(&of_session(session_id), addr(1000)), Route::Handled));
+        assert_eq!(inbox.borrow().len(), 3);
+
+        // the same id from another address, and an unknown id, are dropped
+        assert!(matches!(dispatcher.route(&of_session(session_id), addr(1001)), Route::Handled));
+        assert!(matches!(dispatcher.route(&of_session(session_id ^ 1), addr(1000)), Route::Handled));
+        assert_eq!(inbox.borrow().len(), 3);
+
+        // once ended, its datagrams go nowhere
+        dispatcher.end(session_id);
+        assert!(matches!(dispatcher.route(&of_session(session_id), addr(1000)), Route::Handled));
+        assert_eq!(inbox.borrow().len(), 3);
+    }
+
+    #[test]
+    fn a_full_table_turns_new_sessions_away_until_one_ends(){
+        let mut dispatcher = dispatcher(1, 60_000);
+        let (session_id, _) = start(&mut dispatcher, 1000, 7);
+
+        let Route::Busy(busy) = dispatcher.route(&open(8), addr(1001)) else{
+            panic!("a second session was let in");
+        };
+        let (res, _) = ZTPResponse::decode_from_slice(&busy).unwrap();
+        assert_eq!(res.get_error().map(|error| error.code), Some(ZTPErrorCode::ServerBusy));
+
+        dispatcher.end(session_id);
+        start(&mut dispatcher, 1001, 8);
+    }
+
+    #[test]
+    fn idle_sessions_expire(){
+        let mut dispatcher = dispatcher(1, 0);
+        let (session_id, inbox) = start(&mut dispatcher, 1000, 7);
+        dispatcher.expire();
+
+        assert!(matches!(dispatcher.route(&of_session(session_id), addr(1000)), Route::Handled));
+        assert_eq!(inbox.borrow().len(), 1);
+        start(&mut dispatcher, 1001, 8);
+    }
+}
@@ -1,301 +1,918 @@
 use std::{
-    collections::HashSet, fs, io::Error, net::UdpSocket, sync::{mpsc, Arc, Mutex}, thread, time::Duration
+    cell::Cell, fs::{self, File, OpenOptions}, future::{self, Future}, io::{Cursor, Error, ErrorKind, Read, Seek, SeekFrom},
+    net::{SocketAddr, UdpSocket}, path::{Path, PathBuf},
+    sync::{mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError}, Arc, Mutex}, time::{Duration, Instant}
 };
+use log::{debug, error, info, warn};
 
 use crate::constants::*;
-use crate::application::ztp;
-
-use super::ztp::{ZTPMetadata, ZTPResponse, ZTPResponseCode, ZTPResponseData, ZTPRequest};
 
+use super::auth::{AuthLink, Key, Keyring, Permission};
+use super::crypto::{CryptoLink, Handshake, Role};
+use super::error::ZtpError;
+use super::rtt::TransferStats;
+use super::session::{self, SessionLink};
+use super::transfer::{self, Link, PieceSink};
+use super::ztp::{
+    self, to_hex, ZTPErrorCode, ZTPErrorReport, ZTPListEntry, ZTPMetadata, ZTPResponse, ZTPResponseCode, ZTPRequest,
+    ZTPRequestCode, ZTPSessionCode, ZTPSessionControl, ZTPWireError
+};
+use dispatcher::{Deliver, Dispatcher, Route};
+use sandbox::{Sandbox, UPLOAD_SUFFIX};
+
+pub use config::ServerConfig;
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncZtpServer;
+
+#[cfg(feature = "async")]
+mod asynchronous;
+mod config;
+mod dispatcher;
+mod sandbox;
 mod thread_pool;
 
 /*================================================= SERVER ============================================================= */
 
-pub struct Server{
-    connections: HashSet<String>, 
+pub struct ZtpServer{
+    config: Arc<ServerConfig>,
+    keyring: Option<Arc<Keyring>>,
 }
 
-impl Server{
+impl Default for ZtpServer{
+    fn default() -> Self{
+        ZtpServer::new(ServerConfig::default())
+    }
+}
+
+impl ZtpServer{
     
-    pub fn new() -> Server{
-        let connections = HashSet::with_capacity(THREAD_POOL_SIZE);
-        Server{connections}
+    pub fn new(config: ServerConfig) -> ZtpServer{
+        ZtpServer{config: Arc::new(config), keyring: None}
+    }
+
+    // Only requests sealed with one of these keys are served, and the whole
+    // session is then sealed with the key the client used.
+    pub fn with_keyring(mut self, keyring: Keyring) -> ZtpServer{
+        self.keyring = Some(Arc::new(keyring));
+        self
     }
 
-    pub fn run(&mut self){
-        println!("Initializing Server");
-        let socket = Arc::new(Mutex::new(UdpSocket::bind(SERVER_ADDRESS).expect("Failed to bind to address")));
-        let pool = thread_pool::ThreadPool::new(THREAD_POOL_SIZE);
+    // The only reader of the socket, see `Dispatcher`. Every session runs on a worker thread.
+    pub fn run(&mut self) -> Result<(), ZtpError>{
+        info!("Initializing Server");
+        let socket = Arc::new(UdpSocket::bind(&self.config.bind_address)?);
+        socket.set_read_timeout(Some(Duration::from_millis(SESSION_SWEEP_MILLIS)))?;
+        let pool = thread_pool::ThreadPool::new(self.config.pool_size);
+        info!("Serving {} on {}", self.config.resource_root, self.config.bind_address);
         let mut buffer: [u8; 4096] = [0; 4096];
+        let mut dispatcher = Dispatcher::new(&self.config);
         
-        let (sender, receiver) = mpsc::channel::<String>();
+        let (sender, receiver) = mpsc::channel::<u32>();
 
         let sender = Arc::new(Mutex::new(sender));
         
-        socket.lock().unwrap().set_nonblocking(true).unwrap();
         loop{
-            //check for incomming requests
-            if let Ok((_, addr)) = socket.lock().unwrap().peek_from(&mut buffer){
-                let addr_str = format!("{addr}");
-                if !self.connections.contains(&addr_str){
-                   self.connections.insert(addr_str.clone());
-                    let socket_clone = Arc::clone(&socket);
-                    let sender_clone = Arc::clone(&sender);
-                    pool.execute(move ||{
-                        handle_connection(
-                            addr_str,
-                            socket_clone,
-                            sender_clone
-                        )
-                    });
-                }
-            }
+            let received = socket.recv_from(&mut buffer);
 
-            //clear finished requests 
-            while let Ok(msg) = receiver.try_recv(){
-                println!("Removing address {msg} from set");
-                self.connections.remove(&msg);
+            //clear finished sessions
+            while let Ok(session_id) = receiver.try_recv(){
+                dispatcher.end(session_id);
             }
+            dispatcher.expire();
+
+            let (bytes, addr) = match received{
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+                Err(e) => {
+                    error!("Failed to receive datagram: {e}");
+                    continue;
+                }
+            };
+            let datagram = &buffer[..bytes];
+
+            let nonce = match dispatcher.route(datagram, addr){
+                Route::Handled => continue,
+                Route::Busy(busy) => {
+                    let _ = socket.send_to(&busy, addr);
+                    continue;
+                },
+                Route::Open(nonce) => nonce
+            };
+
+            let (inbox, session_inbox) = mpsc::channel();
+            let session_id = dispatcher.start(addr, nonce, inbox, datagram);
+
+            let socket_clone = Arc::clone(&socket);
+            let sender_clone = Arc::clone(&sender);
+            let keyring = self.keyring.clone();
+            let config = Arc::clone(&self.config);
+            pool.execute(move ||{
+                handle_connection(
+                    session_id,
+                    addr,
+                    socket_clone,
+                    session_inbox,
+                    sender_clone,
+                    keyring,
+                    config
+                )
+            });
         }
     }
 }
 
+impl Deliver for Sender<Vec<u8>>{
+    fn deliver(&self, datagram: Vec<u8>){
+        let _ = self.send(datagram);
+    }
+}
+
+/*================================================= SERVER BUILDER ============================================================= */
+
+// Starts from the default settings, or a whole `ServerConfig`, and only hands out
+// a server once they validate. Without a keyring the one named by `keys` is loaded.
+#[derive(Default)]
+pub struct ZtpServerBuilder{
+    config: ServerConfig,
+    keyring: Option<Keyring>,
+}
+
+impl ZtpServer{
+    pub fn builder() -> ZtpServerBuilder{
+        ZtpServerBuilder::default()
+    }
+}
+
+impl ZtpServerBuilder{
+    pub fn with_config(mut self, config: ServerConfig) -> ZtpServerBuilder{
+        self.config = config;
+        self
+    }
+
+    pub fn with_bind_address(mut self, bind_address: impl Into<String>) -> ZtpServerBuilder{
+        self.config.bind_address = bind_address.into();
+        self
+    }
+
+    pub fn with_resource_root(mut self, resource_root: impl Into<String>) -> ZtpServerBuilder{
+        self.config.resource_root = resource_root.into();
+        self
+    }
+
+    pub fn with_pool_size(mut self, pool_size: usize) -> ZtpServerBuilder{
+        self.config.pool_size = pool_size;
+        self
+    }
+
+    // What sessions without a key may do, read-only unless told otherwise.
+    pub fn with_anonymous(mut self, permission: Permission) -> ZtpServerBuilder{
+        self.config.anonymous = permission;
+        self
+    }
+
+    pub fn with_keyring(mut self, keyring: Keyring) -> ZtpServerBuilder{
+        self.keyring = Some(keyring);
+        self
+    }
+
+    pub fn build(self) -> Result<ZtpServer, ZtpError>{
+        let (config, keyring) = self.validated()?;
+        let server = ZtpServer::new(config);
+        Ok(match keyring{
+            Some(keyring) => server.with_keyring(keyring),
+            None => server
+        })
+    }
+
+    fn validated(self) -> Result<(ServerConfig, Option<Keyring>), ZtpError>{
+        self.config.validate()?;
+        let keyring = match (self.keyring, &self.config.keys){
+            (Some(keyring), _) => Some(keyring),
+            (None, Some(path)) => Some(
+                Keyring::load(path).map_err(|e| ZtpError::Config(format!("Failed to load keys from {path}: {e}")))?
+            ),
+            (None, None) => None
+        };
+        Ok((self.config, keyring))
+    }
+}
+
+/*================================================= INBOX LINK ============================================================= */
+
+// The link a session is served over, which knows the client it serves. Both
+// servers serve sessions the same way, only their inbox links differ.
+trait SessionInbox: Link{
+    fn addr(&self) -> SocketAddr;
+}
+
+// Sends straight to the shared socket and receives what the dispatcher routed to this session.
+// A datagram that ends a `wait` is kept in `pending` until the next `recv`.
+struct InboxLink<'a>{
+    socket: &'a UdpSocket,
+    addr: SocketAddr,
+    inbox: &'a Receiver<Vec<u8>>,
+    pending: Cell<Option<Vec<u8>>>,
+}
+
+impl Link for InboxLink<'_>{
+    fn send(&self, buff: &[u8]) -> Result<usize, Error>{
+        self.socket.send_to(buff, self.addr)
+    }
+
+    fn recv(&self, buff: &mut [u8]) -> Result<usize, Error>{
+        let datagram = match self.pending.take(){
+            Some(datagram) => datagram,
+            None => self.inbox.try_recv().map_err(|e| match e{
+                TryRecvError::Empty => Error::from(ErrorKind::WouldBlock),
+                TryRecvError::Disconnected => expired(),
+            })?
+        };
+        let bytes = datagram.len().min(buff.len());
+        buff[..bytes].copy_from_slice(&datagram[..bytes]);
+        Ok(bytes)
+    }
+
+    fn wait(&self, deadline: Instant) -> impl Future<Output = Result<bool, Error>> + Send{
+        future::ready(self.wait_inbox(deadline))
+    }
+
+    fn offload<T: Send + 'static>(&self, work: impl FnOnce() -> T + Send + 'static) -> impl Future<Output = T> + Send{
+        future::ready(work())
+    }
+}
+
+impl SessionInbox for InboxLink<'_>{
+    fn addr(&self) -> SocketAddr{
+        self.addr
+    }
+}
+
+impl InboxLink<'_>{
+    fn wait_inbox(&self, deadline: Instant) -> Result<bool, Error>{
+        let pending = self.pending.take();
+        if pending.is_some(){
+            self.pending.set(pending);
+            return Ok(true);
+        }
+        match self.inbox.recv_timeout(deadline.saturating_duration_since(Instant::now())){
+            Ok(datagram) => {
+                self.pending.set(Some(datagram));
+                Ok(true)
+            },
+            Err(RecvTimeoutError::Timeout) => Ok(false),
+            Err(RecvTimeoutError::Disconnected) => Err(expired())
+        }
+    }
+}
+
+fn expired() -> Error{
+    Error::new(ErrorKind::ConnectionAborted, "session expired")
+}
+
+type ServerLink<L> = CryptoLink<SessionLink<AuthLink<L>>>;
+
+fn peer<L: SessionInbox>(link: &ServerLink<L>) -> SocketAddr{
+    link.inner().inner().inner().addr()
+}
+
+/*================================================= HANDLERS ============================================================= */
+
 fn handle_connection(
-    addr: String,
-    socket: Arc<Mutex<UdpSocket>>,
-    sender: Arc<Mutex<mpsc::Sender<String>>>
+    session_id: u32,
+    addr: SocketAddr,
+    socket: Arc<UdpSocket>,
+    inbox: Receiver<Vec<u8>>,
+    sender: Arc<Mutex<Sender<u32>>>,
+    keyring: Option<Arc<Keyring>>,
+    config: Arc<ServerConfig>
 ){
-    println!("Starting job for addr: {addr}");
-    let mut end_request = false;
-    let mut rx_buff: [u8; 4096] = [0; 4096];
+    // the dispatcher only starts sessions on an Open
+    if let Ok(open) = inbox.recv(){
+        let inbox_link = InboxLink{socket: &socket, addr, inbox: &inbox, pending: Cell::new(None)};
+        transfer::block_on(run_session(session_id, &open, inbox_link, keyring.as_deref(), &config));
+    }
+    end_session(&sender, session_id);
+}
 
-    println!("Draning Socket for addr: {addr}");
+// Everything from the Open to the Close, whichever server the session runs on.
+async fn run_session<L: SessionInbox>(
+    session_id: u32,
+    open: &[u8],
+    inbox_link: L,
+    keyring: Option<&Keyring>,
+    config: &Arc<ServerConfig>
+){
+    let addr = inbox_link.addr();
+    info!("Starting session {session_id:08x} for addr: {addr}");
+    let Some(nonce) = ztp::peek_open_nonce(open) else{
+        return;
+    };
+    let Ok(key) = authenticate(keyring, open, addr) else{
+        return;
+    };
+    let permission = key.as_ref().map_or(config.anonymous, Key::permission);
+    let link = CryptoLink::new(SessionLink::new(AuthLink::new(inbox_link, key)));
+    link.inner().establish(session_id);
+
+    session::send_open_ack(&link, nonce);
+
+    let mut stats = TransferStats::with_rtt(config.rtt());
+    if let Some(req) = wait_for_request(&link, nonce, &mut stats).await{
+        if exchange_keys(&link, &req, &mut stats).await{
+            // like every other look at the file system, compiling the globs and resolving the root is offloaded
+            let sandbox_config = Arc::clone(config);
+            match link.offload(move || sandbox_config.sandbox()).await{
+                Ok(sandbox) => serve(&link, &req, config, &Arc::new(sandbox), permission, &mut stats).await,
+                Err(e) => unavailable(&link, addr, e)
+            }
+            info!("Transfer stats for {addr}: {stats}");
+        }
+        else{
+            warn!("Handshake with {addr} failed");
+        }
+        debug!("Sending EOR to {addr}");
+        send_end_of_req(&link);
+    }
+    wait_for_close(&link, &stats).await;
 
-    while !end_request{
-        while peek_request(&socket, &addr, &mut rx_buff).is_err(){}
-        
-        let bytes = get_request(&socket, &addr, &mut rx_buff).unwrap();
+    info!("Finishing session {session_id:08x} for address {addr}");
+}
 
-        let req = parse_request(&rx_buff[..bytes]);
-        if req.is_none(){
-            end_request = true;
+// Hands the session back to the dispatcher, which may already be gone.
+fn end_session(sender: &Mutex<Sender<u32>>, session_id: u32){
+    if let Ok(sender) = sender.lock(){
+        let _ = sender.send(session_id);
+    }
+}
+
+// The key that sealed the Open, if the server has a keyring. Err when no key did.
+fn authenticate(keyring: Option<&Keyring>, open: &[u8], addr: SocketAddr) -> Result<Option<Key>, ()>{
+    let Some(keyring) = keyring else{
+        return Ok(None);
+    };
+    match keyring.open(open){
+        Some((key, _)) => {
+            info!("Authenticated {addr} with key {}", key.id());
+            Ok(Some(key.clone()))
+        },
+        None => {
+            warn!("Rejected unauthenticated session from {addr}");
+            Err(())
+        }
+    }
+}
+
+fn unavailable(link: &impl Link, addr: SocketAddr, e: ZtpError){
+    warn!("Cannot serve {addr}: {e}");
+    transfer::send_error(link, &ZTPErrorReport::new(ZTPErrorCode::Io, "the resource root is unavailable"));
+}
+
+// Answers retransmitted Opens until the request arrives. The Metadata of a Post
+// whose request got lost is skipped, it comes again with the request. A request
+// in a version we do not speak is refused and waited for again in one we do,
+// the rest of the session is answered in the version it came in.
+async fn wait_for_request<L: SessionInbox>(link: &ServerLink<L>, nonce: u64, stats: &mut TransferStats) -> Option<ZTPRequest>{
+    let mut rx_buff = [0u8; 4096];
+    let deadline = Instant::now() + stats.rtt.give_up_after();
+    while let Some(bytes) = wait_for_datagram(link, &mut rx_buff, deadline).await{
+        let datagram = &rx_buff[..bytes];
+        if ztp::peek_open_nonce(datagram) == Some(nonce){
+            session::send_open_ack(link, nonce);
+            continue;
+        }
+        if ZTPResponse::decode_from_slice(datagram).is_ok(){
             continue;
         }
-        let req = req.unwrap();
-
-        // drain_socket(&socket, &addr);
-        println!("Client requested {}", req.get_resource());
-        let res_buff;
-        match get_resource(req.get_resource()){
-            Ok(buff)  => {res_buff = buff;},
-            Err(_) =>{
-                println!("Resource does not exist!");
-                send_not_found(&socket, &addr);
-                end_request = true;
-                continue;
+        match parse_request(link, peer(link), datagram){
+            Ok(req) => {
+                link.inner().speak(req.get_version());
+                return Some(req);
+            },
+            Err(ZTPWireError::UnsupportedVersion(_)) => continue,
+            Err(_) => return None
+        }
+    }
+    warn!("Connection Timeout: no request from {}", peer(link));
+    None
+}
+
+// Whatever is still in flight is dropped until the client closes the session.
+async fn wait_for_close<L: SessionInbox>(link: &ServerLink<L>, stats: &TransferStats){
+    let mut rx_buff = [0u8; 4096];
+    let deadline = Instant::now() + stats.rtt.give_up_after();
+    while let Some(bytes) = wait_for_datagram(link, &mut rx_buff, deadline).await{
+        if is_close(&rx_buff[..bytes]){
+            debug!("Session closed by {}", peer(link));
+            return;
+        }
+    }
+}
+
+fn is_close(datagram: &[u8]) -> bool{
+    ZTPSessionControl::decode_from_slice(datagram).is_ok_and(|(control, _)| control.get_code() == ZTPSessionCode::Close)
+}
+
+async fn wait_for_datagram(link: &impl Link, rx_buff: &mut [u8], deadline: Instant) -> Option<usize>{
+    loop{
+        match link.recv(rx_buff){
+            Ok(bytes) => return Some(bytes),
+            Err(e) if e.kind() == ErrorKind::ConnectionAborted => return None,
+            Err(_) => {
+                if !link.wait(deadline).await.ok()?{
+                    return None;
+                }
             }
         }
-        let metadata = ZTPResponse::new(
-            ZTPResponseCode::Metadata,
-            Some(ZTPResponseData::Metadata(
-                ZTPMetadata::from_bytes(&res_buff)
-            )),
-            None
-        );
-        println!("Sending Metadata to {addr}");
-        send_metadata(&socket, &addr, metadata);
-        println!("Sending Resource to {addr}");
-        send_resource(&socket, &addr, &res_buff);
-        end_request = true;
     }
-    println!("Sending EOR to {addr}");
-    send_end_of_req(&socket, &addr);
-    thread::sleep(Duration::from_millis(TTL_MILLIS));
-    drain_socket(&socket, &addr);
+}
 
-    println!("Finishing job for address {addr}");
-    sender.lock().unwrap().send(addr).unwrap();
+// Clients that sent a public key get an encrypted session, the others a plain one.
+async fn exchange_keys<L: SessionInbox>(link: &ServerLink<L>, req: &ZTPRequest, stats: &mut TransferStats) -> bool{
+    let Some(peer) = req.get_public_key() else{
+        return true;
+    };
+    let handshake = Handshake::new();
+    let public_key = handshake.public_key();
+    let Some(cipher) = handshake.finish(peer, Role::Server) else{
+        return false;
+    };
+    if !transfer::send_handshake(link, public_key, stats).await{
+        return false;
+    }
+    link.establish(cipher);
+    true
 }
 
-fn parse_request(buffer: &[u8]) -> Option<ZTPRequest>{
-    if let Ok(req) = ZTPRequest::decode_from_slice(buffer){
-       return Some(req.0); 
+async fn serve<L: SessionInbox>(
+    link: &ServerLink<L>,
+    req: &ZTPRequest,
+    config: &ServerConfig,
+    sandbox: &Arc<Sandbox>,
+    permission: Permission,
+    stats: &mut TransferStats
+){
+    if !permitted(link, req, permission){
+        return;
+    }
+    match req.get_code(){
+        ZTPRequestCode::Get => serve_get(link, req, config, sandbox, stats).await,
+        ZTPRequestCode::Post => serve_post(link, req.get_resource(), config, sandbox, stats).await,
+        ZTPRequestCode::List => serve_list(link, req, config, sandbox, stats).await,
+        ZTPRequestCode::Stat => serve_stat(link, req, config, sandbox, stats).await,
+        ZTPRequestCode::Delete | ZTPRequestCode::Rename => {
+            let (code, sandbox) = (req.get_code(), Arc::clone(sandbox));
+            let (resource_name, target_name) = (req.get_resource().to_string(), req.get_target().unwrap_or_default().to_string());
+            let changed = link.offload(move || match code{
+                ZTPRequestCode::Delete => delete_entry(&resource_name, &sandbox),
+                _ => rename_entry(&resource_name, &target_name, &sandbox)
+            });
+            answer(link, changed.await, stats).await
+        },
     }
-    None
 }
 
-fn get_request(socket: &Arc<Mutex<UdpSocket>>, addr: &str, buffer: &mut[u8]) -> Result<usize, Error>{
-    let locked_socket = socket.lock().unwrap();
-    locked_socket.connect(addr).unwrap();
-    locked_socket.recv(buffer)
+fn permitted(link: &impl Link, req: &ZTPRequest, permission: Permission) -> bool{
+    if req.get_code().writes() && !permission.can_write(){
+        warn!("Refusing {:?} of {} from a read-only session", req.get_code(), req.get_resource());
+        refuse(link, ZTPErrorCode::PermissionDenied, req.get_resource());
+        return false;
+    }
+    true
 }
 
-fn peek_request(socket: &Arc<Mutex<UdpSocket>>, addr: &str, buffer: &mut[u8]) -> Result<usize, Error>{
-    let locked_socket = socket.lock().unwrap();
-    locked_socket.connect(addr).unwrap();
-    locked_socket.peek(buffer)
+async fn serve_get<L: SessionInbox>(link: &ServerLink<L>, req: &ZTPRequest, config: &ServerConfig, sandbox: &Arc<Sandbox>, stats: &mut TransferStats){
+    info!("Client requested {} from piece {}", req.get_resource(), req.get_start_pkg());
+    if let Some((file, metadata)) = open_resource(link, req, sandbox).await{
+        send_source(link, file, metadata, req, config, stats).await;
+    }
 }
 
-fn get_resource(resource_name: &str) -> Result<Vec<u8>, Error>{ 
-    let path = format!("./resources/{resource_name}");
-    fs::read(&path)
+// Only the metadata is sent, the client already knows everything it asked for.
+async fn serve_stat<L: SessionInbox>(link: &ServerLink<L>, req: &ZTPRequest, config: &ServerConfig, sandbox: &Arc<Sandbox>, stats: &mut TransferStats){
+    info!("Client asked for the metadata of {}", req.get_resource());
+    if let Some((_, metadata)) = open_resource(link, req, sandbox).await{
+        let metadata = metadata.with_piece_size(config.piece_size);
+        debug!("Sending Metadata to {}", peer(link));
+        transfer::send_metadata(link, None, metadata, stats).await;
+    }
 }
 
-fn send_not_found(socket: &Arc<Mutex<UdpSocket>>, addr: &str) -> usize{
-    let not_found_res = ZTPResponse::new(ZTPResponseCode::NotFound, None, None);
-    let vec = ZTPResponse::encode_to_vec(
-        not_found_res,
-    ).unwrap();
+// Refuses the request itself when the resource cannot be served. Resolving it and
+// reading the whole resource for its digest is offloaded, like every piece read after it.
+async fn open_resource(link: &impl Link, req: &ZTPRequest, sandbox: &Arc<Sandbox>) -> Option<(File, ZTPMetadata)>{
+    let resource_name = req.get_resource();
+    let (resolving_sandbox, resolved_name) = (Arc::clone(sandbox), resource_name.to_string());
+    let path = match link.offload(move || resolving_sandbox.resolve(&resolved_name)).await{
+        Ok(path) => path,
+        Err(code) => {
+            warn!("Refusing {resource_name} with {code:?}");
+            refuse(link, code, resource_name);
+            return None;
+        }
+    };
+    let (opened_path, mode) = (path.clone(), req.get_mode());
+    let opened = link.offload(move || File::open(&opened_path).and_then(|mut file|{
+        let modified = sandbox::modified_secs(&file.metadata()?);
+        let metadata = ZTPMetadata::from_reader(&mut file, mode)?.with_modified(modified);
+        Ok((file, metadata))
+    }));
+    match opened.await{
+        Ok(opened) => Some(opened),
+        Err(e) => {
+            error!("Could not read {}: {e}", path.display());
+            transfer::send_error(link, &ZTPErrorReport::new(ZTPErrorCode::Io, format!("could not read {resource_name}: {e}")));
+            None
+        }
+    }
+}
 
-    socket.lock().unwrap().send_to(&vec, addr).unwrap()
+async fn serve_list<L: SessionInbox>(link: &ServerLink<L>, req: &ZTPRequest, config: &ServerConfig, sandbox: &Arc<Sandbox>, stats: &mut TransferStats){
+    if let Some((listing, metadata)) = open_listing(link, req, sandbox).await{
+        send_source(link, listing, metadata, req, config, stats).await;
+    }
 }
 
-fn send_end_of_req(socket: &Arc<Mutex<UdpSocket>>, addr: &str) -> usize{
-    let end_of_req = ZTPResponse::new(ZTPResponseCode::EndRequest, None, None);
-    let vec = ZTPResponse::encode_to_vec(
-        end_of_req, 
-    ).unwrap();
-
-    let locked_socket = socket.lock().unwrap();
-    locked_socket.send_to(&vec, addr).unwrap()
-}
-
-fn send_metadata(
-    socket: &Arc<Mutex<UdpSocket>>,
-    addr: &str,
-    metadata: ZTPResponse 
-) -> bool{
-    let mut tx_buff: [u8; 2048] = [0; 2048];
-    let mut rx_buff: [u8; 4096] = [0; 4096];
-    let bytes = ZTPResponse::encode_into_slice(
-        metadata, 
-        &mut tx_buff, 
-    ).unwrap();
-
-    let locked_socket = socket.lock().unwrap();
-    locked_socket.send_to(&tx_buff[..bytes], addr).unwrap();
-    drop(locked_socket);
-    println!("Sent Metadata, Waiting for ACK...");
-
-    let mut tries = 0;
-    while peek_request(socket, addr, &mut rx_buff).is_err(){
-        println!("Waiting, try {tries}");
-        thread::sleep(Duration::from_millis(TTL_MILLIS));
-        tries += 1;
-        if tries > MAX_RETRIES{
-            return false;
+// The directory is read by offloaded work, the listing is built in memory and then sent like any resource.
+async fn open_listing(link: &impl Link, req: &ZTPRequest, sandbox: &Arc<Sandbox>) -> Option<(Cursor<Vec<u8>>, ZTPMetadata)>{
+    let dir_name = req.get_resource();
+    info!("Client is listing {dir_name:?}");
+    let (listed_sandbox, listed_name) = (Arc::clone(sandbox), dir_name.to_string());
+    let entries = match link.offload(move || listed_sandbox.list(&listed_name)).await{
+        Ok(entries) => entries,
+        Err(code) => {
+            warn!("Refusing to list {dir_name} with {code:?}");
+            refuse(link, code, dir_name);
+            return None;
+        }
+    };
+    let mut listing = Cursor::new(ZTPListEntry::encode_listing(&entries));
+    match ZTPMetadata::from_reader(&mut listing, req.get_mode()){
+        Ok(metadata) => Some((listing, metadata)),
+        Err(e) => {
+            transfer::send_error(link, &ZTPErrorReport::new(ZTPErrorCode::Io, format!("could not list {dir_name}: {e}")));
+            None
         }
     }
-    println!("Metadata ACK received!");
-    let response = get_response(socket, addr, &mut rx_buff);
-    return true;
 }
 
-fn send_resource(
-    socket: &Arc<Mutex<UdpSocket>>,
-    addr: &str,
-    res_buff: &[u8],
+async fn send_source<L: SessionInbox>(
+    link: &ServerLink<L>,
+    source: impl Read + Seek + Send + 'static,
+    metadata: ZTPMetadata,
+    req: &ZTPRequest,
+    config: &ServerConfig,
+    stats: &mut TransferStats
 ){
-    let size = res_buff.len();
-    println!("Resource Size: {size}");
-        
-    let mut tx_buffer: [u8; 4096] = [0; 4096];
-    let mut rx_buffer: [u8; 4096] = [0; 4096];
-    let mut start = 0;
-    let mut pkg_id = 0;
-    while start <= size{
-        let end = size.min(start + DATA_PIECE_SIZE);
-
-        let response = ZTPResponse::new(
-            ztp::ZTPResponseCode::Data, 
-            Some(ZTPResponseData::Bytes(res_buff[start..end].to_vec())),
-            Some(pkg_id),
-        );
+    let metadata = source_metadata(metadata, req, config);
+    debug!("Sending Metadata to {}", peer(link));
+    if !transfer::send_metadata(link, None, metadata, stats).await.is_some_and(|res| res.is_ack()){
+        return;
+    }
+    info!(
+        "Sending Resource to {} ({:?}{})",
+        peer(link),
+        metadata.mode(),
+        if link.is_encrypted() {", encrypted"} else {""}
+    );
+    if let Err(e) = transfer::send_resource(link, source, metadata, stats).await{
+        warn!("Transfer to {} aborted: {e}", peer(link));
+    }
+}
 
-        let res_size = ZTPResponse::encode_into_slice(
-            response,
-            &mut tx_buffer,
-        ).unwrap();
-
-        let mut tries = 0;
-        let mut package_finished = false;
-        while !package_finished{
-            println!("Sending Data Piece to {addr}, start: {start}, try = {tries}");
-            println!("Sending {res_size} bytes");
-            send_data_piece(socket, addr, &tx_buffer[..res_size]);
-            thread::sleep(Duration::from_millis(TTL_MILLIS));
-            
-            let is_ack;
-            if let Some(res) = get_response(socket,addr, &mut rx_buffer){
-                println!("Received Response from {addr}");
-                is_ack = res.is_ack(); 
-            } else{
-                is_ack = false;
-            }
+fn source_metadata(metadata: ZTPMetadata, req: &ZTPRequest, config: &ServerConfig) -> ZTPMetadata{
+    metadata
+        .with_checksum(req.get_checksum())
+        .with_piece_size(config.piece_size)
+        .resume_from(req.get_start_pkg())
+}
 
-            package_finished = tries >= 10 || is_ack;
-            tries += 1;
+// A refused upload is answered before the client sends its metadata.
+async fn serve_post<L: SessionInbox>(
+    link: &ServerLink<L>,
+    resource_name: &str,
+    config: &ServerConfig,
+    sandbox: &Arc<Sandbox>,
+    stats: &mut TransferStats
+){
+    let Some(path) = upload_target(link, resource_name, sandbox).await else{
+        return;
+    };
+    let metadata = match transfer::receive_metadata(link, stats).await{
+        Some(metadata) => metadata,
+        None => {
+            warn!("Connection Timeout: Metadata did not arrive");
+            return;
         }
+    };
+    let upload_path = upload_path(&path);
+    let stored = store_upload(link, metadata, config, stats, upload_path.clone(), path).await;
+    let resource_name = resource_name.to_string();
+    link.offload(move || report_upload(stored, &resource_name, metadata, &upload_path)).await;
+}
 
-        if tries >= 10 {return;}
-        
-        start += DATA_PIECE_SIZE;
-        pkg_id += 1;
+// Creating, checking and moving the upload in place is offloaded, like every piece write.
+// The client only hears the upload is over once it is in place.
+async fn store_upload(
+    link: &impl Link,
+    metadata: ZTPMetadata,
+    config: &ServerConfig,
+    stats: &mut TransferStats,
+    upload_path: PathBuf,
+    path: PathBuf
+) -> Result<(), Error>{
+    let created_path = upload_path.clone();
+    let file = link.offload(move || create_upload(metadata, &created_path)).await?;
+    let upload = Upload{file, metadata, upload_path, path};
+    transfer::receive_resource(link, metadata, stats, upload, config.corrupt_percent).await.1.map_err(Error::other)
+}
+
+async fn upload_target(link: &impl Link, resource_name: &str, sandbox: &Arc<Sandbox>) -> Option<PathBuf>{
+    info!("Client is uploading {resource_name}");
+    let (resolving_sandbox, resolved_name) = (Arc::clone(sandbox), resource_name.to_string());
+    match link.offload(move || resolving_sandbox.resolve_new(&resolved_name)).await{
+        Ok(path) => Some(path),
+        Err(code) => {
+            warn!("Refusing {resource_name} with {code:?}");
+            refuse(link, code, resource_name);
+            None
+        }
     }
+}
 
-    let end_of_req = ZTPResponse::new(ZTPResponseCode::EndRequest, None, None);
+// Hidden from clients by the sandbox until it is moved in place, see `UPLOAD_SUFFIX`.
+fn upload_path(path: &Path) -> PathBuf{
+    let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{file_name}.{:08x}{UPLOAD_SUFFIX}", rand::random::<u32>()))
+}
 
-    let end_of_req_size = ZTPResponse::encode_into_slice(
-        end_of_req,
-        &mut tx_buffer,
-    ).unwrap();
+// Pieces go into a preallocated file next to `path`, which only replaces
+// `path` once it holds the whole resource with the right digest.
+struct Upload{
+    file: File,
+    metadata: ZTPMetadata,
+    upload_path: PathBuf,
+    path: PathBuf,
+}
 
-    send_data_piece(socket, addr, &tx_buffer[..end_of_req_size]);
+impl PieceSink for Upload{
+    fn write_piece(&mut self, offset: u64, data: &[u8]) -> Result<(), Error>{
+        self.file.write_piece(offset, data)
+    }
 
+    fn finish(&mut self) -> Result<(), ZTPErrorReport>{
+        commit_upload(&mut self.file, self.metadata, &self.upload_path, &self.path)
+    }
 }
 
-fn send_data_piece(
-    socket: &Arc<Mutex<UdpSocket>>,
-    addr: &str,
-    buff: &[u8],
-){
-    let locked_socket = socket.lock().unwrap();
-    locked_socket.send_to(buff, addr);
-}
-
-fn get_response(
-    socket: &Arc<Mutex<UdpSocket>>,
-    addr: &str,
-    rx_buff: &mut [u8],
-) -> Option<ZTPResponse>{ 
-    let locked_socket = socket.lock().unwrap();
-    locked_socket.connect(addr);
-    match locked_socket.recv(rx_buff){
-        Ok(bytes) =>{
-            println!("Received {bytes} bytes");
-            println!("Received bytes (hex): {:02x?}", &rx_buff[..bytes]);
-            let response: ZTPResponse = ZTPResponse::decode_from_slice(
-                &rx_buff[..bytes],
-            ).unwrap().0;
-
-            Some(response)
+fn create_upload(metadata: ZTPMetadata, upload_path: &Path) -> Result<File, Error>{
+    let file = OpenOptions::new().read(true).write(true).create_new(true).open(upload_path)?;
+    file.set_len(metadata.size() as u64)?;
+    Ok(file)
+}
+
+fn commit_upload(file: &mut File, metadata: ZTPMetadata, upload_path: &Path, path: &Path) -> Result<(), ZTPErrorReport>{
+    let io_failure = |e: Error| ZTPErrorReport::new(ZTPErrorCode::Io, format!("could not store the upload: {e}"));
+    file.sync_all().map_err(io_failure)?;
+    file.seek(SeekFrom::Start(0)).map_err(io_failure)?;
+    let (_, digest) = ztp::digest_reader(file).map_err(io_failure)?;
+    if &digest != metadata.digest(){
+        let message = format!(
+            "integrity check failed, expected sha256 {} got {}",
+            to_hex(metadata.digest()),
+            to_hex(&digest),
+        );
+        return Err(ZTPErrorReport::new(ZTPErrorCode::Integrity, message));
+    }
+    fs::rename(upload_path, path).map_err(io_failure)
+}
+
+fn report_upload(stored: Result<(), Error>, resource_name: &str, metadata: ZTPMetadata, upload_path: &Path){
+    match stored{
+        Ok(_) => info!("Saved {resource_name} ({} bytes)", metadata.size()),
+        Err(e) => {
+            warn!("Not saving {resource_name}: {e}");
+            let _ = fs::remove_file(upload_path);
+        }
+    }
+}
+
+// Delete and Rename are answered with a bare Ack, or the reason they failed. Both
+// are carried out by offloaded work, see `serve`.
+async fn answer(link: &impl Link, result: Result<(), ZTPErrorReport>, stats: &mut TransferStats){
+    match result{
+        Ok(_) => {transfer::send_done(link, stats).await;},
+        Err(error) => {transfer::send_error(link, &error);}
+    }
+}
+
+fn delete_entry(resource_name: &str, sandbox: &Sandbox) -> Result<(), ZTPErrorReport>{
+    info!("Client is deleting {resource_name}");
+    let removed = sandbox.resolve_entry(resource_name)
+        .map_err(|code| refusal(code, resource_name))
+        .and_then(|path| fs::remove_file(path).map_err(|e| io_refusal("delete", resource_name, e)));
+    match &removed{
+        Ok(_) => info!("Deleted {resource_name}"),
+        Err(error) => warn!("Refusing to delete {resource_name}: {error}")
+    }
+    removed
+}
+
+// The new name must be free, Rename never replaces a resource.
+fn rename_entry(resource_name: &str, target_name: &str, sandbox: &Sandbox) -> Result<(), ZTPErrorReport>{
+    info!("Client is renaming {resource_name} to {target_name}");
+    let renamed = sandbox.resolve_entry(resource_name)
+        .map_err(|code| refusal(code, resource_name))
+        .and_then(|from|{
+            let to = sandbox.resolve_new(target_name).map_err(|code| refusal(code, target_name))?;
+            if fs::symlink_metadata(&to).is_ok(){
+                return Err(refusal(ZTPErrorCode::Conflict, target_name));
+            }
+            fs::rename(from, to).map_err(|e| io_refusal("rename", resource_name, e))
+        });
+    match &renamed{
+        Ok(_) => info!("Renamed {resource_name} to {target_name}"),
+        Err(error) => warn!("Refusing to rename {resource_name}: {error}")
+    }
+    renamed
+}
+
+// Requests in a version we do not speak are refused with the versions we do.
+fn parse_request(link: &impl Link, addr: SocketAddr, buffer: &[u8]) -> Result<ZTPRequest, ZTPWireError>{
+    match ZTPRequest::decode_from_slice(buffer){
+        Ok((req, _)) => Ok(req),
+        Err(ZTPWireError::UnsupportedVersion(version)) => {
+            warn!("Client at {addr} speaks ZTP version {version}, refusing it");
+            refuse_version(link, version);
+            Err(ZTPWireError::UnsupportedVersion(version))
         },
-        Err(_) => None
+        Err(e) => {
+            warn!("Malformed request from {addr}: {e}");
+            transfer::send_error(link, &ZTPErrorReport::new(ZTPErrorCode::BadRequest, e.to_string()));
+            Err(e)
+        }
     }
+}
 
+fn refuse(link: &impl Link, code: ZTPErrorCode, resource_name: &str) -> usize{
+    transfer::send_error(link, &refusal(code, resource_name))
 }
 
-fn drain_socket(
-    socket: &Arc<Mutex<UdpSocket>>,
-    addr: &str,
-){
-    let mut drain_buff = [0u8; 4096];
-    let locked_socket = socket.lock().unwrap();
-    locked_socket.connect(addr).unwrap();
+fn refusal(code: ZTPErrorCode, resource_name: &str) -> ZTPErrorReport{
+    let message = match code{
+        ZTPErrorCode::NotFound => format!("{resource_name} was not found on the server"),
+        ZTPErrorCode::Forbidden => format!("the server refuses to serve {resource_name}"),
+        ZTPErrorCode::PermissionDenied => format!("permission denied, changing {resource_name} needs a read-write key"),
+        ZTPErrorCode::Conflict => format!("{resource_name} already exists on the server"),
+        code => format!("{resource_name}: {code:?}")
+    };
+    ZTPErrorReport::new(code, message)
+}
 
-    while let Ok(bytes) = locked_socket.recv(&mut drain_buff){
-        println!("Received bytes (hex): {:02x?}", &drain_buff[..bytes]);
+fn io_refusal(action: &str, resource_name: &str, e: Error) -> ZTPErrorReport{
+    match e.kind(){
+        ErrorKind::NotFound => refusal(ZTPErrorCode::NotFound, resource_name),
+        _ => ZTPErrorReport::new(ZTPErrorCode::Io, format!("could not {action} {resource_name}: {e}"))
+    }
+}
+
+fn refuse_version(link: &impl Link, version: u8) -> usize{
+    transfer::send_response(link, ZTPResponse::version_refusal(version))
+}
+
+fn send_end_of_req(link: &impl Link) -> usize{
+    let end_of_req = ZTPResponse::new(ZTPResponseCode::EndRequest, None, None);
+    transfer::send_response(link, end_of_req)
+}
+
+#[cfg(test)]
+mod tests{
+    use std::thread;
+
+    use super::*;
+    use super::super::testing::{socket_pair, Fault, LossyLink};
+    use super::super::ztp::ZTPTransferMode;
+
+    fn request(code: ZTPRequestCode) -> ZTPRequest{
+        ZTPRequest::new(code, "a.txt".to_string(), ZTPTransferMode::StopAndWait)
+    }
+
+    #[test]
+    fn a_read_only_key_cannot_write(){
+        let (server, client) = socket_pair();
+        let mut rx_buff = [0u8; 4096];
+        for code in [ZTPRequestCode::Post, ZTPRequestCode::Delete, ZTPRequestCode::Rename]{
+            assert!(!permitted(&server, &request(code), Permission::ReadOnly), "{code:?}");
+
+            assert!(transfer::block_on(client.wait(Instant::now() + Duration::from_secs(1))).unwrap());
+            let bytes = client.recv(&mut rx_buff).unwrap();
+            let (res, _) = ZTPResponse::decode_from_slice(&rx_buff[..bytes]).unwrap();
+            assert_eq!(res.get_error().map(|error| error.code), Some(ZTPErrorCode::PermissionDenied));
+        }
+    }
+
+    #[test]
+    fn a_read_only_key_can_read_and_a_read_write_key_anything(){
+        let (server, client) = socket_pair();
+        for code in [ZTPRequestCode::Get, ZTPRequestCode::List, ZTPRequestCode::Stat]{
+            assert!(permitted(&server, &request(code), Permission::ReadOnly), "{code:?}");
+        }
+        for code in [
+            ZTPRequestCode::Get, ZTPRequestCode::List, ZTPRequestCode::Stat,
+            ZTPRequestCode::Post, ZTPRequestCode::Delete, ZTPRequestCode::Rename,
+        ]{
+            assert!(permitted(&server, &request(code), Permission::ReadWrite), "{code:?}");
+        }
+        // nothing was refused
+        assert!(client.recv(&mut [0u8; 4096]).is_err());
+    }
+
+    fn refused_with(client: &UdpSocket) -> Option<ZTPErrorCode>{
+        let mut rx_buff = [0u8; 4096];
+        let res = transfer::block_on(transfer::wait_for_response(client, &mut rx_buff, Duration::from_secs(1)))?;
+        res.get_error().map(|error| error.code)
+    }
+
+    #[test]
+    fn nothing_inside_a_denied_directory_is_served(){
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("private")).unwrap();
+        fs::write(dir.path().join("private/file.txt"), "secret").unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        let sandbox = Arc::new(Sandbox::new(&dir.path().to_string_lossy(), &[], &["private".to_string()]).unwrap());
+        let (server, client) = socket_pair();
+
+        let get = ZTPRequest::new(ZTPRequestCode::Get, "private/file.txt".to_string(), ZTPTransferMode::StopAndWait);
+        assert!(transfer::block_on(open_resource(&server, &get, &sandbox)).is_none());
+        assert_eq!(refused_with(&client), Some(ZTPErrorCode::Forbidden));
+
+        assert!(transfer::block_on(upload_target(&server, "private/new.txt", &sandbox)).is_none());
+        assert_eq!(refused_with(&client), Some(ZTPErrorCode::Forbidden));
+
+        assert_eq!(delete_entry("private/file.txt", &sandbox).unwrap_err().code, ZTPErrorCode::Forbidden);
+
+        assert_eq!(rename_entry("a.txt", "private/a.txt", &sandbox).unwrap_err().code, ZTPErrorCode::Forbidden);
+        assert_eq!(rename_entry("private/file.txt", "b.txt", &sandbox).unwrap_err().code, ZTPErrorCode::Forbidden);
+
+        // everything is still where it was
+        assert!(dir.path().join("private/file.txt").is_file());
+        assert!(dir.path().join("a.txt").is_file());
+        assert!(!dir.path().join("b.txt").exists());
+    }
+
+    #[test]
+    fn an_upload_failing_its_digest_is_refused_before_the_end_is_acked(){
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("up.bin");
+        let resource = b"an upload that gets corrupted".to_vec();
+        // the digest the client claims is not the one of what it sends
+        let metadata = ZTPMetadata::new(resource.len(), 1, ZTPTransferMode::StopAndWait, [7; 32]);
+        let (server, client) = socket_pair();
+
+        let target = path.clone();
+        let receiver = thread::spawn(move ||{
+            let mut stats = TransferStats::new();
+            let upload_path = upload_path(&target);
+            transfer::block_on(store_upload(&server, metadata, &ServerConfig::default(), &mut stats, upload_path, target))
+        });
+        let mut stats = TransferStats::new();
+        let sent = transfer::block_on(transfer::send_resource(&client, Cursor::new(resource), metadata, &mut stats));
+
+        assert_eq!(sent.unwrap_err().code, ZTPErrorCode::Integrity);
+        assert!(receiver.join().unwrap().is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn an_upload_survives_lost_metadata_pieces_and_acks(){
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("up.bin");
+        let resource: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let metadata = ZTPMetadata::from_reader(&mut Cursor::new(&resource), ZTPTransferMode::SelectiveRepeat(4)).unwrap();
+        let (server, client) = socket_pair();
+
+        let server = LossyLink::new(server, &[Fault::Lose((ZTPResponseCode::Ack, Some(1)))]);
+        let target = path.clone();
+        let receiver = thread::spawn(move ||{
+            let mut stats = TransferStats::new();
+            let metadata = transfer::block_on(transfer::receive_metadata(&server, &mut stats)).unwrap();
+            let upload_path = upload_path(&target);
+            let stored = transfer::block_on(store_upload(&server, metadata, &ServerConfig::default(), &mut stats, upload_path, target));
+            (server, stored)
+        });
+        let client = LossyLink::new(client, &[
+            Fault::Lose((ZTPResponseCode::Metadata, None)),
+            Fault::Lose((ZTPResponseCode::Data, Some(3))),
+        ]);
+        let mut stats = TransferStats::new();
+        assert!(transfer::block_on(transfer::send_metadata(&client, None, metadata, &mut stats)).unwrap().is_ack());
+        transfer::block_on(transfer::send_resource(&client, Cursor::new(resource.clone()), metadata, &mut stats)).unwrap();
+
+        let (server, stored) = receiver.join().unwrap();
+        stored.unwrap();
+        assert!(client.is_spent() && server.is_spent());
+        assert!(client.sent((ZTPResponseCode::Metadata, None)) >= 2);
+        assert!(client.sent((ZTPResponseCode::Data, Some(1))) >= 2);
+        assert!(client.sent((ZTPResponseCode::Data, Some(3))) >= 2);
+        assert_eq!(fs::read(&path).unwrap(), resource);
     }
-    
-    println!("Finished Draining Socket");
 }
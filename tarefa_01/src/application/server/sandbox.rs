@@ -0,0 +1,366 @@
+use std::{
+    fs, io::{Error, ErrorKind}, path::{Component, Path, PathBuf}, time::UNIX_EPOCH
+};
+use glob::{MatchOptions, Pattern};
+use log::warn;
+
+use super::super::ztp::{ZTPListEntry, ZTPErrorCode};
+
+/*================================================= SANDBOX ============================================================= */
+
+// Confines the resource names clients send to the resource root. Names are
+// relative paths made of plain components only, symlinks are followed and must
+// still land inside the root, and both the name and what it resolves to have to
+// pass the allow/deny globs (deny wins, an empty allow list allows everything).
+// Deny globs also match the directories on the way, allow globs only the name.
+// Uploads in progress are never served or listed, whatever the globs say.
+pub struct Sandbox{
+    root: PathBuf,
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+}
+
+// Ends the name of the file an upload is received into, next to the resource it replaces.
+pub const UPLOAD_SUFFIX: &str = ".upload";
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions{
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+impl Sandbox{
+    pub fn new(root: &str, allow: &[String], deny: &[String]) -> Result<Sandbox, Error>{
+        Ok(Sandbox{
+            root: fs::canonicalize(root)?,
+            allow: compile(allow)?,
+            deny: compile(deny)?,
+        })
+    }
+
+    // Path of an existing resource, or the code to refuse it with.
+    pub fn resolve(&self, resource_name: &str) -> Result<PathBuf, ZTPErrorCode>{
+        let path = self.root.join(self.check_name(resource_name)?);
+        let path = fs::canonicalize(path).map_err(|_| ZTPErrorCode::NotFound)?;
+        self.check_globs(resource_name, self.inside(resource_name, &path)?)?;
+        if !path.is_file(){
+            return Err(ZTPErrorCode::NotFound);
+        }
+        Ok(path)
+    }
+
+    // Path an uploaded resource may be written to. Its directory must already
+    // exist, and if the name is taken by a symlink the link has to stay inside.
+    pub fn resolve_new(&self, resource_name: &str) -> Result<PathBuf, ZTPErrorCode>{
+        let name = self.check_name(resource_name)?;
+        let path = self.root.join(&name);
+        let parent = path.parent().and_then(|parent| fs::canonicalize(parent).ok())
+            .ok_or(ZTPErrorCode::NotFound)?;
+        self.inside(resource_name, &parent)?;
+
+        let path = parent.join(name.file_name().ok_or(ZTPErrorCode::Forbidden)?);
+        if fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_symlink()){
+            // a dangling link would be followed by the write
+            let target = fs::canonicalize(&path).map_err(|_| ZTPErrorCode::Forbidden)?;
+            self.check_globs(resource_name, self.inside(resource_name, &target)?)?;
+        }
+        if path.is_dir(){
+            return Err(ZTPErrorCode::Forbidden);
+        }
+        Ok(path)
+    }
+
+    // The directory entry of an existing resource. A symlink is not followed, so
+    // Delete and Rename act on the link and not on what it points to.
+    pub fn resolve_entry(&self, resource_name: &str) -> Result<PathBuf, ZTPErrorCode>{
+        self.resolve(resource_name)?;
+        self.resolve_new(resource_name)
+    }
+
+    // A directory under the root, the root itself for an empty name. Only the deny
+    // list applies, so directories stay reachable with an allow list of file globs.
+    pub fn resolve_dir(&self, dir_name: &str) -> Result<PathBuf, ZTPErrorCode>{
+        if dir_name.is_empty(){
+            return Ok(self.root.clone());
+        }
+        let path = self.root.join(plain_name(dir_name)?);
+        let path = fs::canonicalize(path).map_err(|_| ZTPErrorCode::NotFound)?;
+        if self.is_denied(self.inside(dir_name, &path)?){
+            warn!("Refusing {dir_name}: blocked by the deny list");
+            return Err(ZTPErrorCode::Forbidden);
+        }
+        if !path.is_dir(){
+            return Err(ZTPErrorCode::NotFound);
+        }
+        Ok(path)
+    }
+
+    // The entries of a directory a client could fetch or list in turn, by name.
+    pub fn list(&self, dir_name: &str) -> Result<Vec<ZTPListEntry>, ZTPErrorCode>{
+        let dir = self.resolve_dir(dir_name)?;
+        let read_dir = fs::read_dir(&dir).map_err(|_| ZTPErrorCode::NotFound)?;
+
+        let mut entries = Vec::new();
+        for entry in read_dir.flatten(){
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.contains('\n'){
+                continue;
+            }
+            let relative = if dir_name.is_empty() {name.clone()} else {format!("{dir_name}/{name}")};
+            let (path, is_dir) = match self.resolve(&relative){
+                Ok(path) => (path, false),
+                Err(_) => match self.resolve_dir(&relative){
+                    Ok(path) => (path, true),
+                    Err(_) => continue
+                }
+            };
+            let Ok(metadata) = fs::metadata(&path) else{
+                continue;
+            };
+            entries.push(ZTPListEntry{
+                name,
+                is_dir,
+                size: if is_dir {0} else {metadata.len()},
+                modified: modified_secs(&metadata),
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    fn check_name(&self, resource_name: &str) -> Result<PathBuf, ZTPErrorCode>{
+        let name = plain_name(resource_name)?;
+        self.check_globs(resource_name, &name)?;
+        Ok(name)
+    }
+
+    // `path` is canonical, so a symlink may have taken it anywhere.
+    fn inside<'a>(&self, resource_name: &str, path: &'a Path) -> Result<&'a Path, ZTPErrorCode>{
+        path.strip_prefix(&self.root).map_err(|_|{
+            warn!("Refusing {resource_name}: it links outside of the resource root");
+            ZTPErrorCode::Forbidden
+        })
+    }
+
+    fn check_globs(&self, resource_name: &str, name: &Path) -> Result<(), ZTPErrorCode>{
+        if is_upload(name){
+            warn!("Refusing {resource_name}: it is an upload in progress");
+            return Err(ZTPErrorCode::Forbidden);
+        }
+        let allowed = self.allow.is_empty() ||
+            self.allow.iter().any(|p| p.matches_with(&name.to_string_lossy(), MATCH_OPTIONS));
+        if !allowed || self.is_denied(name){
+            warn!("Refusing {resource_name}: blocked by the allow/deny lists");
+            return Err(ZTPErrorCode::Forbidden);
+        }
+        Ok(())
+    }
+
+    // A denied directory denies everything under it, so every prefix of `name` is matched.
+    fn is_denied(&self, name: &Path) -> bool{
+        name.ancestors()
+            .filter(|prefix| !prefix.as_os_str().is_empty())
+            .any(|prefix| self.deny.iter().any(|p| p.matches_with(&prefix.to_string_lossy(), MATCH_OPTIONS)))
+    }
+}
+
+// Left behind by a crashed upload as well, so the name alone is enough to tell.
+fn is_upload(name: &Path) -> bool{
+    name.file_name().is_some_and(|file_name| file_name.to_string_lossy().ends_with(UPLOAD_SUFFIX))
+}
+
+// `a//b` and `a/./b` are the same resource as `a/b`, anything leaving the
+// directory it starts from (`..`, absolute paths) is refused.
+fn plain_name(resource_name: &str) -> Result<PathBuf, ZTPErrorCode>{
+    let name = Path::new(resource_name);
+    let plain = name.components().all(|component| matches!(component, Component::Normal(_)));
+    if resource_name.is_empty() || !plain{
+        warn!("Refusing {resource_name}: not a relative resource name");
+        return Err(ZTPErrorCode::Forbidden);
+    }
+    Ok(name.components().collect())
+}
+
+fn compile(patterns: &[String]) -> Result<Vec<Pattern>, Error>{
+    patterns.iter()
+        .map(|pattern| Pattern::new(pattern).map_err(|e|{
+            Error::new(ErrorKind::InvalidInput, format!("bad glob {pattern}: {e}"))
+        }))
+        .collect()
+}
+
+// Seconds since the Unix epoch, 0 where the platform does not keep it.
+pub fn modified_secs(metadata: &fs::Metadata) -> u64{
+    metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_secs())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // A resource root with a few files and a secret next to it, outside the root.
+    fn setup(allow: &[&str], deny: &[&str]) -> (tempfile::TempDir, Sandbox){
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("sub/b.txt"), "b").unwrap();
+        fs::write(root.join("sub/c.key"), "c").unwrap();
+        fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+
+        let allow: Vec<String> = allow.iter().map(|pattern| pattern.to_string()).collect();
+        let deny: Vec<String> = deny.iter().map(|pattern| pattern.to_string()).collect();
+        let sandbox = Sandbox::new(&root.to_string_lossy(), &allow, &deny).unwrap();
+        (dir, sandbox)
+    }
+
+    #[test]
+    fn resolves_plain_names(){
+        let (_dir, sandbox) = setup(&[], &[]);
+        assert!(sandbox.resolve("a.txt").unwrap().ends_with("root/a.txt"));
+        assert!(sandbox.resolve("sub/b.txt").unwrap().ends_with("root/sub/b.txt"));
+        assert!(sandbox.resolve("sub//./b.txt").unwrap().ends_with("root/sub/b.txt"));
+        assert_eq!(sandbox.resolve("missing.txt"), Err(ZTPErrorCode::NotFound));
+        assert_eq!(sandbox.resolve("sub"), Err(ZTPErrorCode::NotFound));
+    }
+
+    #[test]
+    fn refuses_dot_dot_traversal(){
+        let (_dir, sandbox) = setup(&[], &[]);
+        for name in ["../secret.txt", "sub/../../secret.txt", "sub/../a.txt", "..", ""]{
+            assert_eq!(sandbox.resolve(name), Err(ZTPErrorCode::Forbidden), "{name:?}");
+            assert_eq!(sandbox.resolve_new(name), Err(ZTPErrorCode::Forbidden), "{name:?}");
+        }
+        assert_eq!(sandbox.resolve_dir("sub/../.."), Err(ZTPErrorCode::Forbidden));
+    }
+
+    #[test]
+    fn refuses_absolute_paths(){
+        let (dir, sandbox) = setup(&[], &[]);
+        let secret = dir.path().join("secret.txt").to_string_lossy().into_owned();
+        let inside = dir.path().join("root/a.txt").to_string_lossy().into_owned();
+        for name in [secret.as_str(), inside.as_str(), "/etc/passwd"]{
+            assert_eq!(sandbox.resolve(name), Err(ZTPErrorCode::Forbidden), "{name}");
+            assert_eq!(sandbox.resolve_new(name), Err(ZTPErrorCode::Forbidden), "{name}");
+        }
+        assert_eq!(sandbox.resolve_dir("/"), Err(ZTPErrorCode::Forbidden));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_pointing_outside_the_root(){
+        use std::os::unix::fs::symlink;
+        let (dir, sandbox) = setup(&[], &[]);
+        let root = dir.path().join("root");
+        symlink(dir.path().join("secret.txt"), root.join("out.txt")).unwrap();
+        symlink(dir.path(), root.join("up")).unwrap();
+        symlink(dir.path().join("nowhere.txt"), root.join("dangling.txt")).unwrap();
+
+        assert_eq!(sandbox.resolve("out.txt"), Err(ZTPErrorCode::Forbidden));
+        assert_eq!(sandbox.resolve("up/secret.txt"), Err(ZTPErrorCode::Forbidden));
+        assert_eq!(sandbox.resolve_new("out.txt"), Err(ZTPErrorCode::Forbidden));
+        assert_eq!(sandbox.resolve_new("up/new.txt"), Err(ZTPErrorCode::Forbidden));
+        assert_eq!(sandbox.resolve_new("dangling.txt"), Err(ZTPErrorCode::Forbidden));
+        assert_eq!(sandbox.resolve_dir("up"), Err(ZTPErrorCode::Forbidden));
+        let listing = sandbox.list("").unwrap();
+        assert!(listing.iter().all(|entry| !["out.txt", "up", "dangling.txt"].contains(&entry.name.as_str())));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks_that_stay_inside(){
+        use std::os::unix::fs::symlink;
+        let (dir, sandbox) = setup(&[], &[]);
+        let root = dir.path().join("root");
+        symlink(root.join("sub/b.txt"), root.join("link.txt")).unwrap();
+
+        assert!(sandbox.resolve("link.txt").unwrap().ends_with("root/sub/b.txt"));
+        // Delete and Rename act on the link itself
+        assert!(sandbox.resolve_entry("link.txt").unwrap().ends_with("root/link.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn globs_apply_to_what_a_symlink_resolves_to(){
+        use std::os::unix::fs::symlink;
+        let (dir, sandbox) = setup(&[], &["*.key", "**/*.key"]);
+        let root = dir.path().join("root");
+        symlink(root.join("sub/c.key"), root.join("c.txt")).unwrap();
+        assert_eq!(sandbox.resolve("c.txt"), Err(ZTPErrorCode::Forbidden));
+    }
+
+    #[test]
+    fn deny_overrides_allow(){
+        let (_dir, sandbox) = setup(&["*.txt", "sub/*"], &["sub/*.key", "a.*"]);
+        assert!(sandbox.resolve("sub/b.txt").is_ok());
+        assert_eq!(sandbox.resolve("sub/c.key"), Err(ZTPErrorCode::Forbidden));
+        assert_eq!(sandbox.resolve("a.txt"), Err(ZTPErrorCode::Forbidden));
+        assert_eq!(sandbox.resolve_new("a.txt"), Err(ZTPErrorCode::Forbidden));
+        assert_eq!(sandbox.resolve_new("sub/new.key"), Err(ZTPErrorCode::Forbidden));
+        assert!(sandbox.resolve_new("sub/new.txt").is_ok());
+    }
+
+    #[test]
+    fn allow_list_limits_what_is_served(){
+        let (_dir, sandbox) = setup(&["*.txt"], &[]);
+        assert!(sandbox.resolve("a.txt").is_ok());
+        // `*` does not cross directories
+        assert_eq!(sandbox.resolve("sub/b.txt"), Err(ZTPErrorCode::Forbidden));
+        // directories stay listable, their entries are filtered
+        let names: Vec<String> = sandbox.list("").unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["a.txt", "sub"]);
+        assert!(sandbox.list("sub").unwrap().is_empty());
+    }
+
+    #[test]
+    fn deny_list_hides_directories(){
+        let (_dir, sandbox) = setup(&[], &["sub"]);
+        assert_eq!(sandbox.resolve_dir("sub"), Err(ZTPErrorCode::Forbidden));
+        let names: Vec<String> = sandbox.list("").unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["a.txt"]);
+    }
+
+    #[test]
+    fn a_denied_directory_denies_what_is_inside(){
+        let (dir, sandbox) = setup(&[], &["sub"]);
+        assert_eq!(sandbox.resolve("sub/b.txt"), Err(ZTPErrorCode::Forbidden));
+        assert_eq!(sandbox.resolve_new("sub/new.txt"), Err(ZTPErrorCode::Forbidden));
+        assert_eq!(sandbox.resolve_entry("sub/b.txt"), Err(ZTPErrorCode::Forbidden));
+        assert_eq!(sandbox.list("sub"), Err(ZTPErrorCode::Forbidden));
+
+        // the dotfile rule of server.example.toml hides whole dot directories
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join(".secret/deeper")).unwrap();
+        fs::write(root.join(".secret/file.txt"), "secret").unwrap();
+        fs::write(root.join(".secret/deeper/file.txt"), "secret").unwrap();
+        let sandbox = Sandbox::new(&root.to_string_lossy(), &[], &["**/.*".to_string()]).unwrap();
+        for name in [".secret/file.txt", ".secret/deeper/file.txt"]{
+            assert_eq!(sandbox.resolve(name), Err(ZTPErrorCode::Forbidden), "{name}");
+            assert_eq!(sandbox.resolve_new(name), Err(ZTPErrorCode::Forbidden), "{name}");
+        }
+        assert!(sandbox.resolve("a.txt").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn uploads_in_progress_are_never_served(){
+        use std::os::unix::fs::symlink;
+        let (dir, sandbox) = setup(&[], &[]);
+        let root = dir.path().join("root");
+        fs::write(root.join(".a.txt.0badf00d.upload"), "half").unwrap();
+        fs::write(root.join("sub/.b.txt.12345678.upload"), "half").unwrap();
+        symlink(root.join(".a.txt.0badf00d.upload"), root.join("half.txt")).unwrap();
+
+        for name in [".a.txt.0badf00d.upload", "sub/.b.txt.12345678.upload", "half.txt"]{
+            assert_eq!(sandbox.resolve(name), Err(ZTPErrorCode::Forbidden), "{name}");
+        }
+        assert_eq!(sandbox.resolve_new("new.txt.upload"), Err(ZTPErrorCode::Forbidden));
+        assert_eq!(sandbox.resolve_entry(".a.txt.0badf00d.upload"), Err(ZTPErrorCode::Forbidden));
+        let names: Vec<String> = sandbox.list("").unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["a.txt", "sub"]);
+        let names: Vec<String> = sandbox.list("sub").unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["b.txt", "c.key"]);
+    }
+}
@@ -1,10 +1,11 @@
 use std::{
-    sync::{mpsc, Arc, Mutex}, thread,
+    panic::{self, AssertUnwindSafe}, sync::{mpsc, Arc, Mutex}, thread,
 };
+use log::{debug, error, info, warn};
 
 pub struct ThreadPool{
     workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    sender: Option<mpsc::Sender<Job>>,
 }
 
 impl ThreadPool{
@@ -18,7 +19,7 @@ impl ThreadPool{
         for i in 0..size{
             workers.push(Worker::new(i, Arc::clone(&receiver))); 
         }
-        ThreadPool{workers, sender}
+        ThreadPool{workers, sender: Some(sender)}
     }
 
     pub fn execute<F>(&self, f: F)
@@ -27,30 +28,54 @@ impl ThreadPool{
     {
         let job = Box::new(f);
 
-        match self.sender.send(job){
-            Err(e) => {dbg!(e);},
-            _ => {}
-        };
+        if let Some(Err(e)) = self.sender.as_ref().map(|sender| sender.send(job)){
+            error!("Could not hand a job to the workers: {e}");
+        }
+    }
+}
+
+impl Drop for ThreadPool{
+    fn drop(&mut self){
+        //closing the channel makes every idle worker leave its loop
+        drop(self.sender.take());
+
+        for worker in &mut self.workers{
+            info!("Shutting down worker {}", worker.id);
+            if let Some(thread) = worker.thread.take(){
+                if thread.join().is_err(){
+                    error!("Worker {} had panicked", worker.id);
+                }
+            }
+        }
     }
 }
 
 struct Worker{
     id: usize,
-    thread: thread::JoinHandle<()>,
+    thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker{
     fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker{
         let thread = thread::spawn(move || {
             loop {
-                let job = receiver.lock().unwrap().recv().unwrap();
-                println!("Worker {id} got a job; executing");
-                job();
+                // a worker that panicked while holding the lock leaves the receiver usable
+                let message = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                match message{
+                    Ok(job) => {
+                        debug!("Worker {id} got a job; executing");
+                        // a bad session costs its own job, not the worker
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err(){
+                            warn!("Worker {id} recovered from a panicking job");
+                        }
+                    },
+                    Err(_) => break
+                }
             }
         });
         Worker{
             id,
-            thread
+            thread: Some(thread)
         }
     }
 }
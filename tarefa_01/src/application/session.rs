@@ -0,0 +1,142 @@
+use std::{future::Future, io::{Error, ErrorKind}, sync::atomic::{AtomicU32, AtomicU8, Ordering}, time::Instant};
+use log::debug;
+
+use super::rtt::TransferStats;
+use super::transfer::Link;
+use super::ztp::{self, ZTPErrorCode, ZTPErrorReport, ZTPResponse, ZTPSessionCode, ZTPSessionControl, ZTP_VERSION};
+
+/*================================================= SESSION LINK ============================================================= */
+
+// Once the session is open every datagram sent carries its id, and received
+// datagrams stamped with another id (e.g. stragglers of an earlier run on the
+// same port) are dropped. Datagrams go out in the version the session speaks.
+pub struct SessionLink<L: Link>{
+    link: L,
+    session_id: AtomicU32,
+    version: AtomicU8,
+}
+
+impl<L: Link> SessionLink<L>{
+    pub fn new(link: L) -> SessionLink<L>{
+        SessionLink{link, session_id: AtomicU32::new(0), version: AtomicU8::new(ZTP_VERSION)}
+    }
+
+    pub fn establish(&self, session_id: u32){
+        self.session_id.store(session_id, Ordering::Relaxed);
+    }
+
+    pub fn session_id(&self) -> u32{
+        self.session_id.load(Ordering::Relaxed)
+    }
+
+    // Everything sent from now on is stamped with `version`, as negotiated with the peer.
+    pub fn speak(&self, version: u8){
+        self.version.store(version, Ordering::Relaxed);
+    }
+
+    pub fn version(&self) -> u8{
+        self.version.load(Ordering::Relaxed)
+    }
+
+    pub fn inner(&self) -> &L{
+        &self.link
+    }
+}
+
+impl<L: Link> Link for SessionLink<L>{
+    fn send(&self, buff: &[u8]) -> Result<usize, Error>{
+        let (session_id, version) = (self.session_id(), self.version());
+        if session_id == 0 && version == ZTP_VERSION{
+            return self.link.send(buff);
+        }
+        // a datagram in another version (a version refusal) keeps it until one is negotiated
+        let mut stamped = buff.to_vec();
+        ztp::set_session_id(&mut stamped, session_id)
+            .and_then(|_| if version == ZTP_VERSION {Ok(())} else {ztp::set_version(&mut stamped, version)})
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        self.link.send(&stamped)
+    }
+
+    fn recv(&self, buff: &mut [u8]) -> Result<usize, Error>{
+        let bytes = self.link.recv(buff)?;
+        let session_id = self.session_id();
+        match ztp::peek_session_id(&buff[..bytes]){
+            Some(id) if session_id != 0 && id != 0 && id != session_id => {
+                Err(Error::new(ErrorKind::InvalidData, "datagram of another session"))
+            },
+            _ => Ok(bytes)
+        }
+    }
+
+    fn wait(&self, deadline: Instant) -> impl Future<Output = Result<bool, Error>> + Send{
+        self.link.wait(deadline)
+    }
+
+    fn offload<T: Send + 'static>(&self, work: impl FnOnce() -> T + Send + 'static) -> impl Future<Output = T> + Send{
+        self.link.offload(work)
+    }
+}
+
+/*================================================= OPEN / CLOSE ============================================================= */
+
+// Client side of the open: sends Open with a random nonce until an OpenAck
+// echoing it arrives, then stamps everything with the session id it carries.
+// A busy server answers the Open with an Error instead.
+pub async fn open<L: Link>(link: &SessionLink<L>, stats: &mut TransferStats) -> Result<(), ZTPErrorReport>{
+    let nonce: u64 = rand::random();
+    let open = ZTPSessionControl::open(nonce).encode_to_vec()
+        .map_err(|e| ZTPErrorReport::new(ZTPErrorCode::BadRequest, format!("could not encode Open: {e}")))?;
+    let mut rx_buff = [0u8; 4096];
+
+    for tries in 0..=stats.rtt.max_retries(){
+        let sent_at = Instant::now();
+        let _ = link.send(&open);
+        let deadline = sent_at + stats.rtt.backoff(tries);
+
+        loop{
+            if let Ok(bytes) = link.recv(&mut rx_buff){
+                match parse_open_answer(&rx_buff[..bytes], nonce){
+                    Some(Ok(session_id)) => {
+                        if tries == 0{
+                            stats.rtt.sample(sent_at.elapsed());
+                        }
+                        link.establish(session_id);
+                        debug!("Opened session {session_id:08x}");
+                        return Ok(());
+                    },
+                    Some(Err(error)) => return Err(error),
+                    None => continue
+                }
+            }
+            if !link.wait(deadline).await.unwrap_or(false){
+                break;
+            }
+        }
+    }
+    Err(ZTPErrorReport::new(ZTPErrorCode::Aborted, format!("no answer to Open after {} retries", stats.rtt.max_retries())))
+}
+
+// The session id of the OpenAck echoing `nonce`, or the Error of a server that
+// turned the Open down. None for anything else.
+fn parse_open_answer(datagram: &[u8], nonce: u64) -> Option<Result<u32, ZTPErrorReport>>{
+    if let Ok((control, _)) = ZTPSessionControl::decode_from_slice(datagram){
+        let answers = control.get_code() == ZTPSessionCode::OpenAck && control.get_nonce() == Some(nonce);
+        return (answers && control.get_session_id() != 0).then_some(Ok(control.get_session_id()));
+    }
+    let (res, _) = ZTPResponse::decode_from_slice(datagram).ok()?;
+    res.get_error().map(Err)
+}
+
+pub fn send_open_ack(link: &impl Link, nonce: u64) -> usize{
+    send_control(link, ZTPSessionControl::open_ack(nonce))
+}
+
+// Best effort, a lost Close only means the server expires the session later.
+pub fn close<L: Link>(link: &SessionLink<L>){
+    send_control(link, ZTPSessionControl::close());
+    debug!("Closed session {:08x}", link.session_id());
+}
+
+fn send_control(link: &impl Link, control: ZTPSessionControl) -> usize{
+    control.encode_to_vec().ok().and_then(|datagram| link.send(&datagram).ok()).unwrap_or(0)
+}
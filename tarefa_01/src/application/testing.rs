@@ -0,0 +1,103 @@
+use std::{future::Future, io::Error, net::UdpSocket, sync::Mutex, time::Instant};
+
+use super::transfer::Link;
+use super::ztp::{ZTPResponse, ZTPResponseCode};
+
+/*================================================= FIXTURES ============================================================= */
+
+// Two non-blocking loopback sockets connected to each other, the two ends of a link.
+pub(crate) fn socket_pair() -> (UdpSocket, UdpSocket){
+    let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+    a.connect(b.local_addr().unwrap()).unwrap();
+    b.connect(a.local_addr().unwrap()).unwrap();
+    a.set_nonblocking(true).unwrap();
+    b.set_nonblocking(true).unwrap();
+    (a, b)
+}
+
+/*================================================= LOSSY LINK ============================================================= */
+
+// A response as the tests name it: its code and its pkg_id, or the index a cumulative Ack carries.
+pub(crate) type Label = (ZTPResponseCode, Option<u64>);
+
+// What happens to the next datagram sent with a label, once.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Fault{
+    Lose(Label),
+    // the payload of a Data piece is flipped, its hash left as it was
+    Corrupt(u64),
+}
+
+// Injects `faults` into what goes out over `link` and keeps the label of every
+// datagram it was asked to send, lost or not.
+pub(crate) struct LossyLink<L: Link>{
+    link: L,
+    faults: Mutex<Vec<Fault>>,
+    sent: Mutex<Vec<Label>>,
+}
+
+impl<L: Link> LossyLink<L>{
+    pub(crate) fn new(link: L, faults: &[Fault]) -> LossyLink<L>{
+        LossyLink{link, faults: Mutex::new(faults.to_vec()), sent: Mutex::new(Vec::new())}
+    }
+
+    // How many times a datagram with `label` was sent.
+    pub(crate) fn sent(&self, label: Label) -> usize{
+        self.sent.lock().unwrap().iter().filter(|sent| **sent == label).count()
+    }
+
+    // Whether every fault was injected.
+    pub(crate) fn is_spent(&self) -> bool{
+        self.faults.lock().unwrap().is_empty()
+    }
+
+    fn take_fault(&self, label: Label) -> Option<Fault>{
+        let mut faults = self.faults.lock().unwrap();
+        let index = faults.iter().position(|fault| match fault{
+            Fault::Lose(lost) => *lost == label,
+            Fault::Corrupt(pkg_id) => label == (ZTPResponseCode::Data, Some(*pkg_id)),
+        })?;
+        Some(faults.remove(index))
+    }
+}
+
+impl<L: Link> Link for LossyLink<L>{
+    fn send(&self, buff: &[u8]) -> Result<usize, Error>{
+        let Some(label) = label(buff) else{
+            return self.link.send(buff);
+        };
+        self.sent.lock().unwrap().push(label);
+        match self.take_fault(label){
+            Some(Fault::Lose(_)) => Ok(buff.len()),
+            Some(Fault::Corrupt(_)) => self.link.send(&corrupt(buff)),
+            None => self.link.send(buff)
+        }
+    }
+
+    fn recv(&self, buff: &mut [u8]) -> Result<usize, Error>{
+        self.link.recv(buff)
+    }
+
+    fn wait(&self, deadline: Instant) -> impl Future<Output = Result<bool, Error>> + Send{
+        self.link.wait(deadline)
+    }
+
+    fn offload<T: Send + 'static>(&self, work: impl FnOnce() -> T + Send + 'static) -> impl Future<Output = T> + Send{
+        self.link.offload(work)
+    }
+}
+
+fn label(datagram: &[u8]) -> Option<Label>{
+    let (res, _) = ZTPResponse::decode_from_slice(datagram).ok()?;
+    let id = res.get_pkg_id().or(res.get_package_index().map(|index| index as u64));
+    Some((res.get_code(), id))
+}
+
+fn corrupt(datagram: &[u8]) -> Vec<u8>{
+    let (mut res, _) = ZTPResponse::decode_from_slice(datagram).unwrap();
+    let (mut bytes, hash) = res.take_bytes().unwrap();
+    bytes[0] ^= 0xff;
+    res.set_bytes(bytes, hash);
+    res.encode_to_vec().unwrap()
+}
@@ -0,0 +1,1330 @@
+use std::{
+    collections::BTreeMap, fs::File, future::{self, Future}, io::{Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    net::UdpSocket, pin::pin, sync::{Arc, Mutex, MutexGuard, PoisonError}, task::{Context, Poll, Wake, Waker}, thread, time::{Duration, Instant}
+};
+use log::{debug, error, trace, warn};
+use rand::{prelude::*, rngs::StdRng};
+
+use super::rtt::TransferStats;
+use super::ztp::{
+    to_hex, ZTPChecksum, ZTPErrorCode, ZTPErrorReport, ZTPMetadata, ZTPRequest, ZTPResponse, ZTPResponseCode, ZTPResponseData,
+    ZTPTransferMode, ZTPWireError
+};
+
+#[cfg(feature = "async")]
+pub(crate) mod asynchronous;
+
+/*================================================= LINK ============================================================= */
+
+// Both ends of a transfer only need to send and (non-blocking) receive datagrams
+// to/from the peer, so the same sender/receiver logic serves GET and POST, over
+// blocking sockets and tokio ones alike.
+// `wait` is the only place a transfer sleeps: Ok(true) once a receive may succeed,
+// Ok(false) if `deadline` passes first, an error once the link is closed for good.
+// Blocking links wait before handing back a ready future, tokio links await in it.
+// There is no polling fallback, every link has something to block on.
+// `offload` runs the file work of a transfer: in place on blocking links, on
+// tokio's blocking threads on tokio links, so no disk ever stalls the runtime.
+pub trait Link{
+    fn send(&self, buff: &[u8]) -> Result<usize, Error>;
+    fn recv(&self, buff: &mut [u8]) -> Result<usize, Error>;
+    fn wait(&self, deadline: Instant) -> impl Future<Output = Result<bool, Error>> + Send;
+    fn offload<T: Send + 'static>(&self, work: impl FnOnce() -> T + Send + 'static) -> impl Future<Output = T> + Send;
+}
+
+// The protocol is written once, as async fns. The blocking client and server
+// run them here, on the calling thread: their links do the waiting, so the
+// futures are ready every time they are polled.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output{
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop{
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context){
+            return output;
+        }
+        thread::park();
+    }
+}
+
+struct Unpark(thread::Thread);
+
+impl Wake for Unpark{
+    fn wake(self: Arc<Self>){
+        self.0.unpark();
+    }
+}
+
+// The socket is non-blocking, `wait` blocks on a peek so the datagram stays
+// queued for `recv`.
+impl Link for UdpSocket{
+    fn send(&self, buff: &[u8]) -> Result<usize, Error>{
+        UdpSocket::send(self, buff)
+    }
+
+    fn recv(&self, buff: &mut [u8]) -> Result<usize, Error>{
+        UdpSocket::recv(self, buff)
+    }
+
+    fn wait(&self, deadline: Instant) -> impl Future<Output = Result<bool, Error>> + Send{
+        future::ready(peek_until(self, deadline))
+    }
+
+    fn offload<T: Send + 'static>(&self, work: impl FnOnce() -> T + Send + 'static) -> impl Future<Output = T> + Send{
+        future::ready(work())
+    }
+}
+
+fn peek_until(socket: &UdpSocket, deadline: Instant) -> Result<bool, Error>{
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    if timeout.is_zero(){
+        return Ok(false);
+    }
+    socket.set_nonblocking(false)?;
+    socket.set_read_timeout(Some(timeout))?;
+    let peeked = socket.peek(&mut [0u8; 1]);
+    socket.set_nonblocking(true)?;
+    match peeked{
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(false),
+        // anything else, a truncated peek included, is for `recv` to report
+        _ => Ok(true)
+    }
+}
+
+// Where a receiver stores accepted pieces, each at its offset in the resource.
+// `finish` runs once every piece is there, before the sender is told the transfer
+// is over, so a resource failing it is reported with the error instead of an Ack.
+pub trait PieceSink{
+    fn write_piece(&mut self, offset: u64, data: &[u8]) -> Result<(), Error>;
+
+    fn finish(&mut self) -> Result<(), ZTPErrorReport>{
+        Ok(())
+    }
+}
+
+impl PieceSink for File{
+    fn write_piece(&mut self, offset: u64, data: &[u8]) -> Result<(), Error>{
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(data)
+    }
+}
+
+impl PieceSink for Vec<u8>{
+    fn write_piece(&mut self, offset: u64, data: &[u8]) -> Result<(), Error>{
+        let end = offset as usize + data.len();
+        if self.len() < end{
+            self.resize(end, 0);
+        }
+        self[offset as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+// A source or sink, shared with the work `Link::offload` runs on it.
+type Shared<T> = Arc<Mutex<T>>;
+
+// A panic in offloaded work is raised again where it was awaited, the poison carries no news.
+fn lock<T>(shared: &Mutex<T>) -> MutexGuard<'_, T>{
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Offloaded work drops its clone before it is done, the transfer holds the last one.
+fn unshare<T>(shared: Shared<T>) -> T{
+    let Ok(mutex) = Arc::try_unwrap(shared) else{
+        unreachable!("offloaded work outlived the transfer");
+    };
+    mutex.into_inner().unwrap_or_else(PoisonError::into_inner)
+}
+
+/*================================================= SENDER ============================================================= */
+
+// Client side of a request: sent until the server answers it, the answer is
+// then acked like any control. An unencrypted Post has no answer of its own,
+// its request goes with the Metadata instead, see `send_metadata`.
+pub async fn send_request(link: &impl Link, request: &[u8], stats: &mut TransferStats) -> Option<ZTPResponse>{
+    let res = exchange(link, &[request], "Request", stats, true).await?;
+    acknowledge(link, &res);
+    Some(res)
+}
+
+// Returns the receiver's reply, an Ack unless it refused the transfer. A `request`
+// goes out again with every try of the Metadata, for a lost one to be recovered too.
+pub async fn send_metadata(
+    link: &impl Link,
+    request: Option<&[u8]>,
+    metadata: ZTPMetadata,
+    stats: &mut TransferStats
+) -> Option<ZTPResponse>{
+    let response = ZTPResponse::new(
+        ZTPResponseCode::Metadata,
+        Some(ZTPResponseData::Metadata(metadata)),
+        None
+    );
+    let control = response.encode_to_vec().ok()?;
+    match request{
+        Some(request) => exchange(link, &[request, &control], "Request and Metadata", stats, false).await,
+        None => exchange(link, &[&control], "Metadata", stats, false).await
+    }
+}
+
+// Server half of the key exchange, always sent before the Metadata. The Metadata
+// of a Post confirms it as well as an Ack, the client sends it again until acked.
+pub async fn send_handshake(link: &impl Link, public_key: [u8; 32], stats: &mut TransferStats) -> bool{
+    let response = ZTPResponse::new(
+        ZTPResponseCode::Handshake,
+        Some(ZTPResponseData::PublicKey(public_key)),
+        None
+    );
+    send_control(link, response, "Handshake", stats).await.is_some_and(|res| res.get_error().is_none())
+}
+
+// Answers a Delete or Rename once it is carried out.
+pub async fn send_done(link: &impl Link, stats: &mut TransferStats) -> bool{
+    let response = ZTPResponse::new(ZTPResponseCode::Ack, None, None);
+    send_control(link, response, "Done", stats).await.is_some_and(|res| res.is_ack())
+}
+
+async fn send_control(link: &impl Link, response: ZTPResponse, name: &str, stats: &mut TransferStats) -> Option<ZTPResponse>{
+    let control = response.encode_to_vec().ok()?;
+    exchange(link, &[&control], name, stats, false).await
+}
+
+// Sends `datagrams` until the peer answers, again each time the backoff runs out,
+// so losing them or the answer costs a retransmission instead of the transfer.
+// None once `max_retries` retransmissions went unanswered. Only the answer to a
+// request may be a Handshake, anywhere else it is a repeat of one.
+async fn exchange(
+    link: &impl Link,
+    datagrams: &[&[u8]],
+    name: &str,
+    stats: &mut TransferStats,
+    is_request: bool
+) -> Option<ZTPResponse>{
+    let mut rx_buff = [0u8; 4096];
+    for tries in 0..=stats.rtt.max_retries(){
+        let sent_at = Instant::now();
+        for datagram in datagrams{
+            let _ = link.send(datagram);
+        }
+        debug!("Sent {name}, try = {tries}, waiting for an answer...");
+
+        let deadline = sent_at + stats.rtt.backoff(tries);
+        while let Some(res) = wait_for_response(link, &mut rx_buff, deadline.saturating_duration_since(Instant::now())).await{
+            if !is_request && repeated(link, &res, &[ZTPResponseCode::Handshake]){
+                continue;
+            }
+            if res.is_ack(){
+                debug!("{name} ACK received!");
+                if tries == 0 {stats.rtt.sample(sent_at.elapsed());}
+            }
+            return Some(res);
+        }
+    }
+    warn!("No answer to {name} after {} retries", stats.rtt.max_retries());
+    None
+}
+
+// One of `controls` the peer sent again because our Ack of it got lost, acked again.
+fn repeated(link: &impl Link, res: &ZTPResponse, controls: &[ZTPResponseCode]) -> bool{
+    if !controls.contains(&res.get_code()){
+        return false;
+    }
+    trace!("{:?} repeated, acking it again", res.get_code());
+    send_ack(link, &mut [0u8; 256], None);
+    true
+}
+
+// Pieces are read from `source` as they are sent, so only the window is ever in memory.
+// Fails with the error sent to the receiver when giving up, or with the one it sent.
+pub async fn send_resource(
+    link: &impl Link,
+    source: impl Read + Seek + Send + 'static,
+    metadata: ZTPMetadata,
+    stats: &mut TransferStats
+) -> Result<(), ZTPErrorReport>{
+    debug!("Resource Size: {}", metadata.size());
+
+    let source = Arc::new(Mutex::new(source));
+    match metadata.mode(){
+        ZTPTransferMode::StopAndWait => send_stop_and_wait(link, &source, metadata, stats).await,
+        ZTPTransferMode::SelectiveRepeat(window) => send_selective_repeat(link, &source, metadata, window, stats).await,
+        ZTPTransferMode::GoBackN(window) => send_go_back_n(link, &source, metadata, window, stats).await,
+    }?;
+
+    finish_transfer(link, stats).await
+}
+
+async fn send_stop_and_wait(
+    link: &impl Link,
+    source: &Shared<impl Read + Seek + Send + 'static>,
+    metadata: ZTPMetadata,
+    stats: &mut TransferStats
+) -> Result<(), ZTPErrorReport>{
+    let mut rx_buffer: [u8; 4096] = [0; 4096];
+    for pkg_id in metadata.start_pkg()..metadata.count() as u64{
+        let piece = build_piece(link, source, pkg_id, &metadata).await?;
+
+        let mut tries = 0;
+        loop{
+            trace!("Sending Data Piece {pkg_id}, try = {tries}");
+            let sent_at = Instant::now();
+            let _ = link.send(&piece);
+            stats.count_sent(tries);
+
+            // late ACKs for earlier pieces may still arrive, only ours counts
+            let deadline = sent_at + stats.rtt.backoff(tries);
+            let mut is_ack = false;
+            while let Some(res) = wait_for_response(link, &mut rx_buffer, deadline.saturating_duration_since(Instant::now())).await{
+                if let Some(error) = peer_error(&res) {return Err(error);}
+                if res.get_pkg_id() != Some(pkg_id) {continue;}
+                is_ack = res.is_ack();
+                break;
+            }
+
+            if is_ack{
+                if tries == 0 {stats.rtt.sample(sent_at.elapsed());}
+                break;
+            }
+            if tries >= stats.rtt.max_retries() {return Err(give_up(link, stats));}
+            tries += 1;
+        }
+    }
+    Ok(())
+}
+
+struct InFlight{
+    piece: Vec<u8>,
+    sent_at: Instant,
+    tries: usize,
+}
+
+impl InFlight{
+    fn send(link: &impl Link, piece: Vec<u8>, stats: &mut TransferStats) -> InFlight{
+        let _ = link.send(&piece);
+        stats.count_sent(0);
+        InFlight{piece, sent_at: Instant::now(), tries: 0}
+    }
+
+    fn resend(&mut self, link: &impl Link, stats: &mut TransferStats) -> bool{
+        if self.tries >= stats.rtt.max_retries() {return false;}
+        let _ = link.send(&self.piece);
+        self.sent_at = Instant::now();
+        self.tries += 1;
+        stats.count_sent(self.tries);
+        true
+    }
+}
+
+// Keeps up to `window` pieces in flight, each with its own timer. Pieces are
+// acknowledged individually and only the ones NACKed or timed out are resent.
+async fn send_selective_repeat(
+    link: &impl Link,
+    source: &Shared<impl Read + Seek + Send + 'static>,
+    metadata: ZTPMetadata,
+    window: u16,
+    stats: &mut TransferStats
+) -> Result<(), ZTPErrorReport>{
+    let mut rx_buffer: [u8; 4096] = [0; 4096];
+    let mut sender = SelectiveRepeat::new(metadata, window);
+
+    while !sender.is_done(){
+        sender.fill(link, source, stats).await?;
+        while let Some(res) = get_response(link, &mut rx_buffer){
+            sender.on_response(&res, link, stats)?;
+        }
+        sender.on_timers(link, stats)?;
+        if let Some(deadline) = sender.next_timeout(stats){
+            link.wait(deadline).await.map_err(|_| closed(link))?;
+        }
+    }
+    Ok(())
+}
+
+// State of a Selective Repeat sender.
+struct SelectiveRepeat{
+    metadata: ZTPMetadata,
+    window: u64,
+    count: u64,
+    in_flight: BTreeMap<u64, InFlight>,
+    base: u64,
+    next: u64,
+}
+
+impl SelectiveRepeat{
+    fn new(metadata: ZTPMetadata, window: u16) -> SelectiveRepeat{
+        let base = metadata.start_pkg();
+        SelectiveRepeat{
+            metadata,
+            window: window as u64,
+            count: metadata.count() as u64,
+            in_flight: BTreeMap::new(),
+            base,
+            next: base,
+        }
+    }
+
+    fn is_done(&self) -> bool{
+        self.base >= self.count
+    }
+
+    async fn fill(
+        &mut self,
+        link: &impl Link,
+        source: &Shared<impl Read + Seek + Send + 'static>,
+        stats: &mut TransferStats
+    ) -> Result<(), ZTPErrorReport>{
+        while self.next < self.count && self.next < self.base + self.window{
+            trace!("Sending Data Piece {}", self.next);
+            let piece = build_piece(link, source, self.next, &self.metadata).await?;
+            self.in_flight.insert(self.next, InFlight::send(link, piece, stats));
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    fn on_response(&mut self, res: &ZTPResponse, link: &impl Link, stats: &mut TransferStats) -> Result<(), ZTPErrorReport>{
+        if let Some(error) = peer_error(res) {return Err(error);}
+        match (res.get_code(), res.get_pkg_id()){
+            (ZTPResponseCode::Ack, Some(pkg_id)) => {
+                if let Some(acked) = self.in_flight.remove(&pkg_id){
+                    if acked.tries == 0 {stats.rtt.sample(acked.sent_at.elapsed());}
+                }
+            },
+            (ZTPResponseCode::Nack, Some(pkg_id)) => {
+                if let Some(pending) = self.in_flight.get_mut(&pkg_id){
+                    trace!("Piece {pkg_id} NACKed, resending");
+                    if !pending.resend(link, stats) {return Err(give_up(link, stats));}
+                }
+            },
+            _ => {}
+        }
+        self.base = self.in_flight.keys().next().copied().unwrap_or(self.next);
+        Ok(())
+    }
+
+    fn on_timers(&mut self, link: &impl Link, stats: &mut TransferStats) -> Result<(), ZTPErrorReport>{
+        for (pkg_id, pending) in self.in_flight.iter_mut(){
+            if pending.sent_at.elapsed() >= stats.rtt.backoff(pending.tries){
+                trace!("Piece {pkg_id} timed out, resending");
+                if !pending.resend(link, stats) {return Err(give_up(link, stats));}
+            }
+        }
+        Ok(())
+    }
+
+    // When the oldest running timer fires, None with nothing in flight.
+    fn next_timeout(&self, stats: &TransferStats) -> Option<Instant>{
+        self.in_flight.values().map(|pending| pending.sent_at + stats.rtt.backoff(pending.tries)).min()
+    }
+}
+
+// Keeps up to `window` pieces in flight under a single timer. ACKs are cumulative
+// (highest in-order pkg_id) and a timeout resends everything from the oldest unacked piece.
+async fn send_go_back_n(
+    link: &impl Link,
+    source: &Shared<impl Read + Seek + Send + 'static>,
+    metadata: ZTPMetadata,
+    window: u16,
+    stats: &mut TransferStats
+) -> Result<(), ZTPErrorReport>{
+    let mut rx_buffer: [u8; 4096] = [0; 4096];
+    let mut sender = GoBackN::new(metadata, window);
+
+    while !sender.is_done(){
+        sender.fill(link, source, stats).await?;
+        while let Some(res) = get_response(link, &mut rx_buffer){
+            sender.on_response(&res, stats)?;
+        }
+        sender.on_timer(link, source, stats).await?;
+        if let Some(deadline) = sender.next_timeout(stats){
+            link.wait(deadline).await.map_err(|_| closed(link))?;
+        }
+    }
+    Ok(())
+}
+
+// State of a Go-Back-N sender.
+struct GoBackN{
+    metadata: ZTPMetadata,
+    window: u64,
+    count: u64,
+    base: u64,
+    next: u64,
+    timer: Instant,
+    tries: usize,
+    // send times of pieces that were never retransmitted, the only valid RTT samples
+    sent_at: BTreeMap<u64, Instant>,
+}
+
+impl GoBackN{
+    fn new(metadata: ZTPMetadata, window: u16) -> GoBackN{
+        let base = metadata.start_pkg();
+        GoBackN{
+            metadata,
+            window: window as u64,
+            count: metadata.count() as u64,
+            base,
+            next: base,
+            timer: Instant::now(),
+            tries: 0,
+            sent_at: BTreeMap::new(),
+        }
+    }
+
+    fn is_done(&self) -> bool{
+        self.base >= self.count
+    }
+
+    async fn fill(
+        &mut self,
+        link: &impl Link,
+        source: &Shared<impl Read + Seek + Send + 'static>,
+        stats: &mut TransferStats
+    ) -> Result<(), ZTPErrorReport>{
+        while self.next < self.count && self.next < self.base + self.window{
+            trace!("Sending Data Piece {}", self.next);
+            let piece = build_piece(link, source, self.next, &self.metadata).await?;
+            let _ = link.send(&piece);
+            stats.count_sent(0);
+            self.sent_at.insert(self.next, Instant::now());
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    fn on_response(&mut self, res: &ZTPResponse, stats: &mut TransferStats) -> Result<(), ZTPErrorReport>{
+        if let Some(error) = peer_error(res) {return Err(error);}
+        if !res.is_ack() {return Ok(());}
+        if let Some(acked) = res.get_package_index(){
+            let acked = acked as u64;
+            if acked < self.base {return Ok(());}
+            if let Some(sent) = self.sent_at.get(&acked){
+                stats.rtt.sample(sent.elapsed());
+            }
+            self.sent_at = self.sent_at.split_off(&(acked + 1));
+            self.base = acked + 1;
+            self.timer = Instant::now();
+            self.tries = 0;
+        }
+        Ok(())
+    }
+
+    async fn on_timer(
+        &mut self,
+        link: &impl Link,
+        source: &Shared<impl Read + Seek + Send + 'static>,
+        stats: &mut TransferStats
+    ) -> Result<(), ZTPErrorReport>{
+        if self.base >= self.next || self.timer.elapsed() < stats.rtt.backoff(self.tries){
+            return Ok(());
+        }
+        if self.tries >= stats.rtt.max_retries() {return Err(give_up(link, stats));}
+        self.tries += 1;
+        trace!("Timeout, going back to piece {}", self.base);
+        for pkg_id in self.base..self.next{
+            let piece = build_piece(link, source, pkg_id, &self.metadata).await?;
+            let _ = link.send(&piece);
+            stats.count_sent(self.tries);
+        }
+        self.sent_at.clear();
+        self.timer = Instant::now();
+        Ok(())
+    }
+
+    // When the window timer fires, None with nothing in flight.
+    fn next_timeout(&self, stats: &TransferStats) -> Option<Instant>{
+        (self.base < self.next).then(|| self.timer + stats.rtt.backoff(self.tries))
+    }
+}
+
+async fn build_piece(
+    link: &impl Link,
+    source: &Shared<impl Read + Seek + Send + 'static>,
+    pkg_id: u64,
+    metadata: &ZTPMetadata
+) -> Result<Vec<u8>, ZTPErrorReport>{
+    let start = metadata.size().min(pkg_id as usize * metadata.piece_size());
+    let end = metadata.size().min(start + metadata.piece_size());
+
+    let source = Arc::clone(source);
+    let read = link.offload(move ||{
+        let mut source = lock(&source);
+        let mut bytes = vec![0u8; end - start];
+        source.seek(SeekFrom::Start(start as u64)).and_then(|_| source.read_exact(&mut bytes)).map(|_| bytes)
+    });
+    let bytes = match read.await{
+        Ok(bytes) => bytes,
+        Err(e) => return Err(abort(link, ZTPErrorCode::Io, format!("could not read piece {pkg_id}: {e}")))
+    };
+    let response = ZTPResponse::new_piece(bytes, pkg_id, metadata.checksum());
+    ZTPResponse::encode_to_vec(response)
+        .map_err(|e| abort(link, ZTPErrorCode::Io, format!("could not encode piece {pkg_id}: {e}")))
+}
+
+// Sends EndRequest until the receiver acknowledges it (or answers with its own EndRequest).
+async fn finish_transfer(link: &impl Link, stats: &mut TransferStats) -> Result<(), ZTPErrorReport>{
+    let mut tx_buff = [0u8; 256];
+    let mut rx_buff = [0u8; 4096];
+    let end_of_req = ZTPResponse::new(ZTPResponseCode::EndRequest, None, None);
+    let bytes = ZTPResponse::encode_into_slice(end_of_req, &mut tx_buff)
+        .map_err(|e| abort(link, ZTPErrorCode::Io, format!("could not encode EndRequest: {e}")))?;
+
+    for tries in 0..=stats.rtt.max_retries(){
+        let deadline = Instant::now() + stats.rtt.backoff(tries);
+        let _ = link.send(&tx_buff[..bytes]);
+
+        while let Some(res) = wait_for_response(link, &mut rx_buff, deadline.saturating_duration_since(Instant::now())).await{
+            if let Some(error) = peer_error(&res) {return Err(error);}
+            let is_end_ack = res.is_ack() && res.get_pkg_id().is_none() && !res.has_data();
+            if is_end_ack || res.get_code() == ZTPResponseCode::EndRequest{
+                return Ok(());
+            }
+        }
+    }
+    Err(give_up(link, stats))
+}
+
+// Tells the peer we are giving up on the transfer, so it stops waiting for us.
+fn abort(link: &impl Link, code: ZTPErrorCode, message: String) -> ZTPErrorReport{
+    let error = ZTPErrorReport::new(code, message);
+    warn!("Aborting the transfer: {error}");
+    send_error(link, &error);
+    error
+}
+
+fn give_up(link: &impl Link, stats: &TransferStats) -> ZTPErrorReport{
+    abort(link, ZTPErrorCode::Aborted, format!("no answer after {} retries", stats.rtt.max_retries()))
+}
+
+fn closed(link: &impl Link) -> ZTPErrorReport{
+    abort(link, ZTPErrorCode::Aborted, "the session was closed".to_string())
+}
+
+fn peer_error(res: &ZTPResponse) -> Option<ZTPErrorReport>{
+    let error = res.get_error()?;
+    warn!("The peer gave up on the transfer: {error}");
+    Some(error)
+}
+
+// Sent once, like every final answer.
+pub fn send_error(link: &impl Link, error: &ZTPErrorReport) -> usize{
+    send_response(link, ZTPResponse::error(error.clone()))
+}
+
+// Fire and forget, 0 when the response could not be encoded or sent.
+pub fn send_response(link: &impl Link, response: ZTPResponse) -> usize{
+    match response.encode_to_vec(){
+        Ok(vec) => link.send(&vec).unwrap_or(0),
+        Err(e) => {
+            error!("Could not encode a response: {e}");
+            0
+        }
+    }
+}
+
+fn get_response(link: &impl Link, rx_buff: &mut [u8]) -> Option<ZTPResponse>{
+    match link.recv(rx_buff){
+        Ok(bytes) =>{
+            trace!("Received {bytes} bytes");
+            parse_response(&rx_buff[..bytes])
+        },
+        Err(_) => None
+    }
+}
+
+// Waits for a response until `timeout` runs out or the link is closed.
+pub async fn wait_for_response(link: &impl Link, rx_buff: &mut [u8], timeout: Duration) -> Option<ZTPResponse>{
+    let deadline = Instant::now() + timeout;
+    loop{
+        if let Some(res) = get_response(link, rx_buff){
+            return Some(res);
+        }
+        if !link.wait(deadline).await.ok()?{
+            return None;
+        }
+    }
+}
+
+/*================================================= RECEIVER ============================================================= */
+
+pub async fn receive_metadata(link: &impl Link, stats: &mut TransferStats) -> Option<ZTPMetadata>{
+    extract_metadata(receive_control(link, stats).await?)
+}
+
+// Waits for the next control response (metadata, an error...) and acks it. The
+// answer to the request came before, a Handshake here is a repeat of it.
+pub async fn receive_control(link: &impl Link, stats: &mut TransferStats) -> Option<ZTPResponse>{
+    let mut rx_buff = [0u8; 4096];
+    let deadline = Instant::now() + stats.rtt.give_up_after();
+
+    loop{
+        let res = wait_for_response(link, &mut rx_buff, deadline.saturating_duration_since(Instant::now())).await?;
+        if repeated(link, &res, &[ZTPResponseCode::Handshake]){
+            continue;
+        }
+        acknowledge(link, &res);
+        return Some(res);
+    }
+}
+
+// Errors are final, nobody waits for them to be acknowledged.
+fn acknowledge(link: &impl Link, res: &ZTPResponse){
+    if res.get_error().is_none(){
+        send_ack(link, &mut [0u8; 256], None);
+    }
+}
+
+// Writes every piece from `metadata.start_pkg()` on at its offset in `sink`, in
+// whatever order they arrive. Succeeds only once every piece is there, `sink`
+// comes back either way. `corrupt_percent` is fault injection, see `Faults`.
+pub async fn receive_resource<W: PieceSink + Send + 'static>(
+    link: &impl Link,
+    metadata: ZTPMetadata,
+    stats: &mut TransferStats,
+    sink: W,
+    corrupt_percent: u8
+) -> (W, Result<(), ZTPErrorReport>){
+    let mut reception = Reception::new(metadata, sink, corrupt_percent);
+    let received = reception.run(link, stats).await;
+    (unshare(reception.sink), received)
+}
+
+struct Reception<W: PieceSink>{
+    sink: Shared<W>,
+    mode: ZTPTransferMode,
+    piece_size: u64,
+    failure: Option<ZTPErrorReport>,
+    delivered: usize,
+    res_code: ZTPResponseCode,
+    received: PieceBitmap,
+    expected: u64,
+    window: u64,
+    checksum: ZTPChecksum,
+    faults: Faults,
+    started_at: Instant,
+    last_activity: Option<Instant>,
+}
+
+impl<W: PieceSink + Send + 'static> Reception<W>{
+    fn new(metadata: ZTPMetadata, sink: W, corrupt_percent: u8) -> Reception<W>{
+        let window = match metadata.mode().negotiate(){
+            ZTPTransferMode::SelectiveRepeat(window) => window as u64,
+            ZTPTransferMode::GoBackN(_) | ZTPTransferMode::StopAndWait => 1,
+        };
+        Reception{
+            sink: Arc::new(Mutex::new(sink)),
+            mode: metadata.mode(),
+            piece_size: metadata.piece_size() as u64,
+            failure: None,
+            delivered: 0,
+            res_code: ZTPResponseCode::Data,
+            received: PieceBitmap::new(metadata.count(), metadata.start_pkg()),
+            expected: metadata.start_pkg(),
+            window,
+            checksum: metadata.checksum(),
+            faults: Faults::new(corrupt_percent),
+            started_at: Instant::now(),
+            last_activity: None,
+        }
+    }
+
+    async fn run(&mut self, link: &impl Link, stats: &mut TransferStats) -> Result<(), ZTPErrorReport>{
+        let mut rx_buff = [0u8; 4096];
+        debug!("Receiving resource");
+        while !self.is_over(){
+            match link.recv(&mut rx_buff){
+                Ok(bytes) => self.on_datagram(&rx_buff[..bytes], link, stats).await?,
+                Err(e) if e.kind() == ErrorKind::ConnectionAborted => return Err(closed(link)),
+                Err(_) => {
+                    self.check_idle(link, stats)?;
+                    link.wait(self.idle_deadline(stats)).await.map_err(|_| closed(link))?;
+                }
+            }
+        }
+        self.finish(link)
+    }
+
+    // The sender ended the transfer.
+    fn is_over(&self) -> bool{
+        self.res_code == ZTPResponseCode::EndRequest
+    }
+
+    async fn on_datagram(&mut self, datagram: &[u8], link: &impl Link, stats: &mut TransferStats) -> Result<(), ZTPErrorReport>{
+        let mut tx_buff = [0u8; 4096];
+        // our metadata ACK to the first piece is the only round trip a receiver can time
+        if self.last_activity.is_none(){
+            stats.rtt.sample(self.started_at.elapsed());
+        }
+        self.last_activity = Some(Instant::now());
+
+        // an unencrypted Post repeats its request with every Metadata, only the Metadata is acked again
+        if ZTPRequest::decode_from_slice(datagram).is_ok(){
+            return Ok(());
+        }
+        let Some(response) = parse_response(datagram) else{
+            send_nack(link, &mut tx_buff, None);
+            return Ok(());
+        };
+        if let Some(error) = peer_error(&response){
+            return Err(error);
+        }
+        if repeated(link, &response, &[ZTPResponseCode::Metadata, ZTPResponseCode::Handshake]){
+            return Ok(());
+        }
+        match self.mode{
+            ZTPTransferMode::StopAndWait => process_response(response, self, link, &mut tx_buff, stats).await,
+            ZTPTransferMode::SelectiveRepeat(_) => process_window_response(response, self, link, &mut tx_buff, stats).await,
+            ZTPTransferMode::GoBackN(_) => process_in_order_response(response, self, link, &mut tx_buff, stats).await,
+        }
+
+        if let Some(failure) = self.failure.take(){
+            return Err(abort(link, failure.code, failure.message));
+        }
+        Ok(())
+    }
+
+    // When the receiver gives up if nothing else arrives.
+    fn idle_deadline(&self, stats: &TransferStats) -> Instant{
+        self.last_activity.unwrap_or(self.started_at) + stats.rtt.give_up_after()
+    }
+
+    fn check_idle(&self, link: &impl Link, stats: &TransferStats) -> Result<(), ZTPErrorReport>{
+        if Instant::now() > self.idle_deadline(stats){
+            let message = format!("nothing arrived for {}ms", stats.rtt.give_up_after().as_millis());
+            return Err(abort(link, ZTPErrorCode::Aborted, message));
+        }
+        Ok(())
+    }
+
+    fn finish(&self, link: &impl Link) -> Result<(), ZTPErrorReport>{
+        match self.received.missing(){
+            0 => Ok(()),
+            missing => Err(abort(link, ZTPErrorCode::Aborted, format!("the transfer ended with {missing} pieces missing")))
+        }
+    }
+}
+
+fn parse_response(
+    buffer: &[u8]
+) -> Option<ZTPResponse>{
+    match ZTPResponse::decode_from_slice(buffer){
+        Ok((res, _)) => Some(res),
+        Err(e @ ZTPWireError::UnsupportedVersion(_)) => {
+            trace!("Dropping response: {e}");
+            None
+        },
+        Err(_) => None
+    }
+}
+
+async fn process_response(
+    response: ZTPResponse,
+    reception: &mut Reception<impl PieceSink + Send + 'static>,
+    link: &impl Link,
+    tx_buff: &mut [u8],
+    stats: &mut TransferStats,
+){
+    reception.res_code = response.get_code();
+    match reception.res_code{
+        ZTPResponseCode::Data => {
+            let Some((data, incoming_hash, pkg_id)) = piece_parts(&response) else{
+                return;
+            };
+            let hash_result = calculate_hash(data, reception.checksum, &mut reception.faults);
+            trace!("Incoming Hash: {}; Calculated Hash: {}", to_hex(incoming_hash), to_hex(&hash_result));
+            if hash_result != incoming_hash{
+                stats.pieces_rejected += 1;
+                send_nack(link, tx_buff, Some(pkg_id));
+                return;
+            }
+            // the sender waits for every piece to be acked, one past the next missing piece was never sent
+            if pkg_id >= reception.expected + reception.window{
+                return;
+            }
+            // a duplicate means our ACK got lost, so it is acknowledged again
+            if store_piece(reception, link, pkg_id, data).await{
+                stats.pieces_received += 1;
+                trace!("Received {} bytes", data.len());
+                trace!("Total Received: {}", reception.delivered);
+            }
+            send_ack(link, tx_buff, Some(pkg_id));
+            reception.expected = reception.received.next_missing(reception.expected);
+        },
+        ZTPResponseCode::EndRequest => end_reception(reception, link, tx_buff).await,
+        _ => {}
+    }
+}
+
+async fn process_window_response(
+    response: ZTPResponse,
+    reception: &mut Reception<impl PieceSink + Send + 'static>,
+    link: &impl Link,
+    tx_buff: &mut [u8],
+    stats: &mut TransferStats,
+){
+    reception.res_code = response.get_code();
+    match reception.res_code{
+        ZTPResponseCode::Data => {
+            let Some((data, hash, pkg_id)) = piece_parts(&response) else{
+                return;
+            };
+            if calculate_hash(data, reception.checksum, &mut reception.faults) != hash{
+                stats.pieces_rejected += 1;
+                send_nack(link, tx_buff, Some(pkg_id));
+                return;
+            }
+
+            // pieces below the window were already stored, their ACK got lost
+            if pkg_id < reception.expected{
+                send_ack(link, tx_buff, Some(pkg_id));
+                return;
+            }
+            if pkg_id >= reception.expected + reception.window{
+                return;
+            }
+
+            if store_piece(reception, link, pkg_id, data).await{
+                stats.pieces_received += 1;
+            }
+            send_ack(link, tx_buff, Some(pkg_id));
+
+            reception.expected = reception.received.next_missing(reception.expected);
+            trace!("Total Received: {}", reception.delivered);
+        },
+        ZTPResponseCode::EndRequest => end_reception(reception, link, tx_buff).await,
+        _ => {}
+    }
+}
+
+async fn process_in_order_response(
+    response: ZTPResponse,
+    reception: &mut Reception<impl PieceSink + Send + 'static>,
+    link: &impl Link,
+    tx_buff: &mut [u8],
+    stats: &mut TransferStats,
+){
+    reception.res_code = response.get_code();
+    match reception.res_code{
+        ZTPResponseCode::Data => {
+            let Some((data, hash, pkg_id)) = piece_parts(&response) else{
+                return;
+            };
+            let is_valid = calculate_hash(data, reception.checksum, &mut reception.faults) == hash;
+
+            // anything but the next expected piece is dropped and the last in-order piece re-acked
+            if is_valid && pkg_id == reception.expected{
+                store_piece(reception, link, pkg_id, data).await;
+                reception.expected += 1;
+                stats.pieces_received += 1;
+                trace!("Total Received: {}", reception.delivered);
+            }
+            else{
+                stats.pieces_rejected += 1;
+            }
+            if reception.expected > 0{
+                send_cumulative_ack(link, tx_buff, reception.expected - 1);
+            }
+        },
+        ZTPResponseCode::EndRequest => end_reception(reception, link, tx_buff).await,
+        _ => {}
+    }
+}
+
+// Bytes, hash and index of a Data piece. A piece missing one of them is
+// dropped, the sender resends it like a lost one.
+fn piece_parts(response: &ZTPResponse) -> Option<(&[u8], &[u8], u64)>{
+    match (response.get_bytes(), response.get_hash(), response.get_pkg_id()){
+        (Some(data), Some(hash), Some(pkg_id)) => Some((data, hash, pkg_id)),
+        _ => {
+            trace!("Dropping malformed Data piece");
+            None
+        }
+    }
+}
+
+// The sender only ends once it saw every piece acked, a hole here means a piece
+// was lost for good, so the EndRequest is not acked and the reception fails.
+// A whole resource is acked only once the sink finished it.
+async fn end_reception<W: PieceSink + Send + 'static>(reception: &mut Reception<W>, link: &impl Link, tx_buff: &mut [u8]){
+    if !reception.received.is_complete(){
+        return;
+    }
+    let sink = Arc::clone(&reception.sink);
+    match link.offload(move || lock(&sink).finish()).await{
+        Ok(_) => {send_ack(link, tx_buff, None);},
+        Err(failure) => reception.failure = Some(failure)
+    }
+}
+
+fn send_cumulative_ack(link: &impl Link, tx_buff: &mut [u8], last_in_order: u64) -> usize{
+    let ack = ZTPResponse::new(
+        ZTPResponseCode::Ack,
+        Some(ZTPResponseData::PackageIndex(last_in_order as usize)),
+        None
+    );
+    trace!("Sending ACK up to {last_in_order}");
+    let Ok(bytes) = ZTPResponse::encode_into_slice(ack, tx_buff) else{
+        return 0;
+    };
+    link.send(&tx_buff[..bytes]).unwrap_or(0)
+}
+
+fn send_ack(link: &impl Link, tx_buff: &mut [u8], pkg_id: Option<u64>) -> usize{
+    let ack = ZTPResponse::new(
+        ZTPResponseCode::Ack,
+        None,
+        pkg_id
+    );
+    trace!("Sending ACK");
+    let Ok(bytes) = ZTPResponse::encode_into_slice(ack, tx_buff) else{
+        return 0;
+    };
+    link.send(&tx_buff[..bytes]).unwrap_or(0)
+}
+
+
+fn send_nack(link: &impl Link, tx_buff: &mut [u8], pkg_id: Option<u64>) -> usize{
+    trace!("Sending NACK");
+    let nack = ZTPResponse::new(
+        ZTPResponseCode::Nack,
+        None,
+        pkg_id
+    );
+    let Ok(bytes) = ZTPResponse::encode_into_slice(nack, tx_buff) else{
+        return 0;
+    };
+    link.send(&tx_buff[..bytes]).unwrap_or(0)
+}
+
+// Writes a piece at its offset, unless it is a duplicate or outside the resource.
+async fn store_piece<W: PieceSink + Send + 'static>(reception: &mut Reception<W>, link: &impl Link, pkg_id: u64, data: &[u8]) -> bool{
+    if !reception.received.insert(pkg_id){
+        return false;
+    }
+    let sink = Arc::clone(&reception.sink);
+    let (offset, piece) = (pkg_id * reception.piece_size, data.to_vec());
+    if let Err(e) = link.offload(move || lock(&sink).write_piece(offset, &piece)).await{
+        reception.failure = Some(ZTPErrorReport::new(ZTPErrorCode::Io, format!("could not store received data: {e}")));
+        return false;
+    }
+    reception.delivered += data.len();
+    true
+}
+
+// One bit per piece from the resume point on, pieces before it count as received.
+// Words are only added as pieces arrive, so the bitmap grows with what was received
+// rather than with the piece count the sender claims.
+struct PieceBitmap{
+    words: Vec<u64>,
+    start: u64,
+    count: u64,
+    received: u64,
+}
+
+impl PieceBitmap{
+    fn new(count: usize, start_pkg: u64) -> PieceBitmap{
+        let start = start_pkg.min(count as u64);
+        PieceBitmap{words: Vec::new(), start, count: count as u64, received: start}
+    }
+
+    // false if the piece was already there or is not part of the resource
+    fn insert(&mut self, pkg_id: u64) -> bool{
+        if pkg_id >= self.count || self.contains(pkg_id){
+            return false;
+        }
+        let (word, bit) = self.position(pkg_id);
+        if word >= self.words.len(){
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= bit;
+        self.received += 1;
+        true
+    }
+
+    fn contains(&self, pkg_id: u64) -> bool{
+        if pkg_id < self.start{
+            return true;
+        }
+        let (word, bit) = self.position(pkg_id);
+        pkg_id < self.count && self.words.get(word).is_some_and(|word| word & bit != 0)
+    }
+
+    // The first piece from `pkg_id` on that has not arrived, `count` if there is none.
+    fn next_missing(&self, pkg_id: u64) -> u64{
+        let mut pkg_id = pkg_id.max(self.start);
+        while pkg_id < self.count{
+            let offset = (pkg_id - self.start) % 64;
+            let word = self.words.get(self.position(pkg_id).0).copied().unwrap_or(0);
+            let present = (word >> offset).trailing_ones() as u64;
+            if present < 64 - offset{
+                return (pkg_id + present).min(self.count);
+            }
+            pkg_id += 64 - offset;
+        }
+        self.count
+    }
+
+    fn missing(&self) -> u64{
+        self.count - self.received
+    }
+
+    fn is_complete(&self) -> bool{
+        self.missing() == 0
+    }
+
+    // the word holding `pkg_id` and its bit in it, `pkg_id` not before `start`
+    fn position(&self, pkg_id: u64) -> (usize, u64){
+        let index = pkg_id - self.start;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+}
+
+pub fn extract_metadata(response: ZTPResponse) -> Option<ZTPMetadata>{
+    if let Some(ZTPResponseData::Metadata(metadata)) = response.get_data(){
+        return Some(*metadata);
+    }
+    None
+}
+
+fn calculate_hash(data: &[u8], checksum: ZTPChecksum, faults: &mut Faults) -> Vec<u8>{
+    if faults.corrupts(){
+        return Vec::new();
+    }
+    checksum.digest(data)
+}
+
+// Fault injection for exercising the retransmissions: that share of the pieces
+// received is rejected as if it arrived corrupt. Off (0) unless configured.
+struct Faults{
+    corrupt_percent: u8,
+    rng: StdRng,
+}
+
+impl Faults{
+    fn new(corrupt_percent: u8) -> Faults{
+        Faults{corrupt_percent, rng: StdRng::from_rng(&mut rand::rng())}
+    }
+
+    fn corrupts(&mut self) -> bool{
+        self.corrupt_percent > 0 && self.rng.random_range(0u8..100) < self.corrupt_percent
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::io::Cursor;
+
+    use super::*;
+    use super::super::rtt::RttEstimator;
+    use super::super::testing::{socket_pair, Fault, LossyLink};
+    use crate::constants::DATA_PIECE_SIZE;
+
+    #[test]
+    fn bitmap_sets_pieces_across_word_boundaries(){
+        let mut bitmap = PieceBitmap::new(130, 0);
+        for pkg_id in [0, 63, 64, 127, 128, 129]{
+            assert!(!bitmap.contains(pkg_id));
+            assert!(bitmap.insert(pkg_id), "{pkg_id}");
+            assert!(bitmap.contains(pkg_id));
+            assert!(!bitmap.insert(pkg_id), "{pkg_id} twice");
+        }
+        assert!(!bitmap.contains(62) && !bitmap.contains(65) && !bitmap.contains(126));
+        assert_eq!(bitmap.missing(), 124);
+        // past the end of the resource
+        assert!(!bitmap.insert(130));
+        assert!(!bitmap.contains(130));
+    }
+
+    #[test]
+    fn bitmap_finds_the_next_missing_piece_across_word_boundaries(){
+        let mut bitmap = PieceBitmap::new(200, 0);
+        assert_eq!(bitmap.next_missing(0), 0);
+        for pkg_id in 0..63{
+            bitmap.insert(pkg_id);
+        }
+        assert_eq!(bitmap.next_missing(0), 63);
+        bitmap.insert(63);
+        assert_eq!(bitmap.next_missing(0), 64);
+        assert_eq!(bitmap.next_missing(10), 64);
+        for pkg_id in 64..128{
+            bitmap.insert(pkg_id);
+        }
+        // a whole word set is skipped, the next word starts missing
+        assert_eq!(bitmap.next_missing(0), 128);
+        assert_eq!(bitmap.next_missing(127), 128);
+        bitmap.insert(129);
+        assert_eq!(bitmap.next_missing(129), 130);
+        assert_eq!(bitmap.next_missing(200), 200);
+    }
+
+    #[test]
+    fn bitmap_next_missing_stops_at_the_last_piece(){
+        for count in [1, 63, 64, 65, 128]{
+            let mut bitmap = PieceBitmap::new(count, 0);
+            for pkg_id in 0..count as u64{
+                bitmap.insert(pkg_id);
+            }
+            assert!(bitmap.is_complete());
+            assert_eq!(bitmap.next_missing(0), count as u64, "{count} pieces");
+        }
+    }
+
+    #[test]
+    fn bitmap_counts_resumed_pieces_as_received(){
+        let bitmap = PieceBitmap::new(100, 64);
+        assert!(bitmap.contains(63));
+        assert!(!bitmap.contains(64));
+        assert_eq!(bitmap.missing(), 36);
+        assert_eq!(bitmap.next_missing(0), 64);
+
+        // a resume point past the end only marks the pieces there are
+        let bitmap = PieceBitmap::new(10, 20);
+        assert!(bitmap.is_complete());
+        assert_eq!(bitmap.next_missing(0), 10);
+    }
+
+    #[test]
+    fn bitmap_only_grows_with_the_pieces_received(){
+        let mut bitmap = PieceBitmap::new(usize::MAX, u64::MAX / 2);
+        assert!(bitmap.words.is_empty());
+        assert_eq!(bitmap.next_missing(0), u64::MAX / 2);
+        assert!(bitmap.insert(u64::MAX / 2 + 70));
+        assert_eq!(bitmap.words.len(), 2);
+        assert!(bitmap.contains(u64::MAX / 2 + 70));
+        assert_eq!(bitmap.next_missing(u64::MAX / 2 + 70), u64::MAX / 2 + 71);
+    }
+
+    fn quick_stats() -> TransferStats{
+        TransferStats::with_rtt(RttEstimator::with_limits(Duration::from_millis(20), 4))
+    }
+
+    fn next_response(link: &UdpSocket) -> Option<ZTPResponse>{
+        block_on(wait_for_response(link, &mut [0u8; 4096], Duration::from_secs(2)))
+    }
+
+    #[test]
+    fn a_lost_control_is_sent_again_until_acked(){
+        let (sender, peer) = socket_pair();
+        let peer = thread::spawn(move ||{
+            // the first Done gets lost, the second is acked
+            assert_eq!(next_response(&peer).unwrap().get_code(), ZTPResponseCode::Ack);
+            assert_eq!(next_response(&peer).unwrap().get_code(), ZTPResponseCode::Ack);
+            send_ack(&peer, &mut [0u8; 256], None);
+        });
+        let mut stats = quick_stats();
+        assert!(block_on(send_done(&sender, &mut stats)));
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn an_unanswered_control_gives_up(){
+        let (sender, _peer) = socket_pair();
+        let mut stats = quick_stats();
+        let started = Instant::now();
+        assert!(!block_on(send_done(&sender, &mut stats)));
+        assert!(started.elapsed() >= stats.rtt.backoff(0) * 5);
+    }
+
+    #[test]
+    fn a_repeated_handshake_is_acked_again(){
+        let (client, server) = socket_pair();
+        let handshake = ZTPResponse::new(ZTPResponseCode::Handshake, Some(ZTPResponseData::PublicKey([7; 32])), None);
+        let bytes = handshake.encode_to_vec().unwrap();
+        let server = thread::spawn(move ||{
+            // the Handshake is sent again as if its Ack got lost, then the Metadata
+            server.send(&bytes).unwrap();
+            assert!(next_response(&server).unwrap().is_ack());
+            let metadata = ZTPMetadata::new(0, 1, ZTPTransferMode::StopAndWait, [0; 32]);
+            let mut stats = quick_stats();
+            assert!(block_on(send_metadata(&server, None, metadata, &mut stats)).unwrap().is_ack());
+        });
+        let mut stats = quick_stats();
+        assert!(block_on(receive_metadata(&client, &mut stats)).is_some());
+        server.join().unwrap();
+    }
+
+    /*=== LOOPBACK TRANSFERS ===*/
+
+    const PIECES: usize = 8;
+
+    fn resource() -> Vec<u8>{
+        // the last piece is a short one
+        (0..PIECES * DATA_PIECE_SIZE - 100).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    // One resource sent from one end of a loopback link to the other, each end
+    // injecting its own faults. Both links come back for their logs.
+    fn transfer(
+        mode: ZTPTransferMode,
+        sender_faults: &[Fault],
+        receiver_faults: &[Fault]
+    ) -> (LossyLink<UdpSocket>, LossyLink<UdpSocket>, TransferStats){
+        let resource = resource();
+        let metadata = ZTPMetadata::from_reader(&mut Cursor::new(&resource), mode).unwrap();
+        let (sender_socket, receiver_socket) = socket_pair();
+        let receiver = LossyLink::new(receiver_socket, receiver_faults);
+        let receiver = thread::spawn(move ||{
+            let mut stats = quick_stats();
+            let (received, result) = block_on(receive_resource(&receiver, metadata, &mut stats, Vec::new(), 0));
+            result.unwrap();
+            (receiver, stats, received)
+        });
+
+        let sender = LossyLink::new(sender_socket, sender_faults);
+        let mut sent_stats = quick_stats();
+        block_on(send_resource(&sender, Cursor::new(resource.clone()), metadata, &mut sent_stats)).unwrap();
+        let (receiver, received_stats, received) = receiver.join().unwrap();
+
+        assert_eq!(received, resource);
+        assert!(sender.is_spent() && receiver.is_spent(), "every fault was injected");
+        assert!(sent_stats.retransmissions > 0);
+        (sender, receiver, received_stats)
+    }
+
+    #[test]
+    fn stop_and_wait_resends_a_lost_piece_and_one_whose_ack_got_lost(){
+        let (sender, receiver, _) = transfer(
+            ZTPTransferMode::StopAndWait,
+            &[Fault::Lose((ZTPResponseCode::Data, Some(1)))],
+            &[Fault::Lose((ZTPResponseCode::Ack, Some(2)))]
+        );
+        assert!(sender.sent((ZTPResponseCode::Data, Some(1))) >= 2);
+        assert!(sender.sent((ZTPResponseCode::Data, Some(2))) >= 2);
+        // the piece came in twice and was acked both times
+        assert!(receiver.sent((ZTPResponseCode::Ack, Some(2))) >= 2);
+    }
+
+    #[test]
+    fn selective_repeat_acks_every_piece_and_resends_what_is_nacked(){
+        let (sender, receiver, stats) = transfer(
+            ZTPTransferMode::SelectiveRepeat(4),
+            &[Fault::Corrupt(2)],
+            &[Fault::Lose((ZTPResponseCode::Ack, Some(5)))]
+        );
+        for pkg_id in 0..PIECES as u64{
+            assert!(receiver.sent((ZTPResponseCode::Ack, Some(pkg_id))) >= 1, "piece {pkg_id}");
+        }
+        assert_eq!(receiver.sent((ZTPResponseCode::Nack, Some(2))), 1);
+        assert_eq!(stats.pieces_rejected, 1);
+        assert!(sender.sent((ZTPResponseCode::Data, Some(2))) >= 2);
+        // only the piece whose Ack got lost goes again, not the ones after it
+        assert!(sender.sent((ZTPResponseCode::Data, Some(5))) >= 2);
+        assert!(receiver.sent((ZTPResponseCode::Ack, Some(5))) >= 2);
+    }
+
+    #[test]
+    fn go_back_n_rewinds_the_window_to_the_first_unacked_piece(){
+        let (sender, receiver, stats) = transfer(
+            ZTPTransferMode::GoBackN(PIECES as u16),
+            &[Fault::Lose((ZTPResponseCode::Data, Some(2)))],
+            &[]
+        );
+        // every piece after the hole is dropped and answered with the last one in order
+        assert!(stats.pieces_rejected >= (PIECES - 3) as u64);
+        assert!(receiver.sent((ZTPResponseCode::Ack, Some(1))) > 1);
+        // going back resends the lost piece and everything sent after it
+        for pkg_id in 2..PIECES as u64{
+            assert!(sender.sent((ZTPResponseCode::Data, Some(pkg_id))) >= 2, "piece {pkg_id}");
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use std::{future::Future, io::Error, panic, time::Instant};
+use tokio::{task, time};
+
+/*================================================= WAIT ============================================================= */
+
+// `Link::wait` of the tokio links. `readable` only says a receive may succeed,
+// the datagram itself still goes through `Link::recv` so every layer gets to
+// filter it. Sends are never awaited, a full socket drops the datagram like
+// the network would.
+pub(crate) async fn wait_readable(readable: impl Future<Output = Result<(), Error>>, deadline: Instant) -> Result<bool, Error>{
+    match time::timeout_at(deadline.into(), readable).await{
+        Ok(ready) => ready.map(|_| true),
+        Err(_) => Ok(false)
+    }
+}
+
+/*================================================= OFFLOAD ============================================================= */
+
+// `Link::offload` of the tokio links.
+pub(crate) async fn offload<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T{
+    match task::spawn_blocking(work).await{
+        Ok(output) => output,
+        // a panic in `work` goes on in the task that offloaded it
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        // only a runtime shutting down cancels blocking work, and this task goes with it
+        Err(e) => panic!("offloaded work did not run: {e}")
+    }
+}
@@ -1,56 +1,718 @@
-use bincode::{Encode, Decode, config, error::{EncodeError, DecodeError}};
+use std::{fmt, io::{Error, Read}};
+use sha2::{Digest, Sha256};
 use xxhash_rust::xxh3;
 
-use crate::constants::DATA_PIECE_SIZE;
+use crate::constants::{DATA_PIECE_SIZE, MAX_PIECE_SIZE, MAX_WINDOW_SIZE};
 
+/* ============================================================ WIRE FORMAT ============================================================ */
+
+// Every ZTP datagram is a fixed 26 byte header, the piece hash and the body.
+// All integers are big-endian.
+//
+//   offset  size  field
+//        0     2  magic, "ZT"
+//        2     1  version
+//        3     1  type, see ZTPRequestCode::wire, ZTPSessionCode::wire and ZTPResponseCode::wire
+//        4     1  flags: 0x01 pkg_id is set, 0x02 the request carries a public key,
+//                 0x04 the request carries a target name,
+//                 the high nibble says what the body of a response holds
+//        5     1  hash length
+//        6     4  session id
+//       10     8  pkg_id
+//       18     4  body length
+//       22     4  CRC32C of the whole datagram, computed with this field zeroed
+//       26        hash, then body
+//
+// Request body: mode (u8, 0 stop-and-wait, 1 selective repeat, 2 go-back-n),
+// window (u16), checksum (u8, 0 xxh3, 1 crc32c, 2 sha256), start_pkg (u64),
+// the 32 byte X25519 public key if flagged and the resource name in UTF-8,
+// followed by a NUL and the target name if flagged.
+//
+// A session starts with an Open carrying a random client nonce in pkg_id, the
+// server answers with an OpenAck echoing the nonce in pkg_id and the session id
+// in the header. Every later datagram carries that session id, until the client
+// sends a Close. None of the three has a hash or a body.
+//
+// Response bodies: Bytes is the payload as is, Metadata is size (u64), package
+// count (u64), piece size (u32), mode (u8), window (u16), start_pkg (u64),
+// checksum (u8), the SHA-256 digest (32) and the modification time in seconds
+// since the Unix epoch (u64, 0 if unknown), PackageIndex is a u64, PublicKey is 32 bytes,
+// SupportedVersions is the lowest and highest version (u8 each) and Error is a
+// ZTPErrorCode (u8) followed by a UTF-8 message.
+//
+// A request the server will not or cannot serve is answered with an Error, and
+// either end sends one when it gives up on a transfer halfway, so the other
+// stops waiting for it. An Error is never acknowledged. The EndRequest of an
+// upload is only acknowledged once the server checked its digest and stored it,
+// otherwise it is answered with an Integrity or Io Error.
+//
+// A request is sent again until it is answered, and every other answer (Handshake,
+// Metadata, the Ack of a Delete or Rename) until it is acknowledged, backing off
+// from the retransmission timeout. A repeated answer is acknowledged again. An
+// unencrypted Post has no answer of its own, its request goes with every Metadata.
+//
+// A List request names a directory under the resource root (empty for the root
+// itself) and is answered like a Get whose resource is the listing: one UTF-8
+// line per entry, `<kind> <size> <mtime> <name>`, kind being `f` for files and
+// `d` for directories and mtime the seconds since the Unix epoch.
+//
+// A Stat request is answered with the Metadata of the resource alone, no pieces follow.
+//
+// Delete removes the resource and Rename moves it to the target name, which must
+// not exist yet. Both need a read-write key and are answered with an Ack once done.
+//
+// Datagrams in a version outside MIN_ZTP_VERSION..=ZTP_VERSION are refused. A
+// request in such a version gets a VersionMismatch listing the versions the
+// server speaks, that response keeps the same layout in every version. The client
+// sends the request again in the highest version both ends speak and keeps to it
+// for the rest of the session, the server answers in the version of the request.
+// Without a version in common the refusal is reported as a BadRequest.
+
+pub const ZTP_MAGIC: [u8; 2] = *b"ZT";
+pub const ZTP_VERSION: u8 = 1;
+pub const MIN_ZTP_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 26;
+
+// The highest version spoken by both us and a peer speaking `min..=max`.
+pub fn common_version(min: u8, max: u8) -> Option<u8>{
+    let version = max.min(ZTP_VERSION);
+    (version >= min.max(MIN_ZTP_VERSION)).then_some(version)
+}
+
+const FLAG_PKG_ID: u8 = 0x01;
+const FLAG_PUBLIC_KEY: u8 = 0x02;
+const FLAG_TARGET: u8 = 0x04;
+
+const BODY_NONE: u8 = 0;
+const BODY_BYTES: u8 = 1;
+const BODY_METADATA: u8 = 2;
+const BODY_PACKAGE_INDEX: u8 = 3;
+const BODY_PUBLIC_KEY: u8 = 4;
+const BODY_SUPPORTED_VERSIONS: u8 = 5;
+const BODY_ERROR: u8 = 6;
+
+const METADATA_SIZE: usize = 72;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZTPWireError{
+    BufferTooSmall,
+    Truncated,
+    BadMagic,
+    BadChecksum,
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    Malformed(&'static str),
+}
+
+impl fmt::Display for ZTPWireError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            ZTPWireError::BufferTooSmall => write!(f, "buffer is too small for the datagram"),
+            ZTPWireError::Truncated => write!(f, "datagram is truncated"),
+            ZTPWireError::BadMagic => write!(f, "not a ZTP datagram"),
+            ZTPWireError::BadChecksum => write!(f, "header checksum mismatch"),
+            ZTPWireError::UnsupportedVersion(version) => write!(
+                f,
+                "ZTP version {version} is not supported, only {MIN_ZTP_VERSION}..={ZTP_VERSION}"
+            ),
+            ZTPWireError::UnknownType(kind) => write!(f, "unknown message type {kind:#04x}"),
+            ZTPWireError::Malformed(reason) => write!(f, "malformed datagram: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ZTPWireError{}
+
+#[derive(Debug, Clone, Copy)]
+struct ZTPHeader{
+    version: u8,
+    kind: u8,
+    flags: u8,
+    session_id: u32,
+    pkg_id: u64,
+}
+
+impl ZTPHeader{
+    fn body_kind(&self) -> u8{
+        self.flags >> 4
+    }
+
+    fn pkg_id(&self) -> Option<u64>{
+        (self.flags & FLAG_PKG_ID != 0).then_some(self.pkg_id)
+    }
+}
+
+fn encode_frame(header: ZTPHeader, hash: &[u8], body: &[u8]) -> Result<Vec<u8>, ZTPWireError>{
+    let hash_len = u8::try_from(hash.len()).map_err(|_| ZTPWireError::Malformed("hash is too long"))?;
+    let body_len = u32::try_from(body.len()).map_err(|_| ZTPWireError::Malformed("body is too long"))?;
+
+    let mut frame = Vec::with_capacity(HEADER_SIZE + hash.len() + body.len());
+    frame.extend_from_slice(&ZTP_MAGIC);
+    frame.push(header.version);
+    frame.push(header.kind);
+    frame.push(header.flags);
+    frame.push(hash_len);
+    frame.extend_from_slice(&header.session_id.to_be_bytes());
+    frame.extend_from_slice(&header.pkg_id.to_be_bytes());
+    frame.extend_from_slice(&body_len.to_be_bytes());
+    frame.extend_from_slice(&[0u8; 4]);
+    frame.extend_from_slice(hash);
+    frame.extend_from_slice(body);
+
+    let checksum = crc32c::crc32c(&frame);
+    frame[22..26].copy_from_slice(&checksum.to_be_bytes());
+    Ok(frame)
+}
+
+// Checks magic, checksum and version and splits the datagram in header, hash and body.
+// A VersionMismatch is let through in any version so it can always be reported.
+fn decode_frame(buffer: &[u8]) -> Result<ZTPFrame<'_>, ZTPWireError>{
+    if buffer.len() < HEADER_SIZE {return Err(ZTPWireError::Truncated);}
+    if buffer[..2] != ZTP_MAGIC {return Err(ZTPWireError::BadMagic);}
+
+    let mut reader = WireReader::new(&buffer[2..HEADER_SIZE]);
+    let version = reader.u8()?;
+    let kind = reader.u8()?;
+    let flags = reader.u8()?;
+    let hash_len = reader.u8()? as usize;
+    let session_id = reader.u32()?;
+    let pkg_id = reader.u64()?;
+    let body_len = reader.u32()? as usize;
+    let checksum = reader.u32()?;
+    let header = ZTPHeader{version, kind, flags, session_id, pkg_id};
+
+    let size = HEADER_SIZE + hash_len + body_len;
+    if buffer.len() < size {return Err(ZTPWireError::Truncated);}
+
+    let crc = crc32c::crc32c(&buffer[..22]);
+    let crc = crc32c::crc32c_append(crc, &[0u8; 4]);
+    let crc = crc32c::crc32c_append(crc, &buffer[HEADER_SIZE..size]);
+    if crc != checksum {return Err(ZTPWireError::BadChecksum);}
+
+    let version_mismatch = header.kind == ZTPResponseCode::VersionMismatch.wire();
+    if !version_mismatch && !(MIN_ZTP_VERSION..=ZTP_VERSION).contains(&header.version){
+        return Err(ZTPWireError::UnsupportedVersion(header.version));
+    }
+
+    Ok(ZTPFrame{
+        header,
+        hash: &buffer[HEADER_SIZE..HEADER_SIZE + hash_len],
+        body: &buffer[HEADER_SIZE + hash_len..size],
+        size,
+    })
+}
+
+struct ZTPFrame<'a>{
+    header: ZTPHeader,
+    hash: &'a [u8],
+    body: &'a [u8],
+    size: usize,
+}
+
+// The session id of a datagram, None if it is not a ZTP datagram.
+pub fn peek_session_id(datagram: &[u8]) -> Option<u32>{
+    if datagram.len() < HEADER_SIZE || datagram[..2] != ZTP_MAGIC {return None;}
+    Some(u32::from_be_bytes(datagram[6..10].try_into().ok()?))
+}
+
+// The client nonce of an Open, None for any other datagram.
+pub fn peek_open_nonce(datagram: &[u8]) -> Option<u64>{
+    peek_session_id(datagram)?;
+    if datagram[3] != ZTPSessionCode::Open.wire() {return None;}
+    Some(u64::from_be_bytes(datagram[10..18].try_into().ok()?))
+}
+
+// Rewrites the session id of an encoded datagram along with its checksum.
+pub fn set_session_id(datagram: &mut [u8], session_id: u32) -> Result<(), ZTPWireError>{
+    rewrite_header(datagram, 6, &session_id.to_be_bytes())
+}
+
+// Rewrites the version of an encoded datagram along with its checksum.
+pub fn set_version(datagram: &mut [u8], version: u8) -> Result<(), ZTPWireError>{
+    rewrite_header(datagram, 2, &[version])
+}
+
+fn rewrite_header(datagram: &mut [u8], offset: usize, field: &[u8]) -> Result<(), ZTPWireError>{
+    let size = decode_frame(datagram)?.size;
+    datagram[offset..offset + field.len()].copy_from_slice(field);
+    datagram[22..26].fill(0);
+    let checksum = crc32c::crc32c(&datagram[..size]);
+    datagram[22..26].copy_from_slice(&checksum.to_be_bytes());
+    Ok(())
+}
+
+struct WireReader<'a>{
+    bytes: &'a [u8],
+}
+
+impl<'a> WireReader<'a>{
+    fn new(bytes: &'a [u8]) -> WireReader<'a>{
+        WireReader{bytes}
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ZTPWireError>{
+        if self.bytes.len() < len {return Err(ZTPWireError::Truncated);}
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ZTPWireError>{
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, ZTPWireError>{
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ZTPWireError>{
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, ZTPWireError>{
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, ZTPWireError>{
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn rest(&mut self) -> &'a [u8]{
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+fn copy_into(frame: Vec<u8>, buffer: &mut [u8]) -> Result<usize, ZTPWireError>{
+    let slot = buffer.get_mut(..frame.len()).ok_or(ZTPWireError::BufferTooSmall)?;
+    slot.copy_from_slice(&frame);
+    Ok(frame.len())
+}
 
 /* ============================================================ ZTP REQUEST ============================================================ */
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Debug)]
 pub struct ZTPRequest{
+    pub version: u8,
+    pub session_id: u32,
     pub code: ZTPRequestCode,
     pub resource: String,
+    pub mode: ZTPTransferMode,
+    pub start_pkg: u64,
+    pub checksum: ZTPChecksum,
+    pub public_key: Option<[u8; 32]>,
+    pub target: Option<String>,
 }
 
 impl ZTPRequest{
-    pub fn new(code: ZTPRequestCode, resource: String) -> ZTPRequest{
+    pub fn new(code: ZTPRequestCode, resource: String, mode: ZTPTransferMode) -> ZTPRequest{
         ZTPRequest{
+            version: ZTP_VERSION,
+            session_id: 0,
             code,
-            resource
+            resource,
+            mode,
+            start_pkg: 0,
+            checksum: ZTPChecksum::default(),
+            public_key: None,
+            target: None
         }
     }
 
+    pub fn with_checksum(mut self, checksum: ZTPChecksum) -> ZTPRequest{
+        self.checksum = checksum;
+        self
+    }
+
+    // the client's X25519 key, asks the server for an encrypted session
+    pub fn with_public_key(mut self, public_key: [u8; 32]) -> ZTPRequest{
+        self.public_key = Some(public_key);
+        self
+    }
+
+    // the new name of a Rename
+    pub fn with_target(mut self, target: String) -> ZTPRequest{
+        self.target = Some(target);
+        self
+    }
+
+    // asks the server to skip every piece before `start_pkg`, used to resume downloads
+    pub fn resume_from(mut self, start_pkg: u64) -> ZTPRequest{
+        self.start_pkg = start_pkg;
+        self
+    }
+
+    pub fn get_version(&self) -> u8{
+        self.version
+    }
+
+    pub fn get_session_id(&self) -> u32{
+        self.session_id
+    }
+
+    pub fn get_code(&self) -> ZTPRequestCode{
+        self.code
+    }
+
     pub fn get_resource(&self) -> &str{
-        return self.resource.as_str();
+        self.resource.as_str()
     }
 
-    pub fn encode_to_vec(self) -> Vec<u8>{
-       bincode::encode_to_vec(self, config::standard()).unwrap() 
+    pub fn get_mode(&self) -> ZTPTransferMode{
+        self.mode
     }
 
-    pub fn encode_into_slice(self, buffer: &mut[u8]) -> Result<usize, EncodeError>{
-        bincode::encode_into_slice(self, buffer, config::standard())
+    pub fn get_start_pkg(&self) -> u64{
+        self.start_pkg
     }
-    
-    pub fn decode_from_slice(buffer: &[u8]) -> Result<(ZTPRequest, usize), DecodeError>{
-        bincode::decode_from_slice(buffer, config::standard())
+
+    pub fn get_checksum(&self) -> ZTPChecksum{
+        self.checksum
+    }
+
+    pub fn get_public_key(&self) -> Option<[u8; 32]>{
+        self.public_key
+    }
+
+    pub fn get_target(&self) -> Option<&str>{
+        self.target.as_deref()
+    }
+
+    pub fn encode_to_vec(self) -> Result<Vec<u8>, ZTPWireError>{
+        let target_len = self.target.as_ref().map_or(0, |target| target.len() + 1);
+        let mut body = Vec::with_capacity(45 + self.resource.len() + target_len);
+        self.mode.write(&mut body);
+        body.push(self.checksum.wire());
+        body.extend_from_slice(&self.start_pkg.to_be_bytes());
+        if let Some(public_key) = self.public_key{
+            body.extend_from_slice(&public_key);
+        }
+        body.extend_from_slice(self.resource.as_bytes());
+        if let Some(target) = &self.target{
+            body.push(0);
+            body.extend_from_slice(target.as_bytes());
+        }
+
+        let mut flags = 0;
+        if self.public_key.is_some() {flags |= FLAG_PUBLIC_KEY;}
+        if self.target.is_some() {flags |= FLAG_TARGET;}
+        let header = ZTPHeader{
+            version: self.version,
+            kind: self.code.wire(),
+            flags,
+            session_id: self.session_id,
+            pkg_id: 0,
+        };
+        encode_frame(header, &[], &body)
+    }
+
+    pub fn encode_into_slice(self, buffer: &mut[u8]) -> Result<usize, ZTPWireError>{
+        copy_into(self.encode_to_vec()?, buffer)
+    }
+
+    pub fn decode_from_slice(buffer: &[u8]) -> Result<(ZTPRequest, usize), ZTPWireError>{
+        let ZTPFrame{header, body, size, ..} = decode_frame(buffer)?;
+        let code = ZTPRequestCode::from_wire(header.kind)?;
+
+        let mut reader = WireReader::new(body);
+        let mode = ZTPTransferMode::read(&mut reader)?;
+        let checksum = ZTPChecksum::from_wire(reader.u8()?)?;
+        let start_pkg = reader.u64()?;
+        let public_key = match header.flags & FLAG_PUBLIC_KEY{
+            0 => None,
+            _ => Some(reader.array()?)
+        };
+        let names = std::str::from_utf8(reader.rest())
+            .map_err(|_| ZTPWireError::Malformed("resource name is not UTF-8"))?;
+        let (resource, target) = match header.flags & FLAG_TARGET{
+            0 => (names.to_string(), None),
+            _ => {
+                let (resource, target) = names.split_once('\0')
+                    .ok_or(ZTPWireError::Malformed("target name is missing"))?;
+                (resource.to_string(), Some(target.to_string()))
+            }
+        };
+
+        let req = ZTPRequest{
+            version: header.version,
+            session_id: header.session_id,
+            code,
+            resource,
+            mode,
+            start_pkg,
+            checksum,
+            public_key,
+            target
+        };
+        Ok((req, size))
     }
 
 }
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ZTPRequestCode{
     Get,
     Post,
+    List,
+    Stat,
+    Delete,
+    Rename,
 }
 
-/* ============================================================ ZTP REQUEST ============================================================ */
+impl ZTPRequestCode{
+    pub fn wire(&self) -> u8{
+        match self{
+            ZTPRequestCode::Get => 0x01,
+            ZTPRequestCode::Post => 0x02,
+            ZTPRequestCode::List => 0x03,
+            ZTPRequestCode::Stat => 0x04,
+            ZTPRequestCode::Delete => 0x05,
+            ZTPRequestCode::Rename => 0x06,
+        }
+    }
+
+    // Requests that change what the server stores.
+    pub fn writes(&self) -> bool{
+        matches!(self, ZTPRequestCode::Post | ZTPRequestCode::Delete | ZTPRequestCode::Rename)
+    }
+
+    fn from_wire(kind: u8) -> Result<ZTPRequestCode, ZTPWireError>{
+        match kind{
+            0x01 => Ok(ZTPRequestCode::Get),
+            0x02 => Ok(ZTPRequestCode::Post),
+            0x03 => Ok(ZTPRequestCode::List),
+            0x04 => Ok(ZTPRequestCode::Stat),
+            0x05 => Ok(ZTPRequestCode::Delete),
+            0x06 => Ok(ZTPRequestCode::Rename),
+            kind => Err(ZTPWireError::UnknownType(kind))
+        }
+    }
+}
+
+/* ============================================================ ZTP TRANSFER MODE ============================================================ */
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ZTPTransferMode{
+    StopAndWait,
+    SelectiveRepeat(u16),
+    GoBackN(u16),
+}
+
+impl ZTPTransferMode{
+    // The requested window is only a proposal, the side that sends the metadata
+    // clamps it and the clamped value is what both ends use.
+    pub fn negotiate(self) -> ZTPTransferMode{
+        match self{
+            ZTPTransferMode::SelectiveRepeat(window) => {
+                ZTPTransferMode::SelectiveRepeat(window.clamp(1, MAX_WINDOW_SIZE))
+            },
+            ZTPTransferMode::GoBackN(window) => {
+                ZTPTransferMode::GoBackN(window.clamp(1, MAX_WINDOW_SIZE))
+            },
+            mode => mode
+        }
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>){
+        let (kind, window) = match self{
+            ZTPTransferMode::StopAndWait => (0u8, 1u16),
+            ZTPTransferMode::SelectiveRepeat(window) => (1, *window),
+            ZTPTransferMode::GoBackN(window) => (2, *window),
+        };
+        bytes.push(kind);
+        bytes.extend_from_slice(&window.to_be_bytes());
+    }
+
+    fn read(reader: &mut WireReader) -> Result<ZTPTransferMode, ZTPWireError>{
+        let kind = reader.u8()?;
+        let window = reader.u16()?;
+        match kind{
+            0 => Ok(ZTPTransferMode::StopAndWait),
+            1 => Ok(ZTPTransferMode::SelectiveRepeat(window)),
+            2 => Ok(ZTPTransferMode::GoBackN(window)),
+            _ => Err(ZTPWireError::Malformed("unknown transfer mode"))
+        }
+    }
+}
+
+/* ============================================================ ZTP CHECKSUM ============================================================ */
+
+// Per-piece checksum, picked by the client in the request and echoed in the
+// metadata: xxh3 is the fastest, CRC32C is hardware accelerated and SHA-256 is
+// the only one that resists deliberate collisions.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum ZTPChecksum{
+    #[default]
+    Xxh3,
+    Crc32c,
+    Sha256,
+}
+
+impl ZTPChecksum{
+    pub fn digest(&self, bytes: &[u8]) -> Vec<u8>{
+        match self{
+            ZTPChecksum::Xxh3 => xxh3::xxh3_64(bytes).to_be_bytes().to_vec(),
+            ZTPChecksum::Crc32c => crc32c::crc32c(bytes).to_be_bytes().to_vec(),
+            ZTPChecksum::Sha256 => Sha256::digest(bytes).to_vec(),
+        }
+    }
+
+    fn wire(&self) -> u8{
+        match self{
+            ZTPChecksum::Xxh3 => 0,
+            ZTPChecksum::Crc32c => 1,
+            ZTPChecksum::Sha256 => 2,
+        }
+    }
+
+    fn from_wire(kind: u8) -> Result<ZTPChecksum, ZTPWireError>{
+        match kind{
+            0 => Ok(ZTPChecksum::Xxh3),
+            1 => Ok(ZTPChecksum::Crc32c),
+            2 => Ok(ZTPChecksum::Sha256),
+            _ => Err(ZTPWireError::Malformed("unknown checksum"))
+        }
+    }
+}
+
+/* ============================================================ ZTP LISTING ============================================================ */
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ZTPListEntry{
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: u64,
+}
+
+impl ZTPListEntry{
+    pub fn encode_listing(entries: &[ZTPListEntry]) -> Vec<u8>{
+        let mut listing = String::new();
+        for entry in entries{
+            let kind = if entry.is_dir {'d'} else {'f'};
+            listing.push_str(&format!("{kind} {} {} {}\n", entry.size, entry.modified, entry.name));
+        }
+        listing.into_bytes()
+    }
+
+    pub fn decode_listing(bytes: &[u8]) -> Result<Vec<ZTPListEntry>, ZTPWireError>{
+        let listing = std::str::from_utf8(bytes).map_err(|_| ZTPWireError::Malformed("listing is not UTF-8"))?;
+        listing.lines()
+            .map(|line|{
+                let mut fields = line.splitn(4, ' ');
+                let malformed = ZTPWireError::Malformed("bad listing line");
+                let is_dir = match fields.next(){
+                    Some("d") => true,
+                    Some("f") => false,
+                    _ => return Err(malformed)
+                };
+                let size = fields.next().and_then(|size| size.parse().ok()).ok_or(malformed)?;
+                let modified = fields.next().and_then(|modified| modified.parse().ok()).ok_or(malformed)?;
+                let name = fields.next().ok_or(malformed)?.to_string();
+                Ok(ZTPListEntry{name, is_dir, size, modified})
+            })
+            .collect()
+    }
+}
+
+/* ============================================================ ZTP SESSION CONTROL ============================================================ */
+
+// Opens and closes a session, see the wire format. The client sends Open and
+// Close, the server answers an Open with an OpenAck.
+#[derive(Debug)]
+pub struct ZTPSessionControl{
+    version: u8,
+    session_id: u32,
+    code: ZTPSessionCode,
+    nonce: Option<u64>,
+}
+
+impl ZTPSessionControl{
+    pub fn open(nonce: u64) -> ZTPSessionControl{
+        ZTPSessionControl{version: ZTP_VERSION, session_id: 0, code: ZTPSessionCode::Open, nonce: Some(nonce)}
+    }
 
-#[derive(Encode, Decode, Debug)]
+    pub fn open_ack(nonce: u64) -> ZTPSessionControl{
+        ZTPSessionControl{version: ZTP_VERSION, session_id: 0, code: ZTPSessionCode::OpenAck, nonce: Some(nonce)}
+    }
+
+    pub fn close() -> ZTPSessionControl{
+        ZTPSessionControl{version: ZTP_VERSION, session_id: 0, code: ZTPSessionCode::Close, nonce: None}
+    }
+
+    pub fn get_version(&self) -> u8{
+        self.version
+    }
+
+    pub fn get_session_id(&self) -> u32{
+        self.session_id
+    }
+
+    pub fn get_code(&self) -> ZTPSessionCode{
+        self.code
+    }
+
+    pub fn get_nonce(&self) -> Option<u64>{
+        self.nonce
+    }
+
+    pub fn encode_to_vec(self) -> Result<Vec<u8>, ZTPWireError>{
+        let header = ZTPHeader{
+            version: self.version,
+            kind: self.code.wire(),
+            flags: if self.nonce.is_some() {FLAG_PKG_ID} else {0},
+            session_id: self.session_id,
+            pkg_id: self.nonce.unwrap_or(0),
+        };
+        encode_frame(header, &[], &[])
+    }
+
+    pub fn decode_from_slice(buffer: &[u8]) -> Result<(ZTPSessionControl, usize), ZTPWireError>{
+        let ZTPFrame{header, size, ..} = decode_frame(buffer)?;
+        let control = ZTPSessionControl{
+            version: header.version,
+            session_id: header.session_id,
+            code: ZTPSessionCode::from_wire(header.kind)?,
+            nonce: header.pkg_id(),
+        };
+        Ok((control, size))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ZTPSessionCode{
+    Open,
+    OpenAck,
+    Close,
+}
+
+impl ZTPSessionCode{
+    pub fn wire(&self) -> u8{
+        match self{
+            ZTPSessionCode::Open => 0x18,
+            ZTPSessionCode::OpenAck => 0x19,
+            ZTPSessionCode::Close => 0x1a,
+        }
+    }
+
+    fn from_wire(kind: u8) -> Result<ZTPSessionCode, ZTPWireError>{
+        match kind{
+            0x18 => Ok(ZTPSessionCode::Open),
+            0x19 => Ok(ZTPSessionCode::OpenAck),
+            0x1a => Ok(ZTPSessionCode::Close),
+            kind => Err(ZTPWireError::UnknownType(kind))
+        }
+    }
+}
+
+/* ============================================================ ZTP RESPONSE ============================================================ */
+
+#[derive(Debug)]
 pub struct ZTPResponse{
+  version: u8,
+  session_id: u32,
   code: ZTPResponseCode,
   data: Option<ZTPResponseData>,
-  hash: Option<u64>,
+  hash: Option<Vec<u8>>,
   pkg_id: Option<u64>,
 }
 
@@ -58,9 +720,11 @@ impl ZTPResponse{
     pub fn new (code: ZTPResponseCode, data: Option<ZTPResponseData>, id: Option<u64>) -> ZTPResponse{
         let mut hash = None;
         if let Some(ZTPResponseData::Bytes(bytes_ref)) = data.as_ref(){
-           hash = Some(xxh3::xxh3_64(&bytes_ref)); 
+           hash = Some(ZTPChecksum::default().digest(bytes_ref));
         }
         ZTPResponse{
+            version: ZTP_VERSION,
+            session_id: 0,
             code,
             data,
             hash,
@@ -68,58 +732,176 @@ impl ZTPResponse{
         }
     }
 
+    // a Data piece hashed with the checksum negotiated for the transfer
+    pub fn new_piece(bytes: Vec<u8>, pkg_id: u64, checksum: ZTPChecksum) -> ZTPResponse{
+        ZTPResponse{
+            version: ZTP_VERSION,
+            session_id: 0,
+            code: ZTPResponseCode::Data,
+            hash: Some(checksum.digest(&bytes)),
+            data: Some(ZTPResponseData::Bytes(bytes)),
+            pkg_id: Some(pkg_id)
+        }
+    }
+
+    pub fn error(error: ZTPErrorReport) -> ZTPResponse{
+        ZTPResponse::new(ZTPResponseCode::Error, Some(ZTPResponseData::Error(error)), None)
+    }
+
+    // refuses a peer speaking an unsupported version, listing the ones we speak in the version it used
+    pub fn version_refusal(version: u8) -> ZTPResponse{
+        let versions = ZTPResponseData::SupportedVersions(MIN_ZTP_VERSION, ZTP_VERSION);
+        ZTPResponse::new(ZTPResponseCode::VersionMismatch, Some(versions), None)
+            .with_version(version)
+    }
+
+    pub fn with_version(mut self, version: u8) -> ZTPResponse{
+        self.version = version;
+        self
+    }
+
+    pub fn get_version(&self) -> u8{
+        self.version
+    }
+
+    pub fn get_session_id(&self) -> u32{
+        self.session_id
+    }
+
     pub fn get_code(&self) -> ZTPResponseCode{
         self.code
     }
 
     pub fn is_ack(&self) -> bool{
-        match self.code{
-            ZTPResponseCode::Ack => true,
-            _ => false
-        }
+        matches!(self.code, ZTPResponseCode::Ack)
     }
-    
+
     pub fn get_bytes(&self) -> Option<&[u8]>{
-        if self.data.is_none() {return None};
-        
-        if let ZTPResponseData::Bytes(vec_ref) = self.data.as_ref().unwrap(){
+        if let ZTPResponseData::Bytes(vec_ref) = self.data.as_ref()?{
             return Some(vec_ref);
         }
         None
     }
 
     pub fn has_data(&self) -> bool{
-        return self.data.is_some();
+        self.data.is_some()
     }
 
     pub fn get_data(&self) -> Option<&ZTPResponseData>{
-        return self.data.as_ref();
+        self.data.as_ref()
     }
 
-    pub fn get_hash(&self) -> Option<u64>{
-        self.hash
+    pub fn get_hash(&self) -> Option<&[u8]>{
+        self.hash.as_deref()
     }
 
     pub fn get_pkg_id(&self) -> Option<u64>{
         self.pkg_id
-    } 
-    
-    pub fn encode_into_slice(self, buffer: &mut[u8]) -> Result<usize, EncodeError>{
-        bincode::encode_into_slice(self, buffer, config::standard())
     }
-    
-    pub fn encode_to_vec(self) -> Result<Vec<u8>, EncodeError>{
-        bincode::encode_to_vec(self, config::standard())
+
+    // Removes the payload of a Bytes response along with its hash.
+    pub fn take_bytes(&mut self) -> Option<(Vec<u8>, Option<Vec<u8>>)>{
+        match self.data.take(){
+            Some(ZTPResponseData::Bytes(bytes)) => Some((bytes, self.hash.take())),
+            data => {
+                self.data = data;
+                None
+            }
+        }
     }
-    
-    pub fn decode_from_slice(buffer: &[u8]) -> Result<(ZTPResponse, usize), DecodeError>{
-        bincode::decode_from_slice(buffer, config::standard())
+
+    pub fn set_bytes(&mut self, bytes: Vec<u8>, hash: Option<Vec<u8>>){
+        self.data = Some(ZTPResponseData::Bytes(bytes));
+        self.hash = hash;
     }
 
-    pub fn hash_and_cmp(&self) -> Option<bool>{
+    // What went wrong on the other end, a VersionMismatch counts as a BadRequest.
+    pub fn get_error(&self) -> Option<ZTPErrorReport>{
+        match self.data.as_ref()?{
+            ZTPResponseData::Error(error) => Some(error.clone()),
+            ZTPResponseData::SupportedVersions(min, max) => Some(ZTPErrorReport::new(
+                ZTPErrorCode::BadRequest,
+                format!("the server speaks ZTP {min}..={max}, we speak {MIN_ZTP_VERSION}..={ZTP_VERSION}")
+            )),
+            _ => None
+        }
+    }
+
+    pub fn get_package_index(&self) -> Option<usize>{
+        if let Some(ZTPResponseData::PackageIndex(index)) = self.data.as_ref(){
+            return Some(*index);
+        }
+        None
+    }
+
+    pub fn encode_into_slice(self, buffer: &mut[u8]) -> Result<usize, ZTPWireError>{
+        copy_into(self.encode_to_vec()?, buffer)
+    }
+
+    pub fn encode_to_vec(self) -> Result<Vec<u8>, ZTPWireError>{
+        let (body_kind, body) = match &self.data{
+            None => (BODY_NONE, Vec::new()),
+            Some(ZTPResponseData::Bytes(bytes)) => (BODY_BYTES, bytes.clone()),
+            Some(ZTPResponseData::Metadata(metadata)) => (BODY_METADATA, metadata.to_wire().to_vec()),
+            Some(ZTPResponseData::PackageIndex(index)) => (BODY_PACKAGE_INDEX, (*index as u64).to_be_bytes().to_vec()),
+            Some(ZTPResponseData::PublicKey(public_key)) => (BODY_PUBLIC_KEY, public_key.to_vec()),
+            Some(ZTPResponseData::SupportedVersions(min, max)) => (BODY_SUPPORTED_VERSIONS, vec![*min, *max]),
+            Some(ZTPResponseData::Error(error)) => {
+                let mut body = vec![error.code.wire()];
+                body.extend_from_slice(error.message.as_bytes());
+                (BODY_ERROR, body)
+            },
+        };
+        let mut flags = body_kind << 4;
+        if self.pkg_id.is_some(){
+            flags |= FLAG_PKG_ID;
+        }
+
+        let header = ZTPHeader{
+            version: self.version,
+            kind: self.code.wire(),
+            flags,
+            session_id: self.session_id,
+            pkg_id: self.pkg_id.unwrap_or(0),
+        };
+        encode_frame(header, self.hash.as_deref().unwrap_or_default(), &body)
+    }
+
+    pub fn decode_from_slice(buffer: &[u8]) -> Result<(ZTPResponse, usize), ZTPWireError>{
+        let ZTPFrame{header, hash, body, size} = decode_frame(buffer)?;
+        let code = ZTPResponseCode::from_wire(header.kind)?;
+
+        let mut reader = WireReader::new(body);
+        let data = match header.body_kind(){
+            BODY_NONE => None,
+            BODY_BYTES => Some(ZTPResponseData::Bytes(reader.rest().to_vec())),
+            BODY_METADATA => Some(ZTPResponseData::Metadata(ZTPMetadata::from_wire(&mut reader)?)),
+            BODY_PACKAGE_INDEX => Some(ZTPResponseData::PackageIndex(reader.u64()? as usize)),
+            BODY_PUBLIC_KEY => Some(ZTPResponseData::PublicKey(reader.array()?)),
+            BODY_SUPPORTED_VERSIONS => Some(ZTPResponseData::SupportedVersions(reader.u8()?, reader.u8()?)),
+            BODY_ERROR => {
+                let code = ZTPErrorCode::from_wire(reader.u8()?)?;
+                let message = String::from_utf8_lossy(reader.rest()).into_owned();
+                Some(ZTPResponseData::Error(ZTPErrorReport::new(code, message)))
+            },
+            _ => return Err(ZTPWireError::Malformed("unknown body kind"))
+        };
+
+        let res = ZTPResponse{
+            version: header.version,
+            session_id: header.session_id,
+            code,
+            data,
+            hash: (!hash.is_empty()).then(|| hash.to_vec()),
+            pkg_id: header.pkg_id(),
+        };
+        Ok((res, size))
+    }
+
+    pub fn hash_and_cmp(&self, checksum: ZTPChecksum) -> Option<bool>{
         if let Some(ZTPResponseData::Bytes(vec_ref)) = self.data.as_ref(){
-            let hash_result = xxh3::xxh3_64(vec_ref);
-            return Some(hash_result == self.hash.unwrap())
+            let hash_result = checksum.digest(vec_ref);
+            return Some(Some(hash_result.as_slice()) == self.get_hash())
         }
         None
     }
@@ -127,54 +909,189 @@ impl ZTPResponse{
 }
 
 
-#[derive(Encode, Decode, Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ZTPResponseCode{
     Data,
     Metadata,
     EndRequest,
     Ack,
     Nack,
-    NotFound,
+    Error,
+    Handshake,
+    VersionMismatch,
+}
+
+impl ZTPResponseCode{
+    pub fn wire(&self) -> u8{
+        match self{
+            ZTPResponseCode::Data => 0x10,
+            ZTPResponseCode::Metadata => 0x11,
+            ZTPResponseCode::EndRequest => 0x12,
+            ZTPResponseCode::Ack => 0x13,
+            ZTPResponseCode::Nack => 0x14,
+            ZTPResponseCode::Error => 0x15,
+            ZTPResponseCode::Handshake => 0x16,
+            ZTPResponseCode::VersionMismatch => 0x17,
+        }
+    }
+
+    fn from_wire(kind: u8) -> Result<ZTPResponseCode, ZTPWireError>{
+        match kind{
+            0x10 => Ok(ZTPResponseCode::Data),
+            0x11 => Ok(ZTPResponseCode::Metadata),
+            0x12 => Ok(ZTPResponseCode::EndRequest),
+            0x13 => Ok(ZTPResponseCode::Ack),
+            0x14 => Ok(ZTPResponseCode::Nack),
+            0x15 => Ok(ZTPResponseCode::Error),
+            0x16 => Ok(ZTPResponseCode::Handshake),
+            0x17 => Ok(ZTPResponseCode::VersionMismatch),
+            kind => Err(ZTPWireError::UnknownType(kind))
+        }
+    }
 }
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Debug)]
 pub enum ZTPResponseData{
     Bytes(Vec<u8>),
     Metadata(ZTPMetadata),
-    PackageIndex(usize)
+    PackageIndex(usize),
+    PublicKey([u8; 32]),
+    SupportedVersions(u8, u8),
+    Error(ZTPErrorReport),
+}
+
+/* ============================================================ ZTP ERROR ============================================================ */
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ZTPErrorCode{
+    NotFound,
+    Forbidden,
+    PermissionDenied,
+    Conflict,
+    BadRequest,
+    ServerBusy,
+    Io,
+    Aborted,
+    Integrity,
+}
+
+impl ZTPErrorCode{
+    pub fn wire(&self) -> u8{
+        match self{
+            ZTPErrorCode::NotFound => 0x01,
+            ZTPErrorCode::Forbidden => 0x02,
+            ZTPErrorCode::PermissionDenied => 0x03,
+            ZTPErrorCode::Conflict => 0x04,
+            ZTPErrorCode::BadRequest => 0x05,
+            // 0x06 is left unused, a version mismatch is a response of its own
+            ZTPErrorCode::ServerBusy => 0x07,
+            ZTPErrorCode::Io => 0x08,
+            ZTPErrorCode::Aborted => 0x09,
+            ZTPErrorCode::Integrity => 0x0a,
+        }
+    }
+
+    fn from_wire(code: u8) -> Result<ZTPErrorCode, ZTPWireError>{
+        match code{
+            0x01 => Ok(ZTPErrorCode::NotFound),
+            0x02 => Ok(ZTPErrorCode::Forbidden),
+            0x03 => Ok(ZTPErrorCode::PermissionDenied),
+            0x04 => Ok(ZTPErrorCode::Conflict),
+            0x05 => Ok(ZTPErrorCode::BadRequest),
+            0x07 => Ok(ZTPErrorCode::ServerBusy),
+            0x08 => Ok(ZTPErrorCode::Io),
+            0x09 => Ok(ZTPErrorCode::Aborted),
+            0x0a => Ok(ZTPErrorCode::Integrity),
+            _ => Err(ZTPWireError::Malformed("unknown error code"))
+        }
+    }
 }
 
+// The body of an Error: the code says what happened, the message is for people.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ZTPErrorReport{
+    pub code: ZTPErrorCode,
+    pub message: String,
+}
 
-#[derive(Encode, Decode, Clone, Copy, Debug)]
+impl ZTPErrorReport{
+    pub fn new(code: ZTPErrorCode, message: impl Into<String>) -> ZTPErrorReport{
+        ZTPErrorReport{code, message: message.into()}
+    }
+}
+
+impl fmt::Display for ZTPErrorReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ZTPErrorReport{}
+
+
+#[derive(Clone, Copy, Debug)]
 pub struct ZTPMetadata{
     size: usize,
     package_count: usize,
+    piece_size: usize,
+    mode: ZTPTransferMode,
+    start_pkg: u64,
+    checksum: ZTPChecksum,
+    digest: [u8; 32],
+    modified: u64,
 }
 
 impl ZTPMetadata{
-    pub fn new(size: usize, package_count: usize) -> ZTPMetadata{
+    pub fn new(size: usize, package_count: usize, mode: ZTPTransferMode, digest: [u8; 32]) -> ZTPMetadata{
         ZTPMetadata{
             size,
-            package_count
+            package_count,
+            piece_size: DATA_PIECE_SIZE,
+            mode,
+            start_pkg: 0,
+            checksum: ZTPChecksum::default(),
+            digest,
+            modified: 0,
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> ZTPMetadata{
-        let package_count;
-        if bytes.len() <= DATA_PIECE_SIZE{
-            package_count = 1;
-        }
-        else if bytes.len() % DATA_PIECE_SIZE == 0{
-            package_count = bytes.len()/DATA_PIECE_SIZE;
-        }
-        else{
-            package_count = bytes.len()/DATA_PIECE_SIZE + 1;
-        }
-        
-        ZTPMetadata{
-            size: bytes.len(),
-            package_count
-        }
+    // Reads the whole resource once to get its size and digest.
+    pub fn from_reader(reader: &mut impl Read, mode: ZTPTransferMode) -> Result<ZTPMetadata, Error>{
+        let (size, digest) = digest_reader(reader)?;
+        Ok(ZTPMetadata{
+            size,
+            package_count: package_count(size, DATA_PIECE_SIZE),
+            piece_size: DATA_PIECE_SIZE,
+            mode: mode.negotiate(),
+            start_pkg: 0,
+            checksum: ZTPChecksum::default(),
+            digest,
+            modified: 0,
+        })
+    }
+
+    pub fn with_checksum(mut self, checksum: ZTPChecksum) -> ZTPMetadata{
+        self.checksum = checksum;
+        self
+    }
+
+    // seconds since the Unix epoch
+    pub fn with_modified(mut self, modified: u64) -> ZTPMetadata{
+        self.modified = modified;
+        self
+    }
+
+    // splits the resource in pieces of `piece_size` bytes, set it before `resume_from`
+    pub fn with_piece_size(mut self, piece_size: usize) -> ZTPMetadata{
+        self.piece_size = piece_size;
+        self.package_count = package_count(self.size, piece_size);
+        self
+    }
+
+    // the first piece that will be transferred, everything before it is already on the receiver
+    pub fn resume_from(mut self, start_pkg: u64) -> ZTPMetadata{
+        self.start_pkg = start_pkg.min(self.package_count as u64);
+        self
     }
 
     pub fn size(&self) -> usize{
@@ -184,5 +1101,382 @@ impl ZTPMetadata{
     pub fn count(&self) -> usize{
         self.package_count
     }
+
+    pub fn piece_size(&self) -> usize{
+        self.piece_size
+    }
+
+    pub fn mode(&self) -> ZTPTransferMode{
+        self.mode
+    }
+
+    pub fn start_pkg(&self) -> u64{
+        self.start_pkg
+    }
+
+    pub fn checksum(&self) -> ZTPChecksum{
+        self.checksum
+    }
+
+    // SHA-256 of the whole resource, checked once it is reassembled
+    pub fn digest(&self) -> &[u8; 32]{
+        &self.digest
+    }
+
+    pub fn modified(&self) -> u64{
+        self.modified
+    }
+
+    fn to_wire(self) -> [u8; METADATA_SIZE]{
+        let mut bytes = Vec::with_capacity(METADATA_SIZE);
+        bytes.extend_from_slice(&(self.size as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.package_count as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.piece_size as u32).to_be_bytes());
+        self.mode.write(&mut bytes);
+        bytes.extend_from_slice(&self.start_pkg.to_be_bytes());
+        bytes.push(self.checksum.wire());
+        bytes.extend_from_slice(&self.digest);
+        bytes.extend_from_slice(&self.modified.to_be_bytes());
+        bytes.try_into().unwrap()
+    }
+
+    // Receivers size their state after the metadata, so counts that do not add up are refused.
+    fn from_wire(reader: &mut WireReader) -> Result<ZTPMetadata, ZTPWireError>{
+        let metadata = ZTPMetadata{
+            size: reader.u64()? as usize,
+            package_count: reader.u64()? as usize,
+            piece_size: reader.u32()? as usize,
+            mode: ZTPTransferMode::read(reader)?,
+            start_pkg: reader.u64()?,
+            checksum: ZTPChecksum::from_wire(reader.u8()?)?,
+            digest: reader.array()?,
+            modified: reader.u64()?,
+        };
+        if !(1..=MAX_PIECE_SIZE).contains(&metadata.piece_size){
+            return Err(ZTPWireError::Malformed("metadata with a piece size out of range"));
+        }
+        if metadata.package_count != package_count(metadata.size, metadata.piece_size){
+            return Err(ZTPWireError::Malformed("metadata whose piece count does not match its size"));
+        }
+        if metadata.start_pkg > metadata.package_count as u64{
+            return Err(ZTPWireError::Malformed("metadata resuming past its last piece"));
+        }
+        Ok(metadata)
+    }
 }
 
+fn package_count(size: usize, piece_size: usize) -> usize{
+    if size <= piece_size{
+        1
+    }
+    else if size.is_multiple_of(piece_size){
+        size/piece_size
+    }
+    else{
+        size/piece_size + 1
+    }
+}
+
+// Size and SHA-256 of everything `reader` yields, read in chunks.
+pub fn digest_reader(reader: &mut impl Read) -> Result<(usize, [u8; 32]), Error>{
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 8 * DATA_PIECE_SIZE];
+    let mut size = 0;
+    loop{
+        let bytes = reader.read(&mut buffer)?;
+        if bytes == 0 {break;}
+        hasher.update(&buffer[..bytes]);
+        size += bytes;
+    }
+    Ok((size, hasher.finalize().into()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String{
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>>{
+    if !hex.len().is_multiple_of(2) {return None;}
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const REQUEST_CODES: [ZTPRequestCode; 6] = [
+        ZTPRequestCode::Get, ZTPRequestCode::Post, ZTPRequestCode::List,
+        ZTPRequestCode::Stat, ZTPRequestCode::Delete, ZTPRequestCode::Rename,
+    ];
+
+    const ERROR_CODES: [ZTPErrorCode; 9] = [
+        ZTPErrorCode::NotFound, ZTPErrorCode::Forbidden, ZTPErrorCode::PermissionDenied,
+        ZTPErrorCode::Conflict, ZTPErrorCode::BadRequest,
+        ZTPErrorCode::ServerBusy, ZTPErrorCode::Io, ZTPErrorCode::Aborted, ZTPErrorCode::Integrity,
+    ];
+
+    fn request() -> ZTPRequest{
+        ZTPRequest::new(ZTPRequestCode::Get, "dir/teste.jpg".to_string(), ZTPTransferMode::StopAndWait)
+    }
+
+    fn round_trip(res: ZTPResponse) -> ZTPResponse{
+        let datagram = res.encode_to_vec().unwrap();
+        let (decoded, size) = ZTPResponse::decode_from_slice(&datagram).unwrap();
+        assert_eq!(size, datagram.len());
+        decoded
+    }
+
+    // Re-encodes a datagram with the CRC fixed, for corrupting fields past the checksum.
+    fn reseal(datagram: &mut [u8]){
+        datagram[22..26].fill(0);
+        let checksum = crc32c::crc32c(datagram);
+        datagram[22..26].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    #[test]
+    fn every_request_code_round_trips(){
+        let modes = [ZTPTransferMode::StopAndWait, ZTPTransferMode::SelectiveRepeat(16), ZTPTransferMode::GoBackN(4)];
+        let checksums = [ZTPChecksum::Xxh3, ZTPChecksum::Crc32c, ZTPChecksum::Sha256];
+        for (i, code) in REQUEST_CODES.into_iter().enumerate(){
+            let mut req = ZTPRequest::new(code, format!("resource {i}"), modes[i % 3])
+                .with_checksum(checksums[i % 3])
+                .resume_from(i as u64 * 1000);
+            if i % 2 == 0{
+                req = req.with_public_key([i as u8; 32]);
+            }
+            if code == ZTPRequestCode::Rename{
+                req = req.with_target("renamed".to_string());
+            }
+            let datagram = req.encode_to_vec().unwrap();
+
+            let (decoded, size) = ZTPRequest::decode_from_slice(&datagram).unwrap();
+            assert_eq!(size, datagram.len());
+            assert_eq!(decoded.get_version(), ZTP_VERSION);
+            assert_eq!(decoded.get_code(), code);
+            assert_eq!(decoded.get_resource(), format!("resource {i}"));
+            assert_eq!(decoded.get_mode(), modes[i % 3]);
+            assert_eq!(decoded.get_checksum(), checksums[i % 3]);
+            assert_eq!(decoded.get_start_pkg(), i as u64 * 1000);
+            assert_eq!(decoded.get_public_key(), (i % 2 == 0).then_some([i as u8; 32]));
+            assert_eq!(decoded.get_target(), (code == ZTPRequestCode::Rename).then_some("renamed"));
+        }
+    }
+
+    #[test]
+    fn data_round_trips_with_every_checksum(){
+        for checksum in [ZTPChecksum::Xxh3, ZTPChecksum::Crc32c, ZTPChecksum::Sha256]{
+            let decoded = round_trip(ZTPResponse::new_piece(b"some piece".to_vec(), 7, checksum));
+            assert_eq!(decoded.get_code(), ZTPResponseCode::Data);
+            assert_eq!(decoded.get_bytes(), Some(&b"some piece"[..]));
+            assert_eq!(decoded.get_pkg_id(), Some(7));
+            assert_eq!(decoded.hash_and_cmp(checksum), Some(true));
+        }
+    }
+
+    #[test]
+    fn metadata_round_trips(){
+        let metadata = ZTPMetadata::new(5000, 5, ZTPTransferMode::GoBackN(8), [3u8; 32])
+            .with_checksum(ZTPChecksum::Sha256)
+            .with_modified(1_700_000_000)
+            .with_piece_size(1200)
+            .resume_from(2);
+        let decoded = round_trip(ZTPResponse::new(ZTPResponseCode::Metadata, Some(ZTPResponseData::Metadata(metadata)), None));
+        assert_eq!(decoded.get_code(), ZTPResponseCode::Metadata);
+        let Some(ZTPResponseData::Metadata(decoded)) = decoded.get_data() else{
+            panic!("no metadata in {decoded:?}");
+        };
+        assert_eq!(decoded.size(), 5000);
+        assert_eq!(decoded.count(), metadata.count());
+        assert_eq!(decoded.piece_size(), 1200);
+        assert_eq!(decoded.mode(), ZTPTransferMode::GoBackN(8));
+        assert_eq!(decoded.start_pkg(), 2);
+        assert_eq!(decoded.checksum(), ZTPChecksum::Sha256);
+        assert_eq!(decoded.digest(), &[3u8; 32]);
+        assert_eq!(decoded.modified(), 1_700_000_000);
+    }
+
+    #[test]
+    fn metadata_that_does_not_add_up_is_refused(){
+        let valid = ZTPMetadata::new(5000, 5, ZTPTransferMode::StopAndWait, [3u8; 32]);
+        let cases = [
+            ZTPMetadata{piece_size: 0, ..valid},
+            ZTPMetadata{piece_size: MAX_PIECE_SIZE + 1, package_count: 2, ..valid},
+            ZTPMetadata{package_count: 4, ..valid},
+            ZTPMetadata{package_count: usize::MAX, ..valid},
+            ZTPMetadata{size: usize::MAX, ..valid},
+            ZTPMetadata{start_pkg: 6, ..valid},
+        ];
+        for metadata in cases{
+            let datagram = ZTPResponse::new(ZTPResponseCode::Metadata, Some(ZTPResponseData::Metadata(metadata)), None)
+                .encode_to_vec()
+                .unwrap();
+            assert!(
+                matches!(ZTPResponse::decode_from_slice(&datagram), Err(ZTPWireError::Malformed(_))),
+                "{metadata:?}"
+            );
+        }
+        // an empty resource is a single empty piece
+        round_trip(ZTPResponse::new(
+            ZTPResponseCode::Metadata,
+            Some(ZTPResponseData::Metadata(ZTPMetadata::new(0, 1, ZTPTransferMode::StopAndWait, [0; 32]))),
+            None
+        ));
+    }
+
+    #[test]
+    fn control_responses_round_trip(){
+        let decoded = round_trip(ZTPResponse::new(ZTPResponseCode::EndRequest, None, None));
+        assert_eq!(decoded.get_code(), ZTPResponseCode::EndRequest);
+        assert!(!decoded.has_data());
+        assert_eq!(decoded.get_pkg_id(), None);
+
+        for code in [ZTPResponseCode::Ack, ZTPResponseCode::Nack]{
+            let decoded = round_trip(ZTPResponse::new(code, Some(ZTPResponseData::PackageIndex(41)), Some(41)));
+            assert_eq!(decoded.get_code(), code);
+            assert_eq!(decoded.get_package_index(), Some(41));
+            assert_eq!(decoded.get_pkg_id(), Some(41));
+        }
+
+        let decoded = round_trip(ZTPResponse::new(ZTPResponseCode::Handshake, Some(ZTPResponseData::PublicKey([9u8; 32])), None));
+        assert_eq!(decoded.get_code(), ZTPResponseCode::Handshake);
+        assert!(matches!(decoded.get_data(), Some(ZTPResponseData::PublicKey(key)) if key == &[9u8; 32]));
+    }
+
+    #[test]
+    fn every_error_code_round_trips(){
+        for code in ERROR_CODES{
+            let report = ZTPErrorReport::new(code, format!("{code:?} happened"));
+            let decoded = round_trip(ZTPResponse::error(report.clone()));
+            assert_eq!(decoded.get_code(), ZTPResponseCode::Error);
+            assert_eq!(decoded.get_error(), Some(report));
+        }
+    }
+
+    #[test]
+    fn every_session_code_round_trips(){
+        for (control, code, nonce) in [
+            (ZTPSessionControl::open(77), ZTPSessionCode::Open, Some(77)),
+            (ZTPSessionControl::open_ack(77), ZTPSessionCode::OpenAck, Some(77)),
+            (ZTPSessionControl::close(), ZTPSessionCode::Close, None),
+        ]{
+            let mut datagram = control.encode_to_vec().unwrap();
+            set_session_id(&mut datagram, 0xdead_beef).unwrap();
+            let (decoded, _) = ZTPSessionControl::decode_from_slice(&datagram).unwrap();
+            assert_eq!(decoded.get_code(), code);
+            assert_eq!(decoded.get_nonce(), nonce);
+            assert_eq!(decoded.get_session_id(), 0xdead_beef);
+            assert_eq!(peek_session_id(&datagram), Some(0xdead_beef));
+            assert_eq!(peek_open_nonce(&datagram), (code == ZTPSessionCode::Open).then_some(77));
+        }
+    }
+
+    #[test]
+    fn codes_do_not_decode_as_another_message(){
+        let req = request().encode_to_vec().unwrap();
+        assert_eq!(ZTPResponse::decode_from_slice(&req).unwrap_err(), ZTPWireError::UnknownType(ZTPRequestCode::Get.wire()));
+        let close = ZTPSessionControl::close().encode_to_vec().unwrap();
+        assert_eq!(ZTPResponse::decode_from_slice(&close).unwrap_err(), ZTPWireError::UnknownType(ZTPSessionCode::Close.wire()));
+        let ack = ZTPResponse::new(ZTPResponseCode::Ack, None, None).encode_to_vec().unwrap();
+        assert_eq!(ZTPRequest::decode_from_slice(&ack).unwrap_err(), ZTPWireError::UnknownType(ZTPResponseCode::Ack.wire()));
+    }
+
+    #[test]
+    fn truncated_datagrams_are_refused(){
+        let datagram = request().encode_to_vec().unwrap();
+        for len in [0, 2, HEADER_SIZE - 1, HEADER_SIZE, datagram.len() - 1]{
+            assert_eq!(ZTPRequest::decode_from_slice(&datagram[..len]).unwrap_err(), ZTPWireError::Truncated, "{len} bytes");
+        }
+        // trailing bytes past the body are not part of the datagram
+        let mut padded = datagram.clone();
+        padded.extend_from_slice(&[0u8; 8]);
+        assert_eq!(ZTPRequest::decode_from_slice(&padded).unwrap().1, datagram.len());
+    }
+
+    #[test]
+    fn bad_magic_is_refused(){
+        let mut datagram = request().encode_to_vec().unwrap();
+        datagram[..2].copy_from_slice(b"XX");
+        assert_eq!(ZTPRequest::decode_from_slice(&datagram).unwrap_err(), ZTPWireError::BadMagic);
+        assert_eq!(peek_session_id(&datagram), None);
+    }
+
+    #[test]
+    fn bad_checksum_is_refused(){
+        let datagram = ZTPResponse::new_piece(b"some piece".to_vec(), 3, ZTPChecksum::Xxh3).encode_to_vec().unwrap();
+        // a flipped bit anywhere, header, hash or body, breaks the CRC
+        for offset in [4, 12, 24, HEADER_SIZE, datagram.len() - 1]{
+            let mut corrupt = datagram.clone();
+            corrupt[offset] ^= 0x01;
+            assert_eq!(ZTPResponse::decode_from_slice(&corrupt).unwrap_err(), ZTPWireError::BadChecksum, "offset {offset}");
+        }
+    }
+
+    #[test]
+    fn unsupported_versions_are_refused(){
+        for version in [MIN_ZTP_VERSION - 1, ZTP_VERSION + 1, u8::MAX]{
+            let mut req = request();
+            req.version = version;
+            let datagram = req.encode_to_vec().unwrap();
+            assert_eq!(ZTPRequest::decode_from_slice(&datagram).unwrap_err(), ZTPWireError::UnsupportedVersion(version));
+
+            let mut datagram = ZTPResponse::new(ZTPResponseCode::Ack, None, None).encode_to_vec().unwrap();
+            datagram[2] = version;
+            reseal(&mut datagram);
+            assert_eq!(ZTPResponse::decode_from_slice(&datagram).unwrap_err(), ZTPWireError::UnsupportedVersion(version));
+        }
+    }
+
+    #[test]
+    fn the_highest_common_version_is_spoken(){
+        assert_eq!(common_version(MIN_ZTP_VERSION, ZTP_VERSION), Some(ZTP_VERSION));
+        assert_eq!(common_version(0, ZTP_VERSION + 3), Some(ZTP_VERSION));
+        assert_eq!(common_version(MIN_ZTP_VERSION, MIN_ZTP_VERSION), Some(MIN_ZTP_VERSION));
+        assert_eq!(common_version(ZTP_VERSION + 1, u8::MAX), None);
+        assert_eq!(common_version(0, MIN_ZTP_VERSION - 1), None);
+    }
+
+    #[test]
+    fn a_datagram_can_be_restamped_with_another_version(){
+        let mut datagram = request().encode_to_vec().unwrap();
+        set_version(&mut datagram, MIN_ZTP_VERSION).unwrap();
+        assert_eq!(ZTPRequest::decode_from_slice(&datagram).unwrap().0.get_version(), MIN_ZTP_VERSION);
+
+        let mut datagram = request().encode_to_vec().unwrap();
+        set_version(&mut datagram, ZTP_VERSION + 1).unwrap();
+        assert_eq!(ZTPRequest::decode_from_slice(&datagram).unwrap_err(), ZTPWireError::UnsupportedVersion(ZTP_VERSION + 1));
+    }
+
+    #[test]
+    fn version_refusal_decodes_in_any_version(){
+        for version in [MIN_ZTP_VERSION - 1, ZTP_VERSION, ZTP_VERSION + 1, u8::MAX]{
+            let decoded = round_trip(ZTPResponse::version_refusal(version));
+            assert_eq!(decoded.get_version(), version);
+            assert_eq!(decoded.get_code(), ZTPResponseCode::VersionMismatch);
+            assert!(matches!(decoded.get_data(), Some(ZTPResponseData::SupportedVersions(MIN_ZTP_VERSION, ZTP_VERSION))));
+            assert_eq!(decoded.get_error().map(|error| error.code), Some(ZTPErrorCode::BadRequest));
+        }
+    }
+
+    #[test]
+    fn malformed_bodies_are_refused(){
+        let mut datagram = ZTPResponse::new(ZTPResponseCode::Ack, None, None).encode_to_vec().unwrap();
+        datagram[4] = 0xf0;
+        reseal(&mut datagram);
+        assert_eq!(ZTPResponse::decode_from_slice(&datagram).unwrap_err(), ZTPWireError::Malformed("unknown body kind"));
+
+        let mut datagram = request().encode_to_vec().unwrap();
+        datagram[HEADER_SIZE] = 9;
+        reseal(&mut datagram);
+        assert_eq!(ZTPRequest::decode_from_slice(&datagram).unwrap_err(), ZTPWireError::Malformed("unknown transfer mode"));
+    }
+
+    #[test]
+    fn encode_into_slice_needs_room(){
+        let len = request().encode_to_vec().unwrap().len();
+        assert_eq!(request().encode_into_slice(&mut vec![0u8; len - 1]).unwrap_err(), ZTPWireError::BufferTooSmall);
+        assert_eq!(request().encode_into_slice(&mut vec![0u8; len]), Ok(len));
+    }
+}
@@ -1,11 +1,17 @@
-pub const CLIENT_ADDRESS: &'static str = "127.0.0.1:4242"; 
-pub const SERVER_ADDRESS: &'static str = "127.0.0.1:34254";
+pub const SERVER_ADDRESS: &str = "127.0.0.1:34254";
+pub const ZTP_PORT: u16 = 34254;
 pub const THREAD_POOL_SIZE: usize = 30;
 pub const TTL_MILLIS: u64 = 20;
 pub const MAX_RETRIES: usize = 10;
+pub const MIN_RTO_MILLIS: u64 = 5;
+pub const MAX_RTO_MILLIS: u64 = 1000;
 pub const DATA_PIECE_SIZE: usize = 1024;
+pub const MAX_PIECE_SIZE: usize = 3072;
+pub const POLL_MILLIS: u64 = 1;
+pub const WINDOW_SIZE: u16 = 16;
+pub const MAX_WINDOW_SIZE: u16 = 64;
+pub const SESSION_IDLE_MILLIS: u64 = 30_000;
+pub const SESSION_SWEEP_MILLIS: u64 = 1000;
+pub const RESOURCE_ROOT: &str = "./resources";
 
-pub const CLIENT_DIR_PATH: &str = "./download";
-pub const RES_NAME: &str = "teste.jpg";
-
-pub const ERROR_CHANCE: u8 = 30;
+pub const CHECKPOINT_PIECES: usize = 64;
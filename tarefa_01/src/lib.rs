@@ -0,0 +1,12 @@
+// ZTP, a file transfer protocol over UDP. `ZtpClient` and `ZtpServer` are all a
+// tool needs to move resources around, `ztp` is the wire codec underneath them.
+pub mod application;
+pub mod constants;
+
+pub use application::auth::{Key, Keyring, Permission};
+pub use application::client::ZtpClient;
+pub use application::error::ZtpError;
+pub use application::server::{ServerConfig, ZtpServer, ZtpServerBuilder};
+pub use application::ztp;
+#[cfg(feature = "async")]
+pub use application::{client::AsyncZtpClient, server::AsyncZtpServer};
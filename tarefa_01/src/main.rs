@@ -1,29 +1,130 @@
 use std::{
-    collections::HashMap, env
+    collections::HashMap, env, net::{SocketAddr, ToSocketAddrs}, path::Path, process
 };
 
-use application::{
-    server::Server,
-    client::Client,
+use tarefa_01::{
+    constants::{WINDOW_SIZE, ZTP_PORT},
+    ztp::{self, ZTPChecksum, ZTPErrorCode, ZTPListEntry, ZTPMetadata, ZTPTransferMode},
+    Keyring, ServerConfig, ZtpClient, ZtpError, ZtpServer,
 };
 
-pub mod application;
-pub mod constants;
+const USAGE: &str = "\
+usage:
+    tarefa_01 get <server> <resource> [-o <path>] [options]
+    tarefa_01 put <server> <file> [-o <resource>] [options]
+    tarefa_01 ls <server> [<directory>] [options]
+    tarefa_01 stat <server> <resource> [options]
+    tarefa_01 rm <server> <resource> [options]
+    tarefa_01 mv <server> <resource> <new name> [options]
+    tarefa_01 role=server [config=<file>] [<setting>=<value>...]
 
-fn main() {
-    let mut server = Server::new();
-    let mut client = Client::new();
+<server> is host[:port], the port defaults to 34254.
+client options: mode=sw|sr|gbn window=<n> checksum=xxh3|crc32c|sha256 encrypt=off keys=<file> key=<id>
+                skip_unchanged=off (get downloads even if the file at the output path matches)
+                corrupt_percent=<n> (fault injection, rejects n% of the received pieces as corrupt)
+server options: async=on (serve from tokio tasks, needs the async feature)
+logging:        RUST_LOG=error|warn|info|debug|trace, on stderr (default info for the server, warn for clients)
+
+exit codes: 0 ok, 1 error, 2 bad usage, 3 not found, 4 timeout, 5 integrity check failed, 6 forbidden,
+            7 permission denied, 8 conflict, 9 rejected by the server (bad request, version mismatch, busy)";
 
+enum Command{
+    Get{server: SocketAddr, resource: String, save_path: String},
+    Put{server: SocketAddr, load_path: String, resource: String},
+    List{server: SocketAddr, dir: String},
+    Stat{server: SocketAddr, resource: String},
+    Delete{server: SocketAddr, resource: String},
+    Rename{server: SocketAddr, resource: String, target: String},
+}
+
+fn main() {
     let var_map = collect_vars();
-    if let Some(role) = var_map.get("role"){
-        match role.as_str(){
-            "server" => server.run(),
-            _ => client.run()
+    let is_server = var_map.get("role").map(String::as_str) == Some("server");
+    // the server reports its sessions, a client only what went wrong, RUST_LOG overrides both
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(if is_server {"info"} else {"warn"}))
+        .format_timestamp_millis()
+        .init();
+    if is_server{
+        if let Err(e) = run_server(&var_map){
+            eprintln!("Server stopped: {e}");
+            process::exit(1);
         }
+        return;
+    }
+
+    let command = parse_command().unwrap_or_else(|e|{
+        eprintln!("{e}\n\n{USAGE}");
+        process::exit(2);
+    });
+    let client = build_client(&var_map).unwrap_or_else(|e|{
+        eprintln!("{e}");
+        process::exit(1);
+    });
+    let result = match command{
+        Command::Get{server, resource, save_path} => client.get_to_file(server, &resource, &save_path),
+        Command::Put{server, load_path, resource} => client.put(server, &load_path, &resource),
+        Command::List{server, dir} => client.list(server, &dir).map(|entries| print_listing(&entries)),
+        Command::Stat{server, resource} => client.stat(server, &resource).map(|metadata| print_stat(&resource, &metadata)),
+        Command::Delete{server, resource} => client.delete(server, &resource),
+        Command::Rename{server, resource, target} => client.rename(server, &resource, &target),
+    };
+    if let Err(e) = result{
+        eprintln!("{e}");
+        process::exit(exit_code(&e));
+    }
+}
+
+fn exit_code(e: &ZtpError) -> i32{
+    match e{
+        ZtpError::Io(_) | ZtpError::Wire(_) | ZtpError::Handshake | ZtpError::Config(_) => 1,
+        ZtpError::Timeout(_) => 4,
+        ZtpError::Integrity(_) => 5,
+        ZtpError::Report(report) => match report.code{
+            ZTPErrorCode::Io => 1,
+            ZTPErrorCode::NotFound => 3,
+            ZTPErrorCode::Aborted => 4,
+            ZTPErrorCode::Integrity => 5,
+            ZTPErrorCode::Forbidden => 6,
+            ZTPErrorCode::PermissionDenied => 7,
+            ZTPErrorCode::Conflict => 8,
+            ZTPErrorCode::BadRequest | ZTPErrorCode::ServerBusy => 9,
+        },
     }
-    else{
-       client.run(); 
+}
+
+fn run_server(var_map: &HashMap<String, String>) -> Result<(), ZtpError>{
+    let config = ServerConfig::from_vars(var_map).unwrap_or_else(|e|{
+        eprintln!("Invalid server configuration: {e}");
+        process::exit(1);
+    });
+    let builder = ZtpServer::builder().with_config(config);
+    #[cfg(feature = "async")]
+    if var_map.get("async").map(String::as_str) == Some("on"){
+        let runtime = tokio::runtime::Runtime::new()?;
+        return runtime.block_on(builder.build_async()?.run());
     }
+    builder.build()?.run()
+}
+
+fn build_client(var_map: &HashMap<String, String>) -> Result<ZtpClient, ZtpError>{
+    let corrupt_percent = match var_map.get("corrupt_percent"){
+        Some(value) => value.parse().map_err(|_| ZtpError::Config(format!("Invalid corrupt_percent: {value}")))?,
+        None => 0
+    };
+    let client = ZtpClient::new(parse_transfer_mode(var_map), parse_checksum(var_map))
+        .with_encryption(var_map.get("encrypt").map(String::as_str) != Some("off"))
+        .with_skip_unchanged(var_map.get("skip_unchanged").map(String::as_str) != Some("off"))
+        .with_corrupt_percent(corrupt_percent);
+
+    let Some(path) = var_map.get("keys") else{
+        return Ok(client);
+    };
+    let keyring = Keyring::load(path).map_err(|e| ZtpError::Config(format!("Failed to load keys from {path}: {e}")))?;
+    let key = match var_map.get("key"){
+        Some(id) => keyring.get(id).ok_or(ZtpError::Config(format!("Key {id} not found in {path}")))?,
+        None => keyring.first().ok_or(ZtpError::Config(format!("{path} has no keys")))?
+    };
+    Ok(client.with_key(key.clone()))
 }
 
 
@@ -38,3 +139,105 @@ fn collect_vars() -> HashMap<String, String>{
         })
         .collect()
 }
+
+// Positional arguments and `-o`, the key=value options are left to `collect_vars`.
+fn parse_command() -> Result<Command, String>{
+    let mut args = env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut output = None;
+    while let Some(arg) = args.next(){
+        if arg == "-o"{
+            output = Some(args.next().ok_or("-o needs a path")?);
+        }
+        else if !arg.contains('='){
+            positional.push(arg);
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let (Some(command), Some(server)) = (positional.next(), positional.next()) else{
+        return Err("expected a command and a server".to_string());
+    };
+    let targets: Vec<String> = positional.collect();
+    let server = resolve_server(&server)?;
+    match (command.as_str(), targets.as_slice()){
+        ("get", [target]) => {
+            let save_path = output.unwrap_or_else(|| file_name(target));
+            Ok(Command::Get{server, resource: target.clone(), save_path})
+        },
+        ("put", [target]) => {
+            let resource = output.unwrap_or_else(|| file_name(target));
+            Ok(Command::Put{server, load_path: target.clone(), resource})
+        },
+        ("ls", []) => Ok(Command::List{server, dir: String::new()}),
+        ("ls", [dir]) => Ok(Command::List{server, dir: dir.clone()}),
+        ("stat", [target]) => Ok(Command::Stat{server, resource: target.clone()}),
+        ("rm", [target]) => Ok(Command::Delete{server, resource: target.clone()}),
+        ("mv", [target, new_name]) => Ok(Command::Rename{server, resource: target.clone(), target: new_name.clone()}),
+        ("mv", [] | [_]) => Err("mv needs a resource and its new name".to_string()),
+        ("get" | "put" | "stat" | "rm", []) => Err(format!("{command} needs a resource")),
+        ("get" | "put" | "stat" | "rm" | "ls" | "mv", _) => Err("too many arguments".to_string()),
+        _ => Err(format!("unknown command {command}"))
+    }
+}
+
+fn resolve_server(server: &str) -> Result<SocketAddr, String>{
+    let resolved = match server.to_socket_addrs(){
+        Ok(addrs) => addrs,
+        Err(_) => (server, ZTP_PORT).to_socket_addrs().map_err(|e| format!("could not resolve {server}: {e}"))?,
+    };
+    resolved.into_iter().next().ok_or(format!("{server} has no address"))
+}
+
+fn print_listing(entries: &[ZTPListEntry]){
+    for entry in entries{
+        let suffix = if entry.is_dir {"/"} else {""};
+        println!("{:>12}  {}  {}{suffix}", entry.size, format_time(entry.modified), entry.name);
+    }
+}
+
+fn print_stat(resource: &str, metadata: &ZTPMetadata){
+    println!("{resource}");
+    println!("  size:     {} bytes", metadata.size());
+    println!("  modified: {} UTC", format_time(metadata.modified()));
+    println!("  sha256:   {}", ztp::to_hex(metadata.digest()));
+}
+
+// `YYYY-MM-DD HH:MM` in UTC, days to civil date as in Howard Hinnant's `civil_from_days`.
+fn format_time(secs: u64) -> String{
+    let days = (secs / 86_400) as i64 + 719_468;
+    let (hour, minute) = (secs % 86_400 / 3600, secs % 3600 / 60);
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {shifted_month + 3} else {shifted_month - 9};
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}")
+}
+
+fn file_name(path: &str) -> String{
+    Path::new(path).file_name().map_or(path.to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+fn parse_transfer_mode(var_map: &HashMap<String, String>) -> ZTPTransferMode{
+    let window = var_map.get("window")
+        .and_then(|window| window.parse().ok())
+        .unwrap_or(WINDOW_SIZE);
+
+    match var_map.get("mode").map(String::as_str){
+        Some("sr") => ZTPTransferMode::SelectiveRepeat(window),
+        Some("gbn") => ZTPTransferMode::GoBackN(window),
+        _ => ZTPTransferMode::StopAndWait
+    }
+}
+
+fn parse_checksum(var_map: &HashMap<String, String>) -> ZTPChecksum{
+    match var_map.get("checksum").map(String::as_str){
+        Some("crc32c") => ZTPChecksum::Crc32c,
+        Some("sha256") => ZTPChecksum::Sha256,
+        _ => ZTPChecksum::Xxh3
+    }
+}